@@ -3,7 +3,7 @@ use ic_auth_types::{ByteArrayB64, deterministic_cbor_into_vec};
 use serde::{Deserialize, Serialize};
 use std::collections::HashSet;
 
-use crate::{RegistryError, SignedEnvelope, TEEInfo, sha3_256};
+use crate::{RegistryError, SignedEnvelope, TEEClaims, TEEInfo, sha3_256};
 
 pub const ZERO_CHALLENGE_CODE: ByteArrayB64<16> = ByteArrayB64([0u8; 16]);
 
@@ -41,6 +41,13 @@ pub struct Agent {
     ///    and actived_start is reset to now_ms
     pub health_power: u64,
 
+    /// Health power decayed by the time elapsed since the challenge expired, used to rank agents.
+    /// It equals health_power while the challenge is valid, see [`Agent::effective_health_power_at`].
+    /// Filled by the registry when the agent is read, it is absent in certified `/lookup` responses,
+    /// agent changes and foreign agents, where it can be computed with [`Agent::effective_health_power_at`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub effective_health_power: Option<u64>,
+
     /// The challenge code for the next round, used to ensure the agent is in a healthy state.
     pub challenge_code: ByteArrayB64<16>,
 
@@ -55,6 +62,44 @@ pub struct Agent {
 
     /// Optional Trusted Execution Environment information where the agent is running.
     pub tee: Option<TEEInfo>,
+
+    /// Claims decoded from the TEE attestation verified by the registry.
+    #[serde(default)]
+    pub tee_claims: Option<TEEClaims>,
+
+    /// Status of the agent, maintained by the registry's expiry sweeper and governance.
+    #[serde(default)]
+    pub status: AgentStatus,
+
+    /// The reason given by governance for suspending or banning the agent.
+    #[serde(default)]
+    pub status_reason: Option<String>,
+}
+
+/// Enumerates the status of an agent in the registry.
+///
+/// Agents that have not been challenged successfully for a grace period after their
+/// challenge expiration are marked as expired, and evicted from the registry eventually.
+/// An expired agent becomes active again after a successful challenge.
+///
+/// Suspended and banned agents are set by governance. They are hidden from listings and
+/// lookups, and their challenges are rejected until they are unbanned. Neither is evicted,
+/// and a banned agent also cannot unregister itself, so its identity and handle stay blocked.
+/// An unbanned agent gets back the status it had before it was moderated.
+#[derive(Clone, Copy, Debug, Default, CandidType, Deserialize, Serialize, Eq, PartialEq, Hash)]
+pub enum AgentStatus {
+    #[default]
+    Active,
+    Expired,
+    Suspended,
+    Banned,
+}
+
+impl AgentStatus {
+    /// Returns true if the agent is suspended or banned by governance.
+    pub fn is_moderated(&self) -> bool {
+        matches!(self, AgentStatus::Suspended | AgentStatus::Banned)
+    }
 }
 
 /// Contains descriptive and operational information about an AI agent.
@@ -64,6 +109,8 @@ pub struct Agent {
 #[derive(Clone, Debug, Default, CandidType, Deserialize, Serialize)]
 pub struct AgentInfo {
     /// Unique account identifier of the agent.
+    /// It is verified on the dMsg.net canister if `handle_canister` is set,
+    /// otherwise it is a registry-native handle, claimed first-come in the registry.
     pub handle: String,
 
     /// The dMsg.net canister where the agent profile is stored.
@@ -145,10 +192,102 @@ impl AgentInfo {
 
         Ok(())
     }
+
+    /// Compares the agent information with the new one and returns the changed fields,
+    /// with their values before and after the change.
+    pub fn diff(&self, new: &AgentInfo) -> Vec<AgentInfoChange> {
+        let mut changes = Vec::new();
+        if self.handle != new.handle {
+            changes.push(AgentInfoChange::Handle {
+                from: self.handle.clone(),
+                to: new.handle.clone(),
+            });
+        }
+        if self.handle_canister != new.handle_canister {
+            changes.push(AgentInfoChange::HandleCanister {
+                from: self.handle_canister,
+                to: new.handle_canister,
+            });
+        }
+        if self.name != new.name {
+            changes.push(AgentInfoChange::Name {
+                from: self.name.clone(),
+                to: new.name.clone(),
+            });
+        }
+        if self.image != new.image {
+            changes.push(AgentInfoChange::Image {
+                from: self.image.clone(),
+                to: new.image.clone(),
+            });
+        }
+        if self.description != new.description {
+            changes.push(AgentInfoChange::Description {
+                from: self.description.clone(),
+                to: new.description.clone(),
+            });
+        }
+        if self.endpoint != new.endpoint {
+            changes.push(AgentInfoChange::Endpoint {
+                from: self.endpoint.clone(),
+                to: new.endpoint.clone(),
+            });
+        }
+        if self.protocols != new.protocols {
+            changes.push(AgentInfoChange::Protocols {
+                from: self.protocols.clone(),
+                to: new.protocols.clone(),
+            });
+        }
+        if self.provider != new.provider {
+            changes.push(AgentInfoChange::Provider {
+                from: self.provider.clone(),
+                to: new.provider.clone(),
+            });
+        }
+        changes
+    }
+}
+
+/// A changed field of the agent information, with its value before and after the change.
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize, Eq, PartialEq)]
+pub enum AgentInfoChange {
+    Handle {
+        from: String,
+        to: String,
+    },
+    HandleCanister {
+        from: Option<Principal>,
+        to: Option<Principal>,
+    },
+    Name {
+        from: String,
+        to: String,
+    },
+    Image {
+        from: String,
+        to: String,
+    },
+    Description {
+        from: String,
+        to: String,
+    },
+    Endpoint {
+        from: String,
+        to: String,
+    },
+    Protocols {
+        from: Vec<AgentProtocol>,
+        to: Vec<AgentProtocol>,
+    },
+    Provider {
+        from: Option<AgentProvider>,
+        to: Option<AgentProvider>,
+    },
 }
 
 /// Information about the agent's communication protocol.
-#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize, Eq, PartialEq)]
 pub struct AgentProtocol {
     /// The name of the agent protocol. Should be uppercase.
     /// (e.g. "A2A", "MCP", "X402")
@@ -194,7 +333,7 @@ impl AgentProtocol {
 }
 
 /// Information about the agent's service provider.
-#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize, Eq, PartialEq)]
 pub struct AgentProvider {
     /// The unique identifier of the agent provider.
     pub id: Principal,
@@ -340,25 +479,13 @@ impl ChallengeRequest {
     /// - `Err(String)` with a descriptive error message if validation fails
     pub fn validate(&self, now_ms: u64, registry: &Principal) -> Result<(), String> {
         self.agent.validate()?;
-        if self.created_at + CHALLENGE_EXPIRES_IN_MS + PERMITTED_DRIFT_MS < now_ms {
-            return Err(format!(
-                "challenge request is too old, created_at: {}, now: {}",
-                self.created_at, now_ms
-            ));
-        }
-        if self.created_at > now_ms + PERMITTED_DRIFT_MS {
-            return Err(format!(
-                "challenge request is in the future, created_at: {}, now: {}",
-                self.created_at, now_ms
-            ));
-        }
-        if self.registry != *registry {
-            return Err(format!(
-                "challenge request is for a different registry, expected: {}, got: {}",
-                registry, self.registry
-            ));
-        }
-        Ok(())
+        validate_request(
+            "challenge request",
+            self.created_at,
+            &self.registry,
+            now_ms,
+            registry,
+        )
     }
 
     /// Verifies the challenge request by validating its components and authentication.
@@ -431,11 +558,275 @@ impl ChallengeEnvelope {
     }
 }
 
+/// Represents a successful challenge (or the registration) of an agent recorded by the registry.
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
+pub struct ChallengeRecord {
+    /// Sequence number of the record in the registry's challenge history.
+    pub id: u64,
+
+    /// Timestamp when the agent was challenged in milliseconds since the Unix epoch.
+    pub challenged_at: u64,
+
+    /// Principal ID of the challenger.
+    pub challenged_by: Principal,
+
+    /// Health power of the agent after the challenge.
+    pub health_power: u64,
+
+    /// Change of the health power caused by the challenge,
+    /// negative if the agent was punished for an expired challenge.
+    pub health_delta: i64,
+
+    /// Whether the previous challenge had expired when the agent was challenged.
+    pub expired: bool,
+
+    /// Whether TEE information was provided with the challenge.
+    pub tee: bool,
+}
+
+/// Represents a revision of the agent information submitted with a challenge.
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
+pub struct AgentRevision {
+    /// Timestamp when the agent information was revised in milliseconds since the Unix epoch.
+    /// It is unique in the agent's revision history.
+    pub revised_at: u64,
+
+    /// Principal ID of the challenger that submitted the revision,
+    /// or of the agent itself for the handle it accepted or transferred away.
+    pub revised_by: Principal,
+
+    /// The changed fields of the agent information.
+    pub changes: Vec<AgentInfoChange>,
+}
+
+/// Represents a request signed by the agent itself to manage its own registration.
+///
+/// Unlike a [`ChallengeRequest`], no challenger is involved: the agent signs the
+/// request with its own ICP identity and submits it to the Registry Canister directly.
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
+pub struct AgentRequest {
+    /// The registry canister where the agent is registered.
+    pub registry: Principal,
+
+    /// The agent's current challenge code.
+    /// It binds the request to the agent's latest state so that it can not be replayed.
+    pub code: ByteArrayB64<16>,
+
+    /// The action requested by the agent.
+    pub action: AgentAction,
+
+    /// Creation timestamp of the request in milliseconds since the Unix epoch.
+    pub created_at: u64,
+}
+
+/// Enumerates the actions an agent can request on its own registration.
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize, Eq, PartialEq)]
+pub enum AgentAction {
+    /// Removes the agent from the registry and frees its handle.
+    Unregister,
+    /// Releases the agent's registry-native handle so that other agents can claim it.
+    ReleaseHandle,
+    /// Offers the agent's registry-native handle to another agent,
+    /// the transfer completes when the recipient accepts it.
+    /// The sender's handle is then cleared until it sets a new one in a challenge.
+    TransferHandle { to: Principal },
+    /// Accepts the registry-native handle offered by another agent,
+    /// the agent's own registry-native handle is released.
+    AcceptHandle { handle: String },
+}
+
+impl AgentRequest {
+    /// Computes a digest (hash) of the agent request.
+    ///
+    /// # Returns
+    /// - A 32-byte array containing the SHA3-256 hash of the serialized data
+    pub fn digest(&self) -> [u8; 32] {
+        let data = deterministic_cbor_into_vec(&self).expect("failed to serialize AgentRequest");
+        sha3_256(&data)
+    }
+
+    /// Validates the agent request's timestamp and target registry.
+    ///
+    /// # Returns
+    /// - `Ok(())` if validation passes
+    /// - `Err(String)` with a descriptive error message if validation fails
+    pub fn validate(&self, now_ms: u64, registry: &Principal) -> Result<(), String> {
+        validate_request(
+            "agent request",
+            self.created_at,
+            &self.registry,
+            now_ms,
+            registry,
+        )
+    }
+}
+
+/// A complete envelope containing an agent request and the agent's signature on it.
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
+pub struct AgentEnvelope {
+    /// The request initiated by the agent.
+    pub request: AgentRequest,
+
+    /// The agent's signature on the request.
+    pub authentication: SignedEnvelope,
+}
+
+impl AgentEnvelope {
+    /// Verifies the agent envelope by validating the request and the agent's signature.
+    /// The challenge code is not verified, it should be checked against the registry state.
+    pub fn verify(&self, now_ms: u64, registry: Principal) -> Result<(), RegistryError> {
+        self.request
+            .validate(now_ms, &registry)
+            .map_err(|error| RegistryError::BadRequest { error })?;
+
+        let digest = self.request.digest();
+        self.authentication
+            .verify(now_ms, Some(registry), Some(&digest))
+            .map_err(|error| RegistryError::Unauthorized { error })?;
+
+        Ok(())
+    }
+}
+
+/// A request to move an agent's registration to a new identity (principal),
+/// e.g. after its key was compromised or regenerated by an enclave restart.
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
+pub struct RotationRequest {
+    /// The registry canister where the agent is registered.
+    pub registry: Principal,
+
+    /// The agent's current challenge code.
+    /// It binds the request to the agent's latest state so that it can not be replayed.
+    pub code: ByteArrayB64<16>,
+
+    /// The new identity of the agent.
+    pub new_id: Principal,
+
+    /// Creation timestamp of the request in milliseconds since the Unix epoch.
+    pub created_at: u64,
+}
+
+impl RotationRequest {
+    /// Computes a digest (hash) of the rotation request.
+    ///
+    /// # Returns
+    /// - A 32-byte array containing the SHA3-256 hash of the serialized data
+    pub fn digest(&self) -> [u8; 32] {
+        let data = deterministic_cbor_into_vec(&self).expect("failed to serialize RotationRequest");
+        sha3_256(&data)
+    }
+
+    /// Validates the rotation request's timestamp and target registry.
+    pub fn validate(&self, now_ms: u64, registry: &Principal) -> Result<(), String> {
+        validate_request(
+            "rotation request",
+            self.created_at,
+            &self.registry,
+            now_ms,
+            registry,
+        )
+    }
+}
+
+/// A complete envelope containing a rotation request signed by both the old and the new
+/// identity of the agent.
+///
+/// The registration, including the health power, the creation time and the handle,
+/// moves to the new identity. The TEE information of the old identity is not kept,
+/// an attestation of the new identity can be provided instead.
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
+pub struct RotationEnvelope {
+    /// The rotation request.
+    pub request: RotationRequest,
+
+    /// The old identity's signature on the request.
+    pub authentication: SignedEnvelope,
+
+    /// The new identity's signature on the request.
+    pub new_authentication: SignedEnvelope,
+
+    /// TEE information of the new identity if the agent is running in a Trusted Execution
+    /// Environment, the attestation should contain the new identity's public key and the
+    /// challenge code.
+    pub tee: Option<TEEInfo>,
+}
+
+impl RotationEnvelope {
+    /// Verifies the rotation envelope by validating the request and both signatures.
+    /// The challenge code and the tee attestation are not verified, they should be
+    /// checked against the registry state.
+    pub fn verify(&self, now_ms: u64, registry: Principal) -> Result<(), RegistryError> {
+        if let Some(tee) = &self.tee {
+            tee.validate()
+                .map_err(|error| RegistryError::BadRequest { error })?;
+        }
+
+        self.request
+            .validate(now_ms, &registry)
+            .map_err(|error| RegistryError::BadRequest { error })?;
+
+        let digest = self.request.digest();
+        self.authentication
+            .verify(now_ms, Some(registry), Some(&digest))
+            .map_err(|error| RegistryError::Unauthorized { error })?;
+        self.new_authentication
+            .verify(now_ms, Some(registry), Some(&digest))
+            .map_err(|error| RegistryError::Unauthorized { error })?;
+
+        let new_id = self.new_authentication.sender();
+        if new_id != self.request.new_id {
+            return Err(RegistryError::Unauthorized {
+                error: format!(
+                    "new identity is not match, expect {}, got {}",
+                    self.request.new_id, new_id
+                ),
+            });
+        }
+        if new_id == self.authentication.sender() {
+            return Err(RegistryError::BadRequest {
+                error: "new identity is the same as the old one".to_string(),
+            });
+        }
+
+        Ok(())
+    }
+}
+
+fn validate_request(
+    kind: &str,
+    created_at: u64,
+    target: &Principal,
+    now_ms: u64,
+    registry: &Principal,
+) -> Result<(), String> {
+    if created_at + CHALLENGE_EXPIRES_IN_MS + PERMITTED_DRIFT_MS < now_ms {
+        return Err(format!(
+            "{kind} is too old, created_at: {created_at}, now: {now_ms}"
+        ));
+    }
+    if created_at > now_ms + PERMITTED_DRIFT_MS {
+        return Err(format!(
+            "{kind} is in the future, created_at: {created_at}, now: {now_ms}"
+        ));
+    }
+    if target != registry {
+        return Err(format!(
+            "{kind} is for a different registry, expected: {registry}, got: {target}"
+        ));
+    }
+    Ok(())
+}
+
 pub static AGENT_EVENT_API: &str = "on_agent_event";
 
 /// Represents an event related to an agent's registration or status change.
 #[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
 pub struct AgentEvent {
+    /// Sequence number of the event, monotonically increasing without gaps within the registry.
+    /// It is assigned by the registry when the event is recorded.
+    #[serde(default)]
+    pub seq: u64,
+
     /// The principal ID of the agent.
     pub id: Principal,
 
@@ -454,6 +845,246 @@ pub enum AgentEventKind {
     Registered,
     Challenged,
     Unregistered,
+    Expired,
+    Evicted,
+    Suspended,
+    Banned,
+    Unbanned,
+    /// The agent's handle was unmapped, because it no longer belongs to the agent on the
+    /// name service, or it was released or transferred by the agent.
+    HandleUnmapped,
+    /// The agent accepted a registry-native handle transferred from another agent.
+    HandleMapped,
+    /// The agent's registration moved to it from the old identity `from`.
+    Rotated {
+        from: Principal,
+    },
+}
+
+/// The A2A protocol version of the agent cards rendered by the registry.
+pub const A2A_PROTOCOL_VERSION: &str = "0.3.0";
+
+/// The URI of the A2A extension and the MCP `_meta` key that carry the registry's health data.
+pub const ANDA_REGISTRY_EXTENSION: &str = "https://anda.ai/extensions/registry/v1";
+
+/// An A2A Agent Card, the self-describing manifest served at `/.well-known/agent.json`.
+///
+/// Only the fields that can be derived from [`AgentInfo`] are rendered.
+/// See https://a2a-protocol.org/latest/specification/#5-agent-discovery-the-agent-card
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AgentCard {
+    pub protocol_version: String,
+    pub name: String,
+    pub description: String,
+    /// The preferred endpoint URL for interacting with the agent.
+    pub url: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub icon_url: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub provider: Option<AgentCardProvider>,
+    pub version: String,
+    pub capabilities: AgentCardCapabilities,
+    pub default_input_modes: Vec<String>,
+    pub default_output_modes: Vec<String>,
+    pub skills: Vec<AgentCardSkill>,
+}
+
+/// The service provider of an A2A Agent Card.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AgentCardProvider {
+    pub organization: String,
+    pub url: String,
+}
+
+/// The optional capabilities of an A2A Agent Card.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AgentCardCapabilities {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub streaming: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub push_notifications: Option<bool>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub extensions: Vec<AgentCardExtension>,
+}
+
+/// An A2A extension declared by an Agent Card.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AgentCardExtension {
+    pub uri: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub required: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub params: Option<serde_json::Value>,
+}
+
+/// A skill of an A2A Agent Card.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AgentCardSkill {
+    pub id: String,
+    pub name: String,
+    pub description: String,
+    pub tags: Vec<String>,
+}
+
+/// An MCP server descriptor in the format of the MCP registry's `server.json`.
+///
+/// See https://github.com/modelcontextprotocol/registry/blob/main/docs/reference/server-json/generic-server-json.md
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct McpServerDescriptor {
+    pub name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    pub description: String,
+    pub version: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub website_url: Option<String>,
+    pub remotes: Vec<McpRemote>,
+    #[serde(rename = "_meta", skip_serializing_if = "Option::is_none")]
+    pub meta: Option<serde_json::Map<String, serde_json::Value>>,
+}
+
+/// A remote transport of an MCP server.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct McpRemote {
+    /// The transport type, "streamable-http" or "sse".
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub url: String,
+}
+
+impl AgentInfo {
+    /// Gets the protocol of the agent by name.
+    pub fn protocol(&self, name: &str) -> Option<&AgentProtocol> {
+        self.protocols.iter().find(|p| p.name == name)
+    }
+
+    /// Renders an MCP server descriptor from the agent information.
+    ///
+    /// Returns `None` if the agent does not support the "MCP" protocol.
+    pub fn to_mcp_server(&self) -> Option<McpServerDescriptor> {
+        let mcp = self.protocol("MCP")?;
+        let kind = if mcp.endpoint.trim_end_matches('/').ends_with("/sse") {
+            "sse"
+        } else {
+            "streamable-http"
+        };
+
+        Some(McpServerDescriptor {
+            name: self.handle.clone(),
+            title: Some(self.name.clone()),
+            description: self.description.clone(),
+            version: mcp.version.clone().unwrap_or_else(|| "1.0.0".to_string()),
+            website_url: Some(self.endpoint.clone()),
+            remotes: vec![McpRemote {
+                kind: kind.to_string(),
+                url: mcp.endpoint.clone(),
+            }],
+            meta: None,
+        })
+    }
+}
+
+impl From<&AgentInfo> for AgentCard {
+    /// Renders an A2A Agent Card from the agent information.
+    ///
+    /// The endpoint of the agent's "A2A" protocol is used as the card's URL when it is
+    /// not a link to another Agent Card, falling back to the agent's endpoint.
+    fn from(info: &AgentInfo) -> Self {
+        let a2a = info.protocol("A2A");
+        let url = a2a
+            .filter(|p| p.endpoint.starts_with("https://") && !p.endpoint.ends_with(".json"))
+            .map(|p| p.endpoint.clone())
+            .unwrap_or_else(|| info.endpoint.clone());
+
+        AgentCard {
+            protocol_version: A2A_PROTOCOL_VERSION.to_string(),
+            name: info.name.clone(),
+            description: info.description.clone(),
+            url,
+            icon_url: (!info.image.is_empty()).then(|| info.image.clone()),
+            provider: info.provider.as_ref().map(|p| AgentCardProvider {
+                organization: p.name.clone(),
+                url: p.url.clone(),
+            }),
+            version: a2a
+                .and_then(|p| p.version.clone())
+                .unwrap_or_else(|| "1.0.0".to_string()),
+            capabilities: AgentCardCapabilities::default(),
+            default_input_modes: vec!["text/plain".to_string()],
+            default_output_modes: vec!["text/plain".to_string()],
+            skills: Vec::new(),
+        }
+    }
+}
+
+impl Agent {
+    /// Computes the effective health power at `now_ms`.
+    ///
+    /// `health_power` only changes when the agent is challenged, so an agent that has
+    /// vanished would keep its score. The effective health power decreases by the time
+    /// elapsed since `challenged_expiration`, and reaches 0 eventually.
+    pub fn effective_health_power_at(&self, now_ms: u64) -> u64 {
+        self.health_power
+            .saturating_sub(now_ms.saturating_sub(self.challenged_expiration))
+    }
+
+    /// Sets `effective_health_power` to the value at `now_ms`.
+    pub fn with_effective_health_power(mut self, now_ms: u64) -> Self {
+        self.effective_health_power = Some(self.effective_health_power_at(now_ms));
+        self
+    }
+
+    /// Returns the registry's health data of the agent, as the parameters of the
+    /// [`ANDA_REGISTRY_EXTENSION`].
+    pub fn registry_params(&self) -> serde_json::Value {
+        let mut params = serde_json::json!({
+            "id": self.id.to_text(),
+            "handle": self.info.handle,
+            "status": self.status,
+            "healthPower": self.health_power,
+            "activedStart": self.actived_start,
+            "challengedAt": self.challenged_at,
+            "challengedExpiration": self.challenged_expiration,
+            "tee": self.tee.as_ref().map(|t| t.kind.to_string()),
+        });
+        if let Some(hp) = self.effective_health_power {
+            params["effectiveHealthPower"] = hp.into();
+        }
+        params
+    }
+
+    /// Renders an A2A Agent Card of the agent, with the registry's health data
+    /// declared as the [`ANDA_REGISTRY_EXTENSION`].
+    pub fn to_agent_card(&self) -> AgentCard {
+        let mut card = AgentCard::from(&self.info);
+        card.capabilities.extensions.push(AgentCardExtension {
+            uri: ANDA_REGISTRY_EXTENSION.to_string(),
+            description: Some("Health data of the agent in the Anda registry".to_string()),
+            required: Some(false),
+            params: Some(self.registry_params()),
+        });
+        card
+    }
+
+    /// Renders an MCP server descriptor of the agent, with the registry's health data
+    /// in the `_meta` field.
+    ///
+    /// Returns `None` if the agent does not support the "MCP" protocol.
+    pub fn to_mcp_server(&self) -> Option<McpServerDescriptor> {
+        let mut server = self.info.to_mcp_server()?;
+        let mut meta = serde_json::Map::new();
+        meta.insert(ANDA_REGISTRY_EXTENSION.to_string(), self.registry_params());
+        server.meta = Some(meta);
+        Some(server)
+    }
 }
 
 #[cfg(test)]
@@ -559,6 +1190,124 @@ mod tests {
         );
     }
 
+    #[test]
+    fn agent_request_validate_rejects_wrong_registry_and_stale_requests() {
+        let registry = sample_principal(15);
+        let now_ms = 5_000_000;
+        let request = AgentRequest {
+            registry,
+            code: ByteArrayB64([1u8; 16]),
+            action: AgentAction::Unregister,
+            created_at: now_ms,
+        };
+        assert!(request.validate(now_ms, &registry).is_ok());
+
+        assert!(
+            matches!(request.validate(now_ms, &sample_principal(16)), Err(message) if message.contains("different registry"))
+        );
+
+        let stale = now_ms + CHALLENGE_EXPIRES_IN_MS + PERMITTED_DRIFT_MS + 1;
+        assert!(
+            matches!(request.validate(stale, &registry), Err(message) if message.contains("too old"))
+        );
+    }
+
+    #[test]
+    fn agent_info_diff_reports_changed_fields() {
+        let info = sample_agent_info();
+        assert!(info.diff(&info.clone()).is_empty());
+
+        let mut new = info.clone();
+        new.endpoint = "https://evil.example/api".into();
+        new.protocols[0].version = Some("v2".into());
+        new.provider = None;
+        let changes = info.diff(&new);
+        assert_eq!(
+            changes,
+            vec![
+                AgentInfoChange::Endpoint {
+                    from: "https://agent.example/api".into(),
+                    to: "https://evil.example/api".into(),
+                },
+                AgentInfoChange::Protocols {
+                    from: info.protocols.clone(),
+                    to: new.protocols.clone(),
+                },
+                AgentInfoChange::Provider {
+                    from: info.provider.clone(),
+                    to: None,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn rotation_request_validate_rejects_wrong_registry_and_stale_requests() {
+        let registry = sample_principal(17);
+        let now_ms = 6_000_000;
+        let request = RotationRequest {
+            registry,
+            code: ByteArrayB64([2u8; 16]),
+            new_id: sample_principal(18),
+            created_at: now_ms,
+        };
+        assert!(request.validate(now_ms, &registry).is_ok());
+
+        assert!(
+            matches!(request.validate(now_ms, &sample_principal(19)), Err(message) if message.contains("different registry"))
+        );
+
+        let stale = now_ms + CHALLENGE_EXPIRES_IN_MS + PERMITTED_DRIFT_MS + 1;
+        assert!(
+            matches!(request.validate(stale, &registry), Err(message) if message.contains("too old"))
+        );
+
+        let mut other = request.clone();
+        other.new_id = sample_principal(20);
+        assert_ne!(request.digest(), other.digest());
+    }
+
+    #[test]
+    fn agent_info_renders_agent_card() {
+        let mut info = sample_agent_info();
+        let card = AgentCard::from(&info);
+        assert_eq!(card.url, info.endpoint);
+        assert_eq!(card.provider.as_ref().unwrap().organization, "Anda Labs");
+        assert_eq!(card.protocol_version, A2A_PROTOCOL_VERSION);
+
+        info.protocols.push(AgentProtocol {
+            name: "A2A".into(),
+            endpoint: "https://agent.example/.well-known/agent.json".into(),
+            version: Some("0.2.0".into()),
+        });
+        let card = AgentCard::from(&info);
+        assert_eq!(card.url, info.endpoint);
+        assert_eq!(card.version, "0.2.0");
+
+        info.protocols[1].endpoint = "https://agent.example/a2a".into();
+        let card = AgentCard::from(&info);
+        assert_eq!(card.url, "https://agent.example/a2a");
+
+        let value = serde_json::to_value(&card).unwrap();
+        assert!(value.get("defaultInputModes").is_some());
+        assert!(value.get("iconUrl").is_some());
+    }
+
+    #[test]
+    fn agent_info_renders_mcp_server() {
+        let mut info = sample_agent_info();
+        let server = info.to_mcp_server().unwrap();
+        assert_eq!(server.name, "agent_one");
+        assert_eq!(server.remotes[0].kind, "streamable-http");
+        assert_eq!(server.remotes[0].url, "https://agent.example/protocol");
+
+        info.protocols[0].endpoint = "https://agent.example/sse".into();
+        assert_eq!(info.to_mcp_server().unwrap().remotes[0].kind, "sse");
+
+        info.protocols.clear();
+        assert!(info.to_mcp_server().is_none());
+    }
+
     fn sample_principal(seed: u8) -> Principal {
         Principal::self_authenticating([seed; 32])
     }
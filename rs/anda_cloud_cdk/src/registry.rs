@@ -2,6 +2,8 @@ use candid::{CandidType, Principal};
 use serde::{Deserialize, Serialize};
 use std::collections::BTreeSet;
 
+use crate::agent::Agent;
+
 /// Represents the state of an Anda Registry Canister.
 ///
 /// The Registry Canister is responsible for managing agent registrations,
@@ -22,6 +24,16 @@ pub struct RegistryState {
     /// Agents must respond to challenges within this timeframe to maintain their active status.
     pub challenge_expires_in_ms: u64,
 
+    /// Grace period in milliseconds after the challenge expiration
+    /// before an agent is marked as expired.
+    #[serde(default)]
+    pub expired_grace_ms: u64,
+
+    /// Grace period in milliseconds after the challenge expiration
+    /// before an expired agent is evicted from the registry. 0 means never.
+    #[serde(default)]
+    pub evicted_grace_ms: u64,
+
     /// Set of principal IDs of peer registry canisters in the network.
     /// These peers can synchronize agent information across the network.
     pub peers: BTreeSet<Principal>,
@@ -41,6 +53,85 @@ pub struct RegistryState {
     /// Optional principal ID of the governance canister that controls this registry.
     /// If set, certain administrative operations require approval from this canister.
     pub governance_canister: Option<Principal>,
+
+    /// Default limits on the calls of challengers.
+    #[serde(default)]
+    pub challenger_limits: ChallengerLimits,
+}
+
+/// Limits on the `register` and `challenge` calls of a challenger.
+///
+/// Calls are rate limited by a token bucket of `burst` capacity refilled by
+/// `refill_per_minute` tokens per minute, and registrations are limited per UTC day.
+/// The default limits are all 0, challengers are unlimited until governance sets limits.
+#[derive(Clone, CandidType, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+pub struct ChallengerLimits {
+    /// Maximum number of calls in a burst. 0 means unlimited.
+    pub burst: u64,
+
+    /// Number of calls refilled into the bucket per minute.
+    pub refill_per_minute: u64,
+
+    /// Maximum number of agents registered per day. 0 means unlimited.
+    pub registrations_per_day: u64,
+}
+
+/// Represents a change of an agent registered in a registry.
+///
+/// Registries expose their changes ordered by sequence number, so that peer registries
+/// can pull them incrementally with a cursor (the last seen sequence number).
+#[derive(Clone, CandidType, Debug, Deserialize, Serialize)]
+pub struct AgentChange {
+    /// Sequence number of the change, monotonically increasing within the source registry.
+    pub seq: u64,
+
+    /// The principal ID of the changed agent.
+    pub id: Principal,
+
+    /// The latest agent record, or `None` if the agent has been removed from the registry.
+    pub agent: Option<Agent>,
+}
+
+/// Represents a read-only agent record synchronized from a peer registry.
+#[derive(Clone, CandidType, Debug, Deserialize, Serialize)]
+pub struct ForeignAgent {
+    /// The agent record as registered in the peer registry.
+    pub agent: Agent,
+
+    /// The principal ID of the peer registry the record was synchronized from.
+    pub peer: Principal,
+
+    /// Sequence number of the change in the peer registry.
+    pub seq: u64,
+
+    /// Timestamp when the record was synchronized in milliseconds since the Unix epoch.
+    pub synced_at: u64,
+}
+
+/// Represents the event delivery status of a subscriber.
+#[derive(Clone, CandidType, Debug, Deserialize, Serialize)]
+pub struct SubscriberStatus {
+    /// The principal ID of the subscriber.
+    pub subscriber: Principal,
+
+    /// Sequence number of the last event delivered to (or dropped for) the subscriber.
+    pub delivered_seq: u64,
+
+    /// Sequence number of the latest event in the registry.
+    pub latest_seq: u64,
+
+    /// Number of events pending delivery to the subscriber.
+    pub lag: u64,
+
+    /// Number of failed attempts to deliver the next pending event.
+    pub retries: u32,
+
+    /// Timestamp of the next delivery attempt in milliseconds since the Unix epoch.
+    pub next_retry_at: u64,
+
+    /// Number of events dropped after the retries were exhausted,
+    /// or skipped because the subscriber fell behind the registry's event log.
+    pub dropped: u64,
 }
 
 /// Represents errors that can occur during registry operations.
@@ -99,3 +190,25 @@ impl RegistryError {
         }
     }
 }
+
+/// Represents the activity statistics of a challenger.
+#[derive(Clone, CandidType, Debug, Deserialize, Serialize)]
+pub struct ChallengerStats {
+    /// The principal ID of the challenger.
+    pub challenger: Principal,
+
+    /// Whether the principal is currently authorized as a challenger.
+    pub active: bool,
+
+    /// Number of agents registered by the challenger.
+    pub registrations: u64,
+
+    /// Number of successful challenges issued by the challenger, registrations excluded.
+    pub challenges: u64,
+
+    /// Number of successful challenges on agents whose previous challenge had expired.
+    pub expired_recoveries: u64,
+
+    /// Timestamp of the challenger's last registration or challenge in milliseconds since the Unix epoch.
+    pub last_active_at: u64,
+}
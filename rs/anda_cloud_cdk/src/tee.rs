@@ -2,6 +2,9 @@ use candid::{CandidType, Principal};
 use core::fmt::Display;
 use ic_auth_types::ByteBufB64;
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+use crate::sha3_256;
 
 pub use ic_auth_verifier::envelope::SignedEnvelope;
 
@@ -24,6 +27,9 @@ pub struct TEEInfo {
     /// Optional attestation data that proves the TEE's authenticity.
     /// This typically contains cryptographic evidence that the TEE is genuine
     /// and running the expected code.
+    ///
+    /// It is the Nitro attestation document for NITRO, the CBOR encoded [`SevSnpAttestation`]
+    /// for SEV_SNP, and the DCAP quote (version 4) with its PCK certificate chain for TDX.
     pub attestation: Option<ByteBufB64>,
 }
 
@@ -34,16 +40,57 @@ pub struct TEEInfo {
 #[derive(
     Clone, Debug, CandidType, Deserialize, Serialize, Eq, PartialEq, Hash, Ord, PartialOrd,
 )]
+#[allow(non_camel_case_types)]
 pub enum TEEKind {
     /// AWS Nitro Enclaves, a TEE technology provided by Amazon Web Services.
     /// Nitro Enclaves provide isolated compute environments for sensitive workloads.
     NITRO,
+
+    /// AMD Secure Encrypted Virtualization with Secure Nested Paging,
+    /// confidential VMs on AMD EPYC processors.
+    SEV_SNP,
+
+    /// Intel Trust Domain Extensions, confidential VMs on Intel Xeon processors.
+    TDX,
+}
+
+impl TEEKind {
+    /// Returns the names of the measurements that identify the code running in the TEE.
+    pub fn measurement_names(&self) -> &'static [&'static str] {
+        match self {
+            TEEKind::NITRO => &["PCR0", "PCR1", "PCR2"],
+            TEEKind::SEV_SNP => &["MEASUREMENT"],
+            TEEKind::TDX => &["MRTD", "RTMR0", "RTMR1", "RTMR2", "RTMR3"],
+        }
+    }
+
+    /// Validates the measurements of an allowlist entry of the kind.
+    ///
+    /// The entry must contain the first measurement of the kind (PCR0, MEASUREMENT or MRTD),
+    /// may contain the others, and each of them is a SHA-384 digest.
+    pub fn validate_measurements(&self, measurements: &TEEMeasurements) -> Result<(), String> {
+        let names = self.measurement_names();
+        if !measurements.contains_key(names[0]) {
+            return Err(format!("{} is required for {}", names[0], self));
+        }
+        for (name, value) in measurements {
+            if !names.contains(&name.as_str()) {
+                return Err(format!("unknown measurement {name:?} for {}", self));
+            }
+            if value.len() != 48 {
+                return Err(format!("{name} should be 48 bytes, got {}", value.len()));
+            }
+        }
+        Ok(())
+    }
 }
 
 impl Display for TEEKind {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             TEEKind::NITRO => write!(f, "NITRO"),
+            TEEKind::SEV_SNP => write!(f, "SEV_SNP"),
+            TEEKind::TDX => write!(f, "TDX"),
         }
     }
 }
@@ -54,11 +101,69 @@ impl TryFrom<&str> for TEEKind {
     fn try_from(s: &str) -> Result<Self, Self::Error> {
         match s.to_uppercase().as_str() {
             "NITRO" => Ok(TEEKind::NITRO),
+            "SEV_SNP" | "SEV-SNP" => Ok(TEEKind::SEV_SNP),
+            "TDX" => Ok(TEEKind::TDX),
             _ => Err(format!("Unknown TEE kind: {}", s)),
         }
     }
 }
 
+/// Normalized measurements of a verified TEE attestation, keyed by the register name.
+/// (e.g. "PCR0", "PCR1", "PCR2" for NITRO, "MEASUREMENT" for SEV_SNP, "MRTD" and "RTMR0" for TDX)
+pub type TEEMeasurements = BTreeMap<String, ByteBufB64>;
+
+/// Returns true if every measurement of the allowlist entry equals the attested one.
+pub fn measurements_match(entry: &TEEMeasurements, attested: &TEEMeasurements) -> bool {
+    entry
+        .iter()
+        .all(|(name, value)| attested.get(name) == Some(value))
+}
+
+/// Returns the report data that a SEV_SNP report or a TDX quote must carry to bind the agent's
+/// public key and the challenge code: the SHA3-256 hash of the public key followed by the code,
+/// padded with zeros to 64 bytes.
+pub fn tee_report_data(pubkey: &[u8], nonce: &[u8]) -> [u8; 64] {
+    let mut data = Vec::with_capacity(pubkey.len() + nonce.len());
+    data.extend_from_slice(pubkey);
+    data.extend_from_slice(nonce);
+    let mut report_data = [0u8; 64];
+    report_data[..32].copy_from_slice(&sha3_256(&data));
+    report_data
+}
+
+/// Attestation of an AMD SEV-SNP confidential VM, encoded in CBOR as [`TEEInfo::attestation`].
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub struct SevSnpAttestation {
+    /// The attestation report (version 2 or later) returned by the AMD secure processor,
+    /// with [`tee_report_data`] as its report data.
+    pub report: ByteBufB64,
+
+    /// DER certificates from the VCEK (or VLEK) that signs the report up to the ASK (or ASVK).
+    /// The ARK that signs the last one is a root trusted by the registry.
+    pub certs: Vec<ByteBufB64>,
+}
+
+/// Normalized claims decoded from a verified TEE attestation.
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize, PartialEq, Eq)]
+pub struct TEEClaims {
+    /// The type of TEE technology that issued the attestation.
+    pub kind: TEEKind,
+
+    /// Identifier of the module that issued the attestation.
+    /// (e.g. the NSM module id of a Nitro enclave)
+    pub module_id: String,
+
+    /// Normalized measurements of the attested image.
+    pub measurements: TEEMeasurements,
+
+    /// Timestamp when the attestation was created in milliseconds since the Unix epoch.
+    /// SEV_SNP reports and TDX quotes carry no timestamp, it is the verification time for them.
+    pub attested_at: u64,
+
+    /// Timestamp when the registry verified the attestation in milliseconds since the Unix epoch.
+    pub verified_at: u64,
+}
+
 impl TEEInfo {
     /// Validates the TEE information to ensure it meets system requirements.
     ///
@@ -83,6 +188,36 @@ impl TEEInfo {
     }
 }
 
+/// Measurements of an AWS Nitro Enclaves image, each is a SHA-384 digest.
+///
+/// PCR0 measures the enclave image file, PCR1 the Linux kernel and bootstrap,
+/// and PCR2 the application. Together they identify the code running in the enclave.
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize, Eq, PartialEq, Ord, PartialOrd)]
+pub struct NitroPcrs {
+    pub pcr0: ByteBufB64,
+    pub pcr1: ByteBufB64,
+    pub pcr2: ByteBufB64,
+}
+
+impl NitroPcrs {
+    /// Validates that the measurements are SHA-384 digests.
+    pub fn validate(&self) -> Result<(), String> {
+        for (i, pcr) in [&self.pcr0, &self.pcr1, &self.pcr2].iter().enumerate() {
+            if pcr.len() != 48 {
+                return Err(format!("PCR{i} should be 48 bytes, got {}", pcr.len()));
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns true if the PCRs of an attestation document match the measurements.
+    pub fn matches(&self, pcrs: &BTreeMap<usize, ByteBufB64>) -> bool {
+        pcrs.get(&0) == Some(&self.pcr0)
+            && pcrs.get(&1) == Some(&self.pcr1)
+            && pcrs.get(&2) == Some(&self.pcr2)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -107,5 +242,65 @@ mod tests {
         assert_eq!(got, expected);
         let got: TEEKind = Value::deserialized(&expected).unwrap();
         assert_eq!(got, val);
+
+        assert_eq!(TEEKind::try_from("sev-snp").unwrap(), TEEKind::SEV_SNP);
+        assert_eq!(TEEKind::try_from("SEV_SNP").unwrap().to_string(), "SEV_SNP");
+        assert_eq!(TEEKind::try_from("tdx").unwrap(), TEEKind::TDX);
+        assert!(TEEKind::try_from("sgx").is_err());
+        let got = serde_json::to_string(&TEEKind::SEV_SNP).unwrap();
+        assert_eq!(got, "\"SEV_SNP\"");
+    }
+
+    #[test]
+    fn test_tee_measurements() {
+        let digest = |v: u8| ByteBufB64::from(vec![v; 48]);
+        let entry: TEEMeasurements = BTreeMap::from([
+            ("MRTD".to_string(), digest(1)),
+            ("RTMR0".to_string(), digest(2)),
+        ]);
+        assert!(TEEKind::TDX.validate_measurements(&entry).is_ok());
+        assert!(TEEKind::SEV_SNP.validate_measurements(&entry).is_err());
+
+        let rtmr0: TEEMeasurements = BTreeMap::from([("RTMR0".to_string(), digest(2))]);
+        assert!(TEEKind::TDX.validate_measurements(&rtmr0).is_err());
+        let short: TEEMeasurements =
+            BTreeMap::from([("MEASUREMENT".to_string(), vec![1u8; 32].into())]);
+        assert!(TEEKind::SEV_SNP.validate_measurements(&short).is_err());
+
+        let mut attested = entry.clone();
+        attested.insert("RTMR1".to_string(), digest(3));
+        assert!(measurements_match(&entry, &attested));
+        attested.insert("RTMR0".to_string(), digest(4));
+        assert!(!measurements_match(&entry, &attested));
+
+        let report_data = tee_report_data(&[1, 2], &[3]);
+        assert_eq!(report_data[..32], sha3_256(&[1, 2, 3]));
+        assert_eq!(report_data[32..], [0u8; 32]);
+    }
+
+    #[test]
+    fn test_nitro_pcrs() {
+        let pcrs = NitroPcrs {
+            pcr0: vec![0u8; 48].into(),
+            pcr1: vec![1u8; 48].into(),
+            pcr2: vec![2u8; 48].into(),
+        };
+        assert!(pcrs.validate().is_ok());
+
+        let mut doc: BTreeMap<usize, ByteBufB64> = BTreeMap::new();
+        doc.insert(0, vec![0u8; 48].into());
+        doc.insert(1, vec![1u8; 48].into());
+        assert!(!pcrs.matches(&doc));
+        doc.insert(2, vec![2u8; 48].into());
+        doc.insert(3, vec![3u8; 48].into());
+        assert!(pcrs.matches(&doc));
+        doc.insert(2, vec![0u8; 48].into());
+        assert!(!pcrs.matches(&doc));
+
+        let invalid = NitroPcrs {
+            pcr2: vec![2u8; 32].into(),
+            ..pcrs
+        };
+        assert!(invalid.validate().is_err());
     }
 }
@@ -1,12 +1,16 @@
 use anda_cloud_cdk::{
-    agent::{Agent, AgentEvent, AgentEventKind, ChallengeEnvelope},
-    registry::{RegistryError, RegistryState},
+    NitroPcrs, TEEKind, TEEMeasurements,
+    agent::{
+        Agent, AgentAction, AgentEnvelope, AgentEvent, AgentEventKind, AgentRevision,
+        ChallengeEnvelope, ChallengeRecord, RotationEnvelope,
+    },
+    registry::{AgentChange, ChallengerStats, ForeignAgent, RegistryError, RegistryState},
 };
 use candid::Principal;
-use ic_tee_nitro_attestation::parse_and_verify;
-use std::collections::BTreeMap;
+use ic_auth_types::{ByteArrayB64, ByteBufB64};
+use std::collections::{BTreeMap, BTreeSet};
 
-use crate::{MILLISECONDS, rand_bytes, store};
+use crate::{MILLISECONDS, rand_bytes, store, tee};
 
 #[ic_cdk::query]
 fn get_state() -> Result<RegistryState, RegistryError> {
@@ -21,35 +25,20 @@ pub async fn register(input: ChallengeEnvelope) -> Result<(), RegistryError> {
 
     let agent = input.authentication.sender();
     let challenger = input.request.authentication.unwrap().sender();
-    if !store::state::is_challenger(&challenger) {
-        return Err(RegistryError::Forbidden {
-            error: format!("challenger {} has no permission", challenger),
-        });
-    }
+    store::state::check_challenger(&challenger, true, now_ms)?;
 
-    if let Some(tee) = &input.tee {
-        let attestation = parse_and_verify(tee.attestation.as_ref().ok_or_else(|| {
-            RegistryError::BadRequest {
-                error: "attestation is not provided".to_string(),
-            }
-        })?)
-        .map_err(|error| RegistryError::BadRequest {
-            error: format!("attestation is not valid: {}", error),
-        })?;
-
-        if attestation.public_key.as_ref().map(|v| v.as_slice())
-            != Some(input.authentication.pubkey.as_slice())
-        {
-            return Err(RegistryError::BadRequest {
-                error: "attestation public key is not equal to agent public key".to_string(),
-            });
-        }
-        if attestation.nonce.as_ref().map(|v| v.as_slice()) != Some(input.request.code.as_slice()) {
-            return Err(RegistryError::BadRequest {
-                error: "attestation nonce is not equal to chanllenge code".to_string(),
-            });
-        }
-    }
+    let provider = input.request.agent.provider.as_ref().map(|p| p.id);
+    let tee = match input.tee {
+        Some(tee) => tee::verify(
+            &tee,
+            input.authentication.pubkey.as_slice(),
+            input.request.code.as_slice(),
+            provider,
+            now_ms,
+        )?
+        .map(|claims| (tee, claims)),
+        None => None,
+    };
 
     if let Some(canister) = &input.request.agent.handle_canister {
         store::state::check_handle(*canister, input.request.agent.handle.clone(), agent).await?;
@@ -58,16 +47,22 @@ pub async fn register(input: ChallengeEnvelope) -> Result<(), RegistryError> {
     let code = rand_bytes::<16>()
         .await
         .map_err(|error| RegistryError::Generic { error })?;
-    store::agent::register(
+    let displaced = store::agent::register(
         agent,
         challenger,
         input.request.agent,
-        input.tee,
+        tee,
         code.into(),
         now_ms,
     )?;
+    store::state::record_registration(&challenger, now_ms);
+    // the agent that lost the handle is certified without it
+    let mut ids = vec![agent];
+    ids.extend(displaced);
+    store::cert::refresh(&ids);
 
     store::state::notify_subscribers(AgentEvent {
+        seq: 0,
         id: agent,
         kind: AgentEventKind::Registered,
         ts: now_ms,
@@ -84,35 +79,20 @@ pub async fn challenge(input: ChallengeEnvelope) -> Result<(), RegistryError> {
 
     let agent = input.authentication.sender();
     let challenger = input.request.authentication.unwrap().sender();
-    if !store::state::is_challenger(&challenger) {
-        return Err(RegistryError::Forbidden {
-            error: format!("challenger {} has no permission", challenger),
-        });
-    }
+    store::state::check_challenger(&challenger, false, now_ms)?;
 
-    if let Some(tee) = &input.tee {
-        let attestation = parse_and_verify(tee.attestation.as_ref().ok_or_else(|| {
-            RegistryError::BadRequest {
-                error: "attestation is not provided".to_string(),
-            }
-        })?)
-        .map_err(|error| RegistryError::BadRequest {
-            error: format!("attestation is not valid: {}", error),
-        })?;
-
-        if attestation.public_key.as_ref().map(|v| v.as_slice())
-            != Some(input.authentication.pubkey.as_slice())
-        {
-            return Err(RegistryError::BadRequest {
-                error: "attestation public key is not equal to agent public key".to_string(),
-            });
-        }
-        if attestation.nonce.as_ref().map(|v| v.as_slice()) != Some(input.request.code.as_slice()) {
-            return Err(RegistryError::BadRequest {
-                error: "attestation nonce is not equal to chanllenge code".to_string(),
-            });
-        }
-    }
+    let provider = input.request.agent.provider.as_ref().map(|p| p.id);
+    let tee = match input.tee {
+        Some(tee) => tee::verify(
+            &tee,
+            input.authentication.pubkey.as_slice(),
+            input.request.code.as_slice(),
+            provider,
+            now_ms,
+        )?
+        .map(|claims| (tee, claims)),
+        None => None,
+    };
 
     if let Some(canister) = &input.request.agent.handle_canister {
         store::state::check_handle(*canister, input.request.agent.handle.clone(), agent).await?;
@@ -121,17 +101,21 @@ pub async fn challenge(input: ChallengeEnvelope) -> Result<(), RegistryError> {
     let new_code = rand_bytes::<16>()
         .await
         .map_err(|error| RegistryError::Generic { error })?;
-    store::agent::challenge(
+    let displaced = store::agent::challenge(
         agent,
         challenger,
         input.request.agent,
-        input.tee,
+        tee,
         input.request.code,
         new_code.into(),
         now_ms,
     )?;
+    let mut ids = vec![agent];
+    ids.extend(displaced);
+    store::cert::refresh(&ids);
 
     store::state::notify_subscribers(AgentEvent {
+        seq: 0,
         id: agent,
         kind: AgentEventKind::Challenged,
         ts: now_ms,
@@ -140,20 +124,164 @@ pub async fn challenge(input: ChallengeEnvelope) -> Result<(), RegistryError> {
     Ok(())
 }
 
+#[ic_cdk::update]
+pub fn unregister(input: AgentEnvelope) -> Result<(), RegistryError> {
+    let now_ms = ic_cdk::api::time() / MILLISECONDS;
+    let canister_self = ic_cdk::api::canister_self();
+    input.verify(now_ms, canister_self)?;
+
+    if input.request.action != AgentAction::Unregister {
+        return Err(RegistryError::BadRequest {
+            error: format!("invalid action {:?}", input.request.action),
+        });
+    }
+
+    let agent = input.authentication.sender();
+    store::agent::unregister(agent, Some(&input.request.code))?;
+    store::cert::refresh(&[agent]);
+
+    store::state::notify_subscribers(AgentEvent {
+        seq: 0,
+        id: agent,
+        kind: AgentEventKind::Unregistered,
+        ts: now_ms,
+    });
+
+    Ok(())
+}
+
+/// Moves the agent's registration to a new identity, with a request signed by both identities.
+/// The agent's challenge code is rotated, returns the new challenge code.
+#[ic_cdk::update]
+pub async fn rotate_identity(input: RotationEnvelope) -> Result<ByteArrayB64<16>, RegistryError> {
+    let now_ms = ic_cdk::api::time() / MILLISECONDS;
+    let canister_self = ic_cdk::api::canister_self();
+    input.verify(now_ms, canister_self)?;
+
+    let agent = input.authentication.sender();
+    let new_agent = input.request.new_id;
+    let tee = match input.tee {
+        Some(tee) => {
+            let provider = store::agent::get_agent(agent)?.info.provider.map(|p| p.id);
+            tee::verify(
+                &tee,
+                input.new_authentication.pubkey.as_slice(),
+                input.request.code.as_slice(),
+                provider,
+                now_ms,
+            )?
+            .map(|claims| (tee, claims))
+        }
+        None => None,
+    };
+
+    let new_code: ByteArrayB64<16> = rand_bytes::<16>()
+        .await
+        .map_err(|error| RegistryError::Generic { error })?
+        .into();
+    let event = store::agent::rotate(
+        agent,
+        new_agent,
+        &input.request.code,
+        new_code.clone(),
+        tee,
+        now_ms,
+    )?;
+    store::cert::refresh(&[agent, new_agent]);
+    store::state::notify_subscribers(event);
+
+    Ok(new_code)
+}
+
+/// Releases, transfers or accepts a registry-native handle with a request signed by the agent.
+/// The agent's challenge code is rotated, returns the new challenge code.
+#[ic_cdk::update]
+pub async fn update_handle(input: AgentEnvelope) -> Result<ByteArrayB64<16>, RegistryError> {
+    let now_ms = ic_cdk::api::time() / MILLISECONDS;
+    let canister_self = ic_cdk::api::canister_self();
+    input.verify(now_ms, canister_self)?;
+
+    let agent = input.authentication.sender();
+    let code = &input.request.code;
+    let new_code: ByteArrayB64<16> = rand_bytes::<16>()
+        .await
+        .map_err(|error| RegistryError::Generic { error })?
+        .into();
+    let events = match input.request.action {
+        AgentAction::ReleaseHandle => vec![store::agent::release_handle(
+            agent,
+            code,
+            new_code.clone(),
+            now_ms,
+        )?],
+        AgentAction::TransferHandle { to } => {
+            store::agent::transfer_handle(agent, code, new_code.clone(), to)?;
+            vec![]
+        }
+        AgentAction::AcceptHandle { handle } => {
+            store::agent::accept_handle(agent, code, new_code.clone(), handle, now_ms)?
+        }
+        action => {
+            return Err(RegistryError::BadRequest {
+                error: format!("invalid action {action:?}"),
+            });
+        }
+    };
+
+    let mut ids: Vec<Principal> = events.iter().map(|e| e.id).collect();
+    if !ids.contains(&agent) {
+        ids.push(agent);
+    }
+    store::cert::refresh(&ids);
+    for event in events {
+        store::state::notify_subscribers(event);
+    }
+
+    Ok(new_code)
+}
+
 #[ic_cdk::query]
 fn get_agent(id: Principal) -> Result<Agent, RegistryError> {
-    store::agent::get_agent(id)
+    let now_ms = ic_cdk::api::time() / MILLISECONDS;
+    store::agent::lookup(id).map(|agent| agent.with_effective_health_power(now_ms))
 }
 
 #[ic_cdk::query]
 fn get_agent_by_handle(handle: String) -> Result<Agent, RegistryError> {
-    store::agent::get_agent_by_handle(handle)
+    let now_ms = ic_cdk::api::time() / MILLISECONDS;
+    store::agent::get_agent_by_handle(handle).map(|agent| agent.with_effective_health_power(now_ms))
+}
+
+/// Gets the agents by their ids, with a result for each id in order.
+#[ic_cdk::query]
+fn get_agents(ids: Vec<Principal>) -> Result<Vec<Result<Agent, RegistryError>>, RegistryError> {
+    let now_ms = ic_cdk::api::time() / MILLISECONDS;
+    let agents = store::agent::get_agents(ids)?;
+    Ok(agents
+        .into_iter()
+        .map(|agent| agent.map(|agent| agent.with_effective_health_power(now_ms)))
+        .collect())
+}
+
+/// Gets the agents by their handles, with a result for each handle in order.
+#[ic_cdk::query]
+fn get_agents_by_handles(
+    handles: Vec<String>,
+) -> Result<Vec<Result<Agent, RegistryError>>, RegistryError> {
+    let now_ms = ic_cdk::api::time() / MILLISECONDS;
+    let agents = store::agent::get_agents_by_handles(handles)?;
+    Ok(agents
+        .into_iter()
+        .map(|agent| agent.map(|agent| agent.with_effective_health_power(now_ms)))
+        .collect())
 }
 
 #[ic_cdk::query]
 fn list(prev: Option<u64>, take: Option<u64>) -> Result<(u64, Vec<Agent>), RegistryError> {
     let take = take.unwrap_or(10).min(1000);
-    store::agent::list(prev, take as usize)
+    let now_ms = ic_cdk::api::time() / MILLISECONDS;
+    let (prev, agents) = store::agent::list(prev, take as usize)?;
+    Ok((prev, with_effective_health_power(agents, now_ms)))
 }
 
 #[ic_cdk::query]
@@ -163,8 +291,126 @@ fn list_by_health_power(take: Option<u64>) -> Result<Vec<Agent>, RegistryError>
     store::agent::list_by_health_power(take as usize, now_ms)
 }
 
+#[ic_cdk::query]
+fn list_by_protocol(
+    name: String,
+    prev: Option<u64>,
+    take: Option<u64>,
+) -> Result<(u64, Vec<Agent>), RegistryError> {
+    let take = take.unwrap_or(10).min(1000);
+    let now_ms = ic_cdk::api::time() / MILLISECONDS;
+    let (prev, agents) = store::agent::list_by_protocol(&name, prev, take as usize)?;
+    Ok((prev, with_effective_health_power(agents, now_ms)))
+}
+
+#[ic_cdk::query]
+fn list_by_provider(
+    provider: Principal,
+    prev: Option<u64>,
+    take: Option<u64>,
+) -> Result<(u64, Vec<Agent>), RegistryError> {
+    let take = take.unwrap_or(10).min(1000);
+    let now_ms = ic_cdk::api::time() / MILLISECONDS;
+    let (prev, agents) = store::agent::list_by_provider(provider, prev, take as usize)?;
+    Ok((prev, with_effective_health_power(agents, now_ms)))
+}
+
+#[ic_cdk::query]
+fn search(
+    query: String,
+    take: Option<u64>,
+    cursor: Option<u64>,
+) -> Result<(Option<u64>, Vec<Agent>), RegistryError> {
+    let take = take.unwrap_or(10).min(100);
+    let now_ms = ic_cdk::api::time() / MILLISECONDS;
+    let (cursor, agents) = store::agent::search(&query, take as usize, cursor)?;
+    Ok((cursor, with_effective_health_power(agents, now_ms)))
+}
+
+#[ic_cdk::query]
+fn get_challenge_history(
+    id: Principal,
+    prev: Option<u64>,
+    take: Option<u64>,
+) -> Result<Vec<ChallengeRecord>, RegistryError> {
+    let take = take.unwrap_or(10).min(1000);
+    store::agent::get_challenge_history(id, prev, take as usize)
+}
+
+#[ic_cdk::query]
+fn get_agent_revisions(
+    id: Principal,
+    prev: Option<u64>,
+    take: Option<u64>,
+) -> Result<Vec<AgentRevision>, RegistryError> {
+    let take = take.unwrap_or(10).min(1000);
+    store::agent::get_agent_revisions(id, prev, take as usize)
+}
+
+#[ic_cdk::query]
+fn get_reserved_handles() -> Result<BTreeSet<String>, RegistryError> {
+    Ok(store::state::get_reserved_handles())
+}
+
+#[ic_cdk::query]
+fn get_nitro_pcrs(provider: Option<Principal>) -> Result<Vec<NitroPcrs>, RegistryError> {
+    Ok(store::state::get_nitro_pcrs(provider))
+}
+
+#[ic_cdk::query]
+fn get_tee_roots(kind: TEEKind) -> Result<Vec<ByteBufB64>, RegistryError> {
+    Ok(store::state::get_tee_roots(&kind))
+}
+
+#[ic_cdk::query]
+fn get_tee_measurements(
+    kind: TEEKind,
+    provider: Option<Principal>,
+) -> Result<Vec<TEEMeasurements>, RegistryError> {
+    Ok(store::state::get_tee_measurements(&kind, provider))
+}
+
+#[ic_cdk::query]
+fn get_challenger_stats() -> Result<Vec<ChallengerStats>, RegistryError> {
+    Ok(store::state::get_challenger_stats())
+}
+
+#[ic_cdk::query]
+fn get_events(after: Option<u64>, take: Option<u64>) -> Result<Vec<AgentEvent>, RegistryError> {
+    let take = take.unwrap_or(100).min(1000);
+    Ok(store::outbox::get_events(after, take as usize))
+}
+
+#[ic_cdk::query]
+fn get_changes(after: Option<u64>, take: Option<u64>) -> Result<Vec<AgentChange>, RegistryError> {
+    let take = take.unwrap_or(100).min(1000);
+    store::agent::get_changes(after, take as usize)
+}
+
+#[ic_cdk::query]
+fn get_foreign_agent(id: Principal) -> Result<ForeignAgent, RegistryError> {
+    store::peer::get_foreign_agent(id)
+}
+
+#[ic_cdk::query]
+fn list_foreign_agents(
+    prev: Option<Principal>,
+    take: Option<u64>,
+) -> Result<Vec<ForeignAgent>, RegistryError> {
+    let take = take.unwrap_or(10).min(1000);
+    store::peer::list_foreign_agents(prev, take as usize)
+}
+
 #[ic_cdk::query]
 fn last_challenged(take: Option<u64>) -> Result<BTreeMap<Principal, u64>, RegistryError> {
     let take = take.unwrap_or(100).min(10000);
     store::agent::last_challenged(take as usize)
 }
+
+/// Fills the effective health power of the agents at `now_ms`.
+pub fn with_effective_health_power(agents: Vec<Agent>, now_ms: u64) -> Vec<Agent> {
+    agents
+        .into_iter()
+        .map(|agent| agent.with_effective_health_power(now_ms))
+        .collect()
+}
@@ -1,7 +1,13 @@
+use anda_cloud_cdk::{
+    NitroPcrs, TEEKind, TEEMeasurements,
+    agent::{AgentEvent, AgentEventKind, AgentStatus, validate_handle},
+    registry::{ChallengerLimits, SubscriberStatus},
+};
 use candid::{CandidType, IDLValue, Principal, pretty::candid::value::pp_value};
+use ic_auth_types::ByteBufB64;
 use std::collections::BTreeSet;
 
-use crate::{is_controller, store, validate_principals};
+use crate::{MILLISECONDS, is_controller, store, tee, validate_principals};
 
 #[ic_cdk::update(guard = "is_controller")]
 fn admin_add_peers(args: BTreeSet<Principal>) -> Result<(), String> {
@@ -17,8 +23,9 @@ fn admin_remove_peers(args: BTreeSet<Principal>) -> Result<(), String> {
     validate_principals(&args)?;
     store::state::with_mut(|s| {
         s.peers.retain(|v| !args.contains(v));
-        Ok(())
-    })
+    });
+    store::peer::remove_peers(&args);
+    Ok(())
 }
 
 #[ic_cdk::update]
@@ -77,6 +84,7 @@ fn admin_remove_challengers(args: BTreeSet<Principal>) -> Result<(), String> {
     validate_principals(&args)?;
     store::state::with_mut(|s| {
         s.challengers.retain(|v| !args.contains(v));
+        s.challenger_usage.retain(|k, _| !args.contains(k));
         Ok(())
     })
 }
@@ -93,9 +101,161 @@ fn validate_admin_remove_challengers(args: BTreeSet<Principal>) -> Result<String
     pretty_format(&args)
 }
 
+#[ic_cdk::update(guard = "is_controller")]
+fn admin_set_challenger_limits(
+    challenger: Option<Principal>,
+    limits: Option<ChallengerLimits>,
+) -> Result<(), String> {
+    validate_challenger_limits(&challenger, &limits)?;
+    store::state::set_challenger_limits(challenger, limits);
+    Ok(())
+}
+
+#[ic_cdk::update]
+fn validate_admin_set_challenger_limits(
+    challenger: Option<Principal>,
+    limits: Option<ChallengerLimits>,
+) -> Result<String, String> {
+    validate_challenger_limits(&challenger, &limits)?;
+    pretty_format(&(challenger, limits))
+}
+
+#[ic_cdk::update(guard = "is_controller")]
+fn admin_add_nitro_pcrs(provider: Option<Principal>, pcrs: Vec<NitroPcrs>) -> Result<(), String> {
+    validate_nitro_pcrs(&provider, &pcrs)?;
+    store::state::add_nitro_pcrs(provider, pcrs);
+    Ok(())
+}
+
+#[ic_cdk::update(guard = "is_controller")]
+fn admin_remove_nitro_pcrs(
+    provider: Option<Principal>,
+    pcrs: Vec<NitroPcrs>,
+) -> Result<(), String> {
+    validate_nitro_pcrs(&provider, &pcrs)?;
+    store::state::remove_nitro_pcrs(provider, &pcrs);
+    Ok(())
+}
+
+#[ic_cdk::update]
+fn validate_admin_add_nitro_pcrs(
+    provider: Option<Principal>,
+    pcrs: Vec<NitroPcrs>,
+) -> Result<String, String> {
+    validate_nitro_pcrs(&provider, &pcrs)?;
+    pretty_format(&(provider, pcrs))
+}
+
+#[ic_cdk::update]
+fn validate_admin_remove_nitro_pcrs(
+    provider: Option<Principal>,
+    pcrs: Vec<NitroPcrs>,
+) -> Result<String, String> {
+    validate_nitro_pcrs(&provider, &pcrs)?;
+    pretty_format(&(provider, pcrs))
+}
+
+#[ic_cdk::update(guard = "is_controller")]
+fn admin_add_tee_roots(kind: TEEKind, certs: Vec<ByteBufB64>) -> Result<(), String> {
+    validate_tee_roots(&kind, &certs)?;
+    store::state::add_tee_roots(kind, certs);
+    Ok(())
+}
+
+#[ic_cdk::update(guard = "is_controller")]
+fn admin_remove_tee_roots(kind: TEEKind, certs: Vec<ByteBufB64>) -> Result<(), String> {
+    validate_tee_roots(&kind, &certs)?;
+    store::state::remove_tee_roots(kind, &certs);
+    Ok(())
+}
+
+#[ic_cdk::update]
+fn validate_admin_add_tee_roots(kind: TEEKind, certs: Vec<ByteBufB64>) -> Result<String, String> {
+    validate_tee_roots(&kind, &certs)?;
+    pretty_format(&(kind, certs))
+}
+
+#[ic_cdk::update]
+fn validate_admin_remove_tee_roots(
+    kind: TEEKind,
+    certs: Vec<ByteBufB64>,
+) -> Result<String, String> {
+    validate_tee_roots(&kind, &certs)?;
+    pretty_format(&(kind, certs))
+}
+
+#[ic_cdk::update(guard = "is_controller")]
+fn admin_add_tee_measurements(
+    kind: TEEKind,
+    provider: Option<Principal>,
+    measurements: Vec<TEEMeasurements>,
+) -> Result<(), String> {
+    validate_tee_measurements(&kind, &provider, &measurements)?;
+    store::state::add_tee_measurements(kind, provider, measurements);
+    Ok(())
+}
+
+#[ic_cdk::update(guard = "is_controller")]
+fn admin_remove_tee_measurements(
+    kind: TEEKind,
+    provider: Option<Principal>,
+    measurements: Vec<TEEMeasurements>,
+) -> Result<(), String> {
+    validate_tee_measurements(&kind, &provider, &measurements)?;
+    store::state::remove_tee_measurements(kind, provider, &measurements);
+    Ok(())
+}
+
+#[ic_cdk::update]
+fn validate_admin_add_tee_measurements(
+    kind: TEEKind,
+    provider: Option<Principal>,
+    measurements: Vec<TEEMeasurements>,
+) -> Result<String, String> {
+    validate_tee_measurements(&kind, &provider, &measurements)?;
+    pretty_format(&(kind, provider, measurements))
+}
+
+#[ic_cdk::update]
+fn validate_admin_remove_tee_measurements(
+    kind: TEEKind,
+    provider: Option<Principal>,
+    measurements: Vec<TEEMeasurements>,
+) -> Result<String, String> {
+    validate_tee_measurements(&kind, &provider, &measurements)?;
+    pretty_format(&(kind, provider, measurements))
+}
+
+#[ic_cdk::update(guard = "is_controller")]
+fn admin_add_reserved_handles(args: BTreeSet<String>) -> Result<(), String> {
+    validate_handles(&args)?;
+    store::state::add_reserved_handles(args);
+    Ok(())
+}
+
+#[ic_cdk::update(guard = "is_controller")]
+fn admin_remove_reserved_handles(args: BTreeSet<String>) -> Result<(), String> {
+    validate_handles(&args)?;
+    store::state::remove_reserved_handles(&args);
+    Ok(())
+}
+
+#[ic_cdk::update]
+fn validate_admin_add_reserved_handles(args: BTreeSet<String>) -> Result<String, String> {
+    validate_handles(&args)?;
+    pretty_format(&args)
+}
+
+#[ic_cdk::update]
+fn validate_admin_remove_reserved_handles(args: BTreeSet<String>) -> Result<String, String> {
+    validate_handles(&args)?;
+    pretty_format(&args)
+}
+
 #[ic_cdk::update(guard = "is_controller")]
 fn admin_add_subscribers(args: BTreeSet<Principal>) -> Result<(), String> {
     validate_principals(&args)?;
+    store::outbox::add_subscribers(&args);
     store::state::with_mut(|s| {
         s.subscribers.extend(args);
         Ok(())
@@ -107,8 +267,14 @@ fn admin_remove_subscribers(args: BTreeSet<Principal>) -> Result<(), String> {
     validate_principals(&args)?;
     store::state::with_mut(|s| {
         s.subscribers.retain(|v| !args.contains(v));
-        Ok(())
-    })
+    });
+    store::outbox::remove_subscribers(&args);
+    Ok(())
+}
+
+#[ic_cdk::query(guard = "is_controller")]
+fn admin_get_subscriber_status() -> Result<Vec<SubscriberStatus>, String> {
+    Ok(store::outbox::get_subscriber_status())
 }
 
 #[ic_cdk::update]
@@ -123,6 +289,166 @@ fn validate_admin_remove_subscribers(args: BTreeSet<Principal>) -> Result<String
     pretty_format(&args)
 }
 
+#[ic_cdk::update(guard = "is_controller")]
+fn admin_unregister_agents(args: BTreeSet<Principal>) -> Result<(), String> {
+    validate_agents(&args)?;
+    let now_ms = ic_cdk::api::time() / MILLISECONDS;
+    for id in args {
+        store::agent::unregister(id, None).map_err(|err| err.to_string())?;
+        store::cert::refresh(&[id]);
+        store::state::notify_subscribers(AgentEvent {
+            seq: 0,
+            id,
+            kind: AgentEventKind::Unregistered,
+            ts: now_ms,
+        });
+    }
+    Ok(())
+}
+
+#[ic_cdk::update]
+fn validate_admin_unregister_agents(args: BTreeSet<Principal>) -> Result<String, String> {
+    validate_agents(&args)?;
+    pretty_format(&args)
+}
+
+#[ic_cdk::update(guard = "is_controller")]
+fn admin_suspend_agent(id: Principal, reason: String) -> Result<(), String> {
+    validate_moderation(&id, &reason)?;
+    moderate_agent(id, AgentStatus::Suspended, Some(reason))
+}
+
+#[ic_cdk::update(guard = "is_controller")]
+fn admin_ban_agent(id: Principal, reason: String) -> Result<(), String> {
+    validate_moderation(&id, &reason)?;
+    moderate_agent(id, AgentStatus::Banned, Some(reason))
+}
+
+#[ic_cdk::update(guard = "is_controller")]
+fn admin_unban_agent(id: Principal) -> Result<(), String> {
+    validate_agents(&BTreeSet::from([id]))?;
+    moderate_agent(id, AgentStatus::Active, None)
+}
+
+#[ic_cdk::update]
+fn validate_admin_suspend_agent(id: Principal, reason: String) -> Result<String, String> {
+    validate_moderation(&id, &reason)?;
+    pretty_format(&(id, reason))
+}
+
+#[ic_cdk::update]
+fn validate_admin_ban_agent(id: Principal, reason: String) -> Result<String, String> {
+    validate_moderation(&id, &reason)?;
+    pretty_format(&(id, reason))
+}
+
+#[ic_cdk::update]
+fn validate_admin_unban_agent(id: Principal) -> Result<String, String> {
+    validate_agents(&BTreeSet::from([id]))?;
+    pretty_format(&id)
+}
+
+fn moderate_agent(
+    id: Principal,
+    status: AgentStatus,
+    reason: Option<String>,
+) -> Result<(), String> {
+    let now_ms = ic_cdk::api::time() / MILLISECONDS;
+    let event =
+        store::agent::moderate(id, status, reason, now_ms).map_err(|err| err.to_string())?;
+    store::cert::refresh(&[id]);
+    store::state::notify_subscribers(event);
+    Ok(())
+}
+
+fn validate_moderation(id: &Principal, reason: &str) -> Result<(), String> {
+    validate_agents(&BTreeSet::from([*id]))?;
+    if reason.trim().is_empty() {
+        return Err("reason is required".to_string());
+    }
+    if reason.len() > 256 {
+        return Err("reason cannot be longer than 256 bytes".to_string());
+    }
+    Ok(())
+}
+
+fn validate_challenger_limits(
+    challenger: &Option<Principal>,
+    limits: &Option<ChallengerLimits>,
+) -> Result<(), String> {
+    if let Some(challenger) = challenger {
+        validate_principals(&BTreeSet::from([*challenger]))?;
+    }
+    if let Some(limits) = limits
+        && limits.burst > 0
+        && limits.refill_per_minute == 0
+    {
+        return Err("refill_per_minute should be greater than 0 if burst is limited".to_string());
+    }
+    Ok(())
+}
+
+fn validate_nitro_pcrs(provider: &Option<Principal>, pcrs: &[NitroPcrs]) -> Result<(), String> {
+    if let Some(provider) = provider {
+        validate_principals(&BTreeSet::from([*provider]))?;
+    }
+    if pcrs.is_empty() {
+        return Err("pcrs cannot be empty".to_string());
+    }
+    for v in pcrs {
+        v.validate()?;
+    }
+    Ok(())
+}
+
+fn validate_tee_roots(kind: &TEEKind, certs: &[ByteBufB64]) -> Result<(), String> {
+    if certs.is_empty() {
+        return Err("certs cannot be empty".to_string());
+    }
+    for cert in certs {
+        tee::validate_root(kind, cert)?;
+    }
+    Ok(())
+}
+
+fn validate_tee_measurements(
+    kind: &TEEKind,
+    provider: &Option<Principal>,
+    measurements: &[TEEMeasurements],
+) -> Result<(), String> {
+    if *kind == TEEKind::NITRO {
+        return Err("use admin_add_nitro_pcrs for NITRO".to_string());
+    }
+    if let Some(provider) = provider {
+        validate_principals(&BTreeSet::from([*provider]))?;
+    }
+    if measurements.is_empty() {
+        return Err("measurements cannot be empty".to_string());
+    }
+    for v in measurements {
+        kind.validate_measurements(v)?;
+    }
+    Ok(())
+}
+
+fn validate_handles(handles: &BTreeSet<String>) -> Result<(), String> {
+    if handles.is_empty() {
+        return Err("handles cannot be empty".to_string());
+    }
+    for handle in handles {
+        validate_handle(handle).map_err(|err| format!("invalid handle {handle:?}: {err}"))?;
+    }
+    Ok(())
+}
+
+fn validate_agents(agents: &BTreeSet<Principal>) -> Result<(), String> {
+    validate_principals(agents)?;
+    for id in agents {
+        store::agent::get_agent(*id).map_err(|err| err.to_string())?;
+    }
+    Ok(())
+}
+
 fn pretty_format<T>(data: &T) -> Result<String, String>
 where
     T: CandidType,
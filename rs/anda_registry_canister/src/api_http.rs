@@ -1,4 +1,7 @@
-use anda_cloud_cdk::{agent::ChallengeEnvelope, registry::RegistryError};
+use anda_cloud_cdk::{
+    agent::{Agent, AgentEnvelope, ChallengeEnvelope, RotationEnvelope},
+    registry::RegistryError,
+};
 use base64::{Engine, engine::general_purpose::STANDARD as BASE64};
 use candid::{CandidType, Principal};
 use cbor2::from_slice;
@@ -8,7 +11,7 @@ use serde::{Deserialize, Serialize};
 use serde_bytes::ByteBuf;
 use url::Url;
 
-use crate::{api, store};
+use crate::{MILLISECONDS, api, store};
 
 #[derive(CandidType, Deserialize, Serialize, Clone, Default)]
 pub struct HttpResponse {
@@ -28,12 +31,14 @@ pub struct HttpResponse {
 
 static CBOR: &str = "application/cbor";
 static JSON: &str = "application/json";
+static WELL_KNOWN_AGENTS: &str = "/.well-known/agents/";
 static IC_CERTIFICATE_HEADER: &str = "ic-certificate";
 static IC_CERTIFICATE_EXPRESSION_HEADER: &str = "ic-certificateexpression";
 
 // request url example:
 // https://lfcwh-piaaa-aaaap-an2fa-cai.icp0.io/lookup?handle=abc123
 // https://lfcwh-piaaa-aaaap-an2fa-cai.icp0.io/lookup?id=nprym-ylvyz-ig3fr-lgcmn-zzzt4-tyuix-3v6bm-fsel7-6lq6x-zh2w7-zqe
+// https://lfcwh-piaaa-aaaap-an2fa-cai.icp0.io/lookup?id=nprym-ylvyz-ig3fr-lgcmn-zzzt4-tyuix-3v6bm-fsel7-6lq6x-zh2w7-zqe&handle=abc123
 #[ic_cdk::query(hidden = true)]
 async fn http_request(request: HttpRequest<'static>) -> HttpResponse {
     if request.method().as_str() == "POST" {
@@ -45,6 +50,14 @@ async fn http_request(request: HttpRequest<'static>) -> HttpResponse {
         };
     }
 
+    if request.method().as_str() == "GET"
+        && let Ok(url) = parse_url(request.url())
+        && url.path() == "/lookup"
+        && url.query_pairs().count() <= 1
+    {
+        return lookup(request.url(), url, supports_cbor(request.headers()));
+    }
+
     let witness = store::state::http_tree_with(|t| {
         t.witness(&store::state::DEFAULT_CERT_ENTRY, request.url())
             .expect("get witness failed")
@@ -85,12 +98,29 @@ async fn http_request(request: HttpRequest<'static>) -> HttpResponse {
         }
     };
 
-    let in_cbor = supports_cbor(request.headers());
+    // discovery documents are always in JSON
+    let in_cbor =
+        supports_cbor(request.headers()) && !req_url.path().starts_with(WELL_KNOWN_AGENTS);
 
     let rt = match (request.method().as_str(), req_url.path()) {
         ("HEAD", _) => Ok(Vec::new()),
         ("GET", "/state") => get_state(in_cbor),
-        ("GET", "/lookup") => lookup(req_url, in_cbor),
+        ("GET", "/challengers") => to_body(&store::state::get_challenger_stats(), in_cbor),
+        ("GET", "/search") => search(req_url, in_cbor),
+        ("GET", "/events") => get_events(req_url, in_cbor),
+        ("GET", "/protocol") => list_by_protocol(req_url, in_cbor),
+        ("GET", "/provider") => list_by_provider(req_url, in_cbor),
+        ("GET", "/agents") => list(req_url, in_cbor),
+        ("GET", "/agents/top") => list_by_health_power(req_url, in_cbor),
+        ("GET", "/agents/recent") => last_challenged(req_url, in_cbor),
+        // lookups of more than one key are served as an uncertified batch
+        ("GET", "/lookup") | ("GET", "/agents/batch") => get_agents(req_url, in_cbor),
+        ("GET", path) if path.starts_with("/agents/") => {
+            get_agent(&path["/agents/".len()..], in_cbor)
+        }
+        ("GET", path) if path.starts_with(WELL_KNOWN_AGENTS) => {
+            well_known_agent(&path[WELL_KNOWN_AGENTS.len()..])
+        }
         (method, path) => Err(RegistryError::NotSupported {
             error: format!("method {method}, path: {path}"),
         }),
@@ -144,6 +174,9 @@ async fn http_request_update(request: HttpUpdateRequest<'static>) -> HttpRespons
     let rt = match (request.method().as_str(), req_url.path()) {
         ("POST", "/register") => register(request.body(), in_cbor).await,
         ("POST", "/challenge") => challenge(request.body(), in_cbor).await,
+        ("POST", "/unregister") => unregister(request.body(), in_cbor),
+        ("POST", "/handle") => update_handle(request.body(), in_cbor).await,
+        ("POST", "/rotate") => rotate_identity(request.body(), in_cbor).await,
         (method, path) => Err(RegistryError::NotSupported {
             error: format!("method {method}, path: {path}"),
         }),
@@ -189,49 +222,259 @@ fn get_state(in_cbor: bool) -> Result<Vec<u8>, RegistryError> {
     }
 }
 
-fn lookup(url: Url, in_cbor: bool) -> Result<Vec<u8>, RegistryError> {
-    if let Some((key, value)) = url.query_pairs().next() {
+// Serves the lookup response with the certification of the agent record.
+fn lookup(raw_url: &str, url: Url, in_cbor: bool) -> HttpResponse {
+    let (key, value) = url
+        .query_pairs()
+        .next()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .unwrap_or_default();
+    let (res, entry) = store::cert::lookup(&key, &value, in_cbor);
+    let witness =
+        store::state::http_tree_with(|t| t.witness(&entry, raw_url).expect("get witness failed"));
+    let certified_data = ic_cdk::api::data_certificate().expect("no data certificate available");
+
+    let mut headers = vec![("x-content-type-options".to_string(), "nosniff".to_string())];
+    headers.extend(res.headers().iter().cloned());
+    headers.push((
+        IC_CERTIFICATE_HEADER.to_string(),
+        format!(
+            "certificate=:{}:, tree=:{}:, expr_path=:{}:, version=2",
+            BASE64.encode(certified_data),
+            BASE64.encode(cbor_into_vec(&witness).expect("failed to serialize witness")),
+            BASE64.encode(
+                cbor_into_vec(&store::cert::LOOKUP_EXPR_PATH.to_expr_path())
+                    .expect("failed to serialize expr path")
+            )
+        ),
+    ));
+    headers.push(("content-length".to_string(), res.body().len().to_string()));
+    HttpResponse {
+        status_code: res.status_code().as_u16(),
+        headers,
+        body: res.body().to_vec().into(),
+        upgrade: None,
+    }
+}
+
+// Gets the agents by the `id` and `handle` query parameters, with a result for each
+// parameter in order. Batch lookups are not certified.
+// request url example:
+// https://lfcwh-piaaa-aaaap-an2fa-cai.icp0.io/agents/batch?id=nprym-ylvyz-ig3fr-lgcmn-zzzt4-tyuix-3v6bm-fsel7-6lq6x-zh2w7-zqe&handle=abc123
+fn get_agents(url: Url, in_cbor: bool) -> Result<Vec<u8>, RegistryError> {
+    let pairs: Vec<(String, String)> = url
+        .query_pairs()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect();
+    store::agent::check_batch(pairs.len())?;
+
+    let now_ms = ic_cdk::api::time() / MILLISECONDS;
+    let mut rt: Vec<Result<Agent, RegistryError>> = Vec::with_capacity(pairs.len());
+    for (key, value) in pairs {
+        let agent = match key.as_str() {
+            "id" => Principal::from_text(&value)
+                .map_err(|err| RegistryError::BadRequest {
+                    error: format!("invalid id: {value}, error: {err}"),
+                })
+                .and_then(store::agent::lookup),
+            "handle" => store::agent::get_agent_by_handle(value),
+            _ => {
+                return Err(RegistryError::BadRequest {
+                    error: format!("invalid query parameter: {key}"),
+                });
+            }
+        };
+        rt.push(agent.map(|agent| agent.with_effective_health_power(now_ms)));
+    }
+    to_body(&rt, in_cbor)
+}
+
+// request url example:
+// https://lfcwh-piaaa-aaaap-an2fa-cai.icp0.io/search?q=recipe&take=10&cursor=42
+fn search(url: Url, in_cbor: bool) -> Result<Vec<u8>, RegistryError> {
+    let mut query = None;
+    let mut take = 10u64;
+    let mut cursor = None;
+    for (key, value) in url.query_pairs() {
+        match key.as_ref() {
+            "q" => query = Some(value.to_string()),
+            "take" => take = parse_u64(&key, &value)?.min(100),
+            "cursor" => cursor = Some(parse_u64(&key, &value)?),
+            other => Err(RegistryError::BadRequest {
+                error: format!("invalid query parameter: {other}={value}"),
+            })?,
+        }
+    }
+    let query = query.ok_or_else(|| RegistryError::BadRequest {
+        error: "missing query parameter: q".to_string(),
+    })?;
+
+    let now_ms = ic_cdk::api::time() / MILLISECONDS;
+    let (cursor, agents) = store::agent::search(&query, take as usize, cursor)?;
+    to_body(
+        &(cursor, api::with_effective_health_power(agents, now_ms)),
+        in_cbor,
+    )
+}
+
+// request url example:
+// https://lfcwh-piaaa-aaaap-an2fa-cai.icp0.io/protocol?name=A2A&take=10&prev=42
+fn list_by_protocol(url: Url, in_cbor: bool) -> Result<Vec<u8>, RegistryError> {
+    let mut name = None;
+    let mut take = 10u64;
+    let mut prev = None;
+    for (key, value) in url.query_pairs() {
+        match key.as_ref() {
+            "name" => name = Some(value.to_string()),
+            "take" => take = parse_u64(&key, &value)?.min(1000),
+            "prev" => prev = Some(parse_u64(&key, &value)?),
+            other => Err(RegistryError::BadRequest {
+                error: format!("invalid query parameter: {other}={value}"),
+            })?,
+        }
+    }
+    let name = name.ok_or_else(|| RegistryError::BadRequest {
+        error: "missing query parameter: name".to_string(),
+    })?;
+
+    let now_ms = ic_cdk::api::time() / MILLISECONDS;
+    let (prev, agents) = store::agent::list_by_protocol(&name, prev, take as usize)?;
+    to_body(
+        &(prev, api::with_effective_health_power(agents, now_ms)),
+        in_cbor,
+    )
+}
+
+// request url example:
+// https://lfcwh-piaaa-aaaap-an2fa-cai.icp0.io/provider?id=nprym-ylvyz-ig3fr-lgcmn-zzzt4-tyuix-3v6bm-fsel7-6lq6x-zh2w7-zqe&take=10&prev=42
+fn list_by_provider(url: Url, in_cbor: bool) -> Result<Vec<u8>, RegistryError> {
+    let mut provider = None;
+    let mut take = 10u64;
+    let mut prev = None;
+    for (key, value) in url.query_pairs() {
         match key.as_ref() {
             "id" => {
-                let id = Principal::from_text(value.as_ref()).map_err(|err| {
+                provider = Some(Principal::from_text(value.as_ref()).map_err(|err| {
                     RegistryError::BadRequest {
                         error: format!("invalid id: {value}, error: {err}"),
                     }
-                })?;
-                let agent = store::agent::get_agent(id)?;
-                if in_cbor {
-                    return cbor_into_vec(&agent).map_err(|err| RegistryError::Generic {
-                        error: format!("failed to serialize agent in CBOR, error: {err}"),
-                    });
-                } else {
-                    return serde_json::to_vec(&agent).map_err(|err| RegistryError::Generic {
-                        error: format!("failed to serialize agent in JSON, error: {err}"),
-                    });
-                }
-            }
-            "handle" => {
-                let agent = store::agent::get_agent_by_handle(value.to_string())?;
-                if in_cbor {
-                    return cbor_into_vec(&agent).map_err(|err| RegistryError::Generic {
-                        error: format!("failed to serialize agent in CBOR, error: {err}"),
-                    });
-                } else {
-                    return serde_json::to_vec(&agent).map_err(|err| RegistryError::Generic {
-                        error: format!("failed to serialize agent in JSON, error: {err}"),
-                    });
-                }
-            }
-            other => {
-                Err(RegistryError::BadRequest {
-                    error: format!("invalid query parameter: {other}={value}"),
-                })?;
+                })?)
             }
+            "take" => take = parse_u64(&key, &value)?.min(1000),
+            "prev" => prev = Some(parse_u64(&key, &value)?),
+            other => Err(RegistryError::BadRequest {
+                error: format!("invalid query parameter: {other}={value}"),
+            })?,
         }
     }
+    let provider = provider.ok_or_else(|| RegistryError::BadRequest {
+        error: "missing query parameter: id".to_string(),
+    })?;
 
-    Err(RegistryError::BadRequest {
-        error: "missing query parameter".to_string(),
-    })
+    let now_ms = ic_cdk::api::time() / MILLISECONDS;
+    let (prev, agents) = store::agent::list_by_provider(provider, prev, take as usize)?;
+    to_body(
+        &(prev, api::with_effective_health_power(agents, now_ms)),
+        in_cbor,
+    )
+}
+
+// request url example:
+// https://lfcwh-piaaa-aaaap-an2fa-cai.icp0.io/agents?prev=42&take=10
+fn list(url: Url, in_cbor: bool) -> Result<Vec<u8>, RegistryError> {
+    let mut take = 10u64;
+    let mut prev = None;
+    for (key, value) in url.query_pairs() {
+        match key.as_ref() {
+            "take" => take = parse_u64(&key, &value)?.min(1000),
+            "prev" => prev = Some(parse_u64(&key, &value)?),
+            other => Err(RegistryError::BadRequest {
+                error: format!("invalid query parameter: {other}={value}"),
+            })?,
+        }
+    }
+
+    let now_ms = ic_cdk::api::time() / MILLISECONDS;
+    let (prev, agents) = store::agent::list(prev, take as usize)?;
+    to_body(
+        &(prev, api::with_effective_health_power(agents, now_ms)),
+        in_cbor,
+    )
+}
+
+// request url example:
+// https://lfcwh-piaaa-aaaap-an2fa-cai.icp0.io/agents/top?take=10
+fn list_by_health_power(url: Url, in_cbor: bool) -> Result<Vec<u8>, RegistryError> {
+    let take = parse_take(&url, 10, 1000)?;
+    let now_ms = ic_cdk::api::time() / MILLISECONDS;
+    let rt = store::agent::list_by_health_power(take as usize, now_ms)?;
+    to_body(&rt, in_cbor)
+}
+
+// request url example:
+// https://lfcwh-piaaa-aaaap-an2fa-cai.icp0.io/agents/recent?take=100
+fn last_challenged(url: Url, in_cbor: bool) -> Result<Vec<u8>, RegistryError> {
+    let take = parse_take(&url, 100, 10000)?;
+    let rt = store::agent::last_challenged(take as usize)?;
+    to_body(&rt, in_cbor)
+}
+
+// request url example:
+// https://lfcwh-piaaa-aaaap-an2fa-cai.icp0.io/agents/nprym-ylvyz-ig3fr-lgcmn-zzzt4-tyuix-3v6bm-fsel7-6lq6x-zh2w7-zqe
+fn get_agent(id: &str, in_cbor: bool) -> Result<Vec<u8>, RegistryError> {
+    let id = Principal::from_text(id).map_err(|err| RegistryError::BadRequest {
+        error: format!("invalid id: {id}, error: {err}"),
+    })?;
+    let now_ms = ic_cdk::api::time() / MILLISECONDS;
+    let rt = store::agent::lookup(id)?.with_effective_health_power(now_ms);
+    to_body(&rt, in_cbor)
+}
+
+// Serves the discovery documents of the agent, the path is `{handle}/agent.json` for
+// the A2A Agent Card, or `{handle}/mcp.json` for the MCP server descriptor.
+// request url example:
+// https://lfcwh-piaaa-aaaap-an2fa-cai.icp0.io/.well-known/agents/abc123/agent.json
+fn well_known_agent(path: &str) -> Result<Vec<u8>, RegistryError> {
+    let (handle, doc) = path
+        .split_once('/')
+        .ok_or_else(|| RegistryError::NotFound {
+            handle: path.to_string(),
+        })?;
+    let now_ms = ic_cdk::api::time() / MILLISECONDS;
+    let agent =
+        store::agent::get_agent_by_handle(handle.to_string())?.with_effective_health_power(now_ms);
+    match doc {
+        "agent.json" => to_body(&agent.to_agent_card(), false),
+        // agents without the MCP protocol have no MCP server descriptor
+        "mcp.json" => match agent.to_mcp_server() {
+            Some(server) => to_body(&server, false),
+            None => Err(RegistryError::NotFound {
+                handle: path.to_string(),
+            }),
+        },
+        _ => Err(RegistryError::NotFound {
+            handle: path.to_string(),
+        }),
+    }
+}
+
+// request url example:
+// https://lfcwh-piaaa-aaaap-an2fa-cai.icp0.io/events?after=42&take=100
+fn get_events(url: Url, in_cbor: bool) -> Result<Vec<u8>, RegistryError> {
+    let mut after = None;
+    let mut take = 100u64;
+    for (key, value) in url.query_pairs() {
+        match key.as_ref() {
+            "after" => after = Some(parse_u64(&key, &value)?),
+            "take" => take = parse_u64(&key, &value)?.min(1000),
+            other => Err(RegistryError::BadRequest {
+                error: format!("invalid query parameter: {other}={value}"),
+            })?,
+        }
+    }
+
+    let events = store::outbox::get_events(after, take as usize);
+    to_body(&events, in_cbor)
 }
 
 async fn register(body: &[u8], in_cbor: bool) -> Result<Vec<u8>, RegistryError> {
@@ -264,6 +507,82 @@ async fn challenge(body: &[u8], in_cbor: bool) -> Result<Vec<u8>, RegistryError>
     Ok(Vec::new())
 }
 
+fn unregister(body: &[u8], in_cbor: bool) -> Result<Vec<u8>, RegistryError> {
+    let envelope: AgentEnvelope = if in_cbor {
+        from_slice(body).map_err(|err| RegistryError::BadRequest {
+            error: format!("failed to decode AgentEnvelope from CBOR, error: {err}"),
+        })?
+    } else {
+        serde_json::from_slice(body).map_err(|err| RegistryError::BadRequest {
+            error: format!("failed to decode AgentEnvelope from JSON, error: {err}"),
+        })?
+    };
+
+    api::unregister(envelope)?;
+    Ok(Vec::new())
+}
+
+async fn update_handle(body: &[u8], in_cbor: bool) -> Result<Vec<u8>, RegistryError> {
+    let envelope: AgentEnvelope = if in_cbor {
+        from_slice(body).map_err(|err| RegistryError::BadRequest {
+            error: format!("failed to decode AgentEnvelope from CBOR, error: {err}"),
+        })?
+    } else {
+        serde_json::from_slice(body).map_err(|err| RegistryError::BadRequest {
+            error: format!("failed to decode AgentEnvelope from JSON, error: {err}"),
+        })?
+    };
+
+    let code = api::update_handle(envelope).await?;
+    to_body(&code, in_cbor)
+}
+
+async fn rotate_identity(body: &[u8], in_cbor: bool) -> Result<Vec<u8>, RegistryError> {
+    let envelope: RotationEnvelope = if in_cbor {
+        from_slice(body).map_err(|err| RegistryError::BadRequest {
+            error: format!("failed to decode RotationEnvelope from CBOR, error: {err}"),
+        })?
+    } else {
+        serde_json::from_slice(body).map_err(|err| RegistryError::BadRequest {
+            error: format!("failed to decode RotationEnvelope from JSON, error: {err}"),
+        })?
+    };
+
+    let code = api::rotate_identity(envelope).await?;
+    to_body(&code, in_cbor)
+}
+
+fn parse_u64(key: &str, value: &str) -> Result<u64, RegistryError> {
+    value.parse().map_err(|err| RegistryError::BadRequest {
+        error: format!("invalid query parameter: {key}={value}, error: {err}"),
+    })
+}
+
+fn parse_take(url: &Url, default: u64, max: u64) -> Result<u64, RegistryError> {
+    let mut take = default;
+    for (key, value) in url.query_pairs() {
+        match key.as_ref() {
+            "take" => take = parse_u64(&key, &value)?.min(max),
+            other => Err(RegistryError::BadRequest {
+                error: format!("invalid query parameter: {other}={value}"),
+            })?,
+        }
+    }
+    Ok(take)
+}
+
+fn to_body<T: Serialize>(value: &T, in_cbor: bool) -> Result<Vec<u8>, RegistryError> {
+    if in_cbor {
+        cbor_into_vec(value).map_err(|err| RegistryError::Generic {
+            error: format!("failed to serialize body in CBOR, error: {err}"),
+        })
+    } else {
+        serde_json::to_vec(value).map_err(|err| RegistryError::Generic {
+            error: format!("failed to serialize body in JSON, error: {err}"),
+        })
+    }
+}
+
 fn parse_url(s: &str) -> Result<Url, String> {
     let url = if s.starts_with('/') {
         Url::parse(format!("http://localhost{}", s).as_str())
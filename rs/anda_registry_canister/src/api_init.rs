@@ -1,7 +1,12 @@
 use candid::{CandidType, Principal};
 use serde::Deserialize;
+use std::time::Duration;
 
-use crate::{CHALLENGE_EXPIRES_IN_MS, store};
+use crate::{
+    CHALLENGE_EXPIRES_IN_MS, DELIVER_EVENTS_INTERVAL_SECS, MILLISECONDS, SWEEP_AGENTS_BATCH_SIZE,
+    SWEEP_AGENTS_INTERVAL_SECS, SYNC_PEERS_INTERVAL_SECS, VERIFY_HANDLES_BATCH_SIZE,
+    VERIFY_HANDLES_INTERVAL_SECS, store,
+};
 
 #[derive(Clone, Debug, CandidType, Deserialize)]
 pub enum ChainArgs {
@@ -21,6 +26,8 @@ pub struct UpgradeArgs {
     name: Option<String>,
     challenge_expires_in_ms: Option<u64>,
     governance_canister: Option<Principal>,
+    expired_grace_ms: Option<u64>,
+    evicted_grace_ms: Option<u64>,
 }
 
 #[ic_cdk::init]
@@ -35,6 +42,10 @@ fn init(args: Option<ChainArgs>) {
                 s.name = args.name;
                 s.challenge_expires_in_ms = args.challenge_expires_in_ms;
                 s.governance_canister = args.governance_canister;
+                s.expired_grace_ms = store::EXPIRED_GRACE_MS;
+                s.evicted_grace_ms = store::EVICTED_GRACE_MS;
+                s.native_handles_mapped = true;
+                s.indexes_initialized = true;
             });
         }
         ChainArgs::Upgrade(_) => {
@@ -45,6 +56,7 @@ fn init(args: Option<ChainArgs>) {
     }
 
     store::state::init_http_certified_data();
+    init_timers();
 }
 
 #[ic_cdk::pre_upgrade]
@@ -68,6 +80,12 @@ fn post_upgrade(args: Option<ChainArgs>) {
                 if let Some(governance_canister) = args.governance_canister {
                     s.governance_canister = Some(governance_canister);
                 }
+                if let Some(expired_grace_ms) = args.expired_grace_ms {
+                    s.expired_grace_ms = expired_grace_ms;
+                }
+                if let Some(evicted_grace_ms) = args.evicted_grace_ms {
+                    s.evicted_grace_ms = evicted_grace_ms;
+                }
             });
         }
         Some(ChainArgs::Init(_)) => {
@@ -79,4 +97,37 @@ fn post_upgrade(args: Option<ChainArgs>) {
     }
 
     store::state::init_http_certified_data();
+    ic_cdk_timers::set_timer(Duration::ZERO, store::agent::index_all());
+    init_timers();
+}
+
+fn init_timers() {
+    ic_cdk_timers::set_timer_interval(
+        Duration::from_secs(SYNC_PEERS_INTERVAL_SECS),
+        store::peer::sync_peers,
+    );
+    ic_cdk_timers::set_timer_interval(
+        Duration::from_secs(DELIVER_EVENTS_INTERVAL_SECS),
+        store::outbox::deliver_all,
+    );
+    ic_cdk_timers::set_timer_interval(Duration::from_secs(SWEEP_AGENTS_INTERVAL_SECS), || async {
+        let now_ms = ic_cdk::api::time() / MILLISECONDS;
+        let events = store::agent::sweep(SWEEP_AGENTS_BATCH_SIZE, now_ms);
+        let ids: Vec<Principal> = events.iter().map(|e| e.id).collect();
+        store::cert::refresh(&ids);
+        for event in events {
+            store::state::notify_subscribers(event);
+        }
+    });
+    ic_cdk_timers::set_timer_interval(
+        Duration::from_secs(VERIFY_HANDLES_INTERVAL_SECS),
+        || async {
+            let events = store::state::verify_handles(VERIFY_HANDLES_BATCH_SIZE).await;
+            let ids: Vec<Principal> = events.iter().map(|e| e.id).collect();
+            store::cert::refresh(&ids);
+            for event in events {
+                store::state::notify_subscribers(event);
+            }
+        },
+    );
 }
@@ -1,8 +1,16 @@
 use anda_cloud_cdk::{
-    agent::{Agent, ChallengeEnvelope},
-    registry::{RegistryError, RegistryState},
+    NitroPcrs, TEEKind, TEEMeasurements,
+    agent::{
+        Agent, AgentEnvelope, AgentEvent, AgentRevision, ChallengeEnvelope, ChallengeRecord,
+        RotationEnvelope,
+    },
+    registry::{
+        AgentChange, ChallengerLimits, ChallengerStats, ForeignAgent, RegistryError, RegistryState,
+        SubscriberStatus,
+    },
 };
 use candid::Principal;
+use ic_auth_types::{ByteArrayB64, ByteBufB64};
 use std::collections::{BTreeMap, BTreeSet};
 
 mod api;
@@ -10,10 +18,18 @@ mod api_admin;
 mod api_http;
 mod api_init;
 mod store;
+mod tee;
 
 use api_init::ChainArgs;
 
 const CHALLENGE_EXPIRES_IN_MS: u64 = 1000 * 60 * 60; // 1 hour
+const SYNC_PEERS_INTERVAL_SECS: u64 = 60 * 10; // 10 minutes
+const SWEEP_AGENTS_INTERVAL_SECS: u64 = 60 * 10; // 10 minutes
+const SWEEP_AGENTS_BATCH_SIZE: usize = 1000;
+const DELIVER_EVENTS_INTERVAL_SECS: u64 = 60; // 1 minute
+const VERIFY_HANDLES_INTERVAL_SECS: u64 = 60 * 60; // 1 hour
+const VERIFY_HANDLES_BATCH_SIZE: usize = 100;
+const MAX_LOOKUP_BATCH: usize = 100;
 const MILLISECONDS: u64 = 1000000;
 const ANONYMOUS: Principal = Principal::anonymous();
 
@@ -37,14 +53,12 @@ fn validate_principals(principals: &BTreeSet<Principal>) -> Result<(), String> {
 }
 
 async fn rand_bytes<const N: usize>() -> Result<[u8; N], String> {
-    let mut data: Vec<u8> = ic_cdk::call::Call::bounded_wait(
-        Principal::management_canister(),
-        "raw_rand",
-    )
-    .await
-    .map_err(|err| format!("{err:?}"))?
-    .candid()
-    .map_err(|err| format!("{err:?}"))?;
+    let mut data: Vec<u8> =
+        ic_cdk::call::Call::bounded_wait(Principal::management_canister(), "raw_rand")
+            .await
+            .map_err(|err| format!("{err:?}"))?
+            .candid()
+            .map_err(|err| format!("{err:?}"))?;
     data.truncate(N);
     data.try_into().map_err(|err| format!("{err:?}"))
 }
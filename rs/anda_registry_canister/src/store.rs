@@ -1,14 +1,20 @@
 use anda_cloud_cdk::{
-    TEEInfo, TEEKind,
+    NitroPcrs, TEEClaims, TEEInfo, TEEKind, TEEMeasurements,
     agent::*,
-    registry::{RegistryError, RegistryState},
+    measurements_match,
+    registry::{
+        AgentChange, ChallengerLimits, ChallengerStats, ForeignAgent, RegistryError, RegistryState,
+        SubscriberStatus,
+    },
 };
 use candid::{CandidType, Principal};
 use cbor2::{from_slice, to_vec as cbor_to_vec};
-use ic_auth_types::ByteArrayB64;
+use ic_auth_types::{ByteArrayB64, ByteBufB64};
 use ic_cdk::call::Call;
 use ic_http_certification::{
+    DefaultFullCelExpression, DefaultResponseCertification, DefaultResponseOnlyCelExpression,
     HttpCertification, HttpCertificationPath, HttpCertificationTree, HttpCertificationTreeEntry,
+    HttpRequest, HttpResponse,
     cel::{DefaultCelBuilder, create_cel_expr},
 };
 use ic_stable_structures::{
@@ -23,9 +29,43 @@ use std::{
     collections::{BTreeMap, BTreeSet},
 };
 
+use crate::{MAX_LOOKUP_BATCH, MILLISECONDS};
+
 const MAX_LAST_CHALLENGED: usize = 10000;
 const MAX_HEALTH_POWER_LIST: usize = 1000;
 const TRIM_STEP: usize = 100;
+const SYNC_BATCH_SIZE: u64 = 200;
+const SYNC_MAX_ROUNDS: usize = 10;
+pub const EXPIRED_GRACE_MS: u64 = 1000 * 60 * 60 * 24; // 1 day
+pub const EVICTED_GRACE_MS: u64 = 1000 * 60 * 60 * 24 * 30; // 30 days
+const MAX_TOKEN_LEN: usize = 64;
+const MAX_TOKENS_PER_AGENT: usize = 100;
+const MAX_SEARCH_SCAN: usize = 10000;
+const MAX_CHALLENGE_RECORDS: usize = 100;
+const DELIVERY_BATCH_SIZE: usize = 100;
+const MAX_DELIVERY_RETRIES: u32 = 10;
+const DELIVERY_BACKOFF_MS: u64 = 1000 * 10; // 10 seconds
+const MAX_DELIVERY_BACKOFF_MS: u64 = 1000 * 60 * 60; // 1 hour
+const MAX_EVENT_LOG: u64 = 100000;
+const MAX_AGENT_REVISIONS: usize = 100;
+const CERTIFY_BATCH_SIZE: usize = 500;
+const INDEX_BATCH_SIZE: usize = 200;
+const MINUTE_MS: u64 = 1000 * 60;
+const DAY_MS: u64 = 1000 * 60 * 60 * 24;
+// handles that can not be claimed as registry-native handles
+const RESERVED_HANDLES: [&str; 11] = [
+    "admin",
+    "administrator",
+    "anda",
+    "api",
+    "dmsg",
+    "icp",
+    "official",
+    "registry",
+    "root",
+    "support",
+    "system",
+];
 
 type Memory = VirtualMemory<DefaultMemoryImpl>;
 
@@ -39,23 +79,109 @@ pub struct State {
     pub subscribers: BTreeSet<Principal>,
     pub name_canisters: BTreeSet<Principal>,
     pub governance_canister: Option<Principal>,
+    // sequence number of the latest agent change
+    #[serde(default)]
+    pub change_seq: u64,
+    // peer -> sequence number of the latest change synchronized from the peer
+    #[serde(default)]
+    pub peer_cursors: BTreeMap<Principal, u64>,
+    #[serde(default = "default_expired_grace_ms")]
+    pub expired_grace_ms: u64,
+    #[serde(default = "default_evicted_grace_ms")]
+    pub evicted_grace_ms: u64,
+    // sequence number of the next challenge record
+    #[serde(default)]
+    pub challenge_seq: u64,
+    // sequence number of the latest event
+    #[serde(default)]
+    pub event_seq: u64,
+    // subscriber -> delivery cursor
+    #[serde(default)]
+    pub subscriber_cursors: BTreeMap<Principal, SubscriberCursor>,
+    #[serde(default)]
+    pub challenger_limits: ChallengerLimits,
+    // challenger -> limits overriding the default ones
+    #[serde(default)]
+    pub challenger_limit_overrides: BTreeMap<Principal, ChallengerLimits>,
+    // challenger -> usage of the limits
+    #[serde(default)]
+    pub challenger_usage: BTreeMap<Principal, ChallengerUsage>,
+    // challenger -> activity counters
+    #[serde(default)]
+    pub challenger_counters: BTreeMap<Principal, ChallengerCounters>,
+    // allowed Nitro enclave images for all agents
+    #[serde(default)]
+    pub nitro_pcrs: BTreeSet<NitroPcrs>,
+    // provider -> allowed Nitro enclave images for the agents of the provider
+    #[serde(default)]
+    pub provider_nitro_pcrs: BTreeMap<Principal, BTreeSet<NitroPcrs>>,
+    // TEE kind -> DER certificates of the roots that the attestations of the kind chain to
+    #[serde(default)]
+    pub tee_roots: BTreeMap<TEEKind, BTreeSet<ByteBufB64>>,
+    // TEE kind -> allowed measurements of confidential VMs for all agents
+    #[serde(default)]
+    pub tee_measurements: BTreeMap<TEEKind, BTreeSet<TEEMeasurements>>,
+    // provider -> TEE kind -> allowed measurements of confidential VMs for the agents of the provider
+    #[serde(default)]
+    pub provider_tee_measurements:
+        BTreeMap<Principal, BTreeMap<TEEKind, BTreeSet<TEEMeasurements>>>,
+    // minimum health power to enter HEALTH_POWER_INDEX
+    #[serde(default)]
+    pub health_power_threshold: u64,
+    // handles reserved by governance, besides RESERVED_HANDLES
+    #[serde(default)]
+    pub reserved_handles: BTreeSet<String>,
+    // registry-native handle -> (the agent holding it, the agent it is offered to)
+    #[serde(default)]
+    pub handle_transfers: BTreeMap<String, (Principal, Principal)>,
+    // whether the registry-native handles of the agents registered before they were
+    // supported have been mapped
+    #[serde(default)]
+    pub native_handles_mapped: bool,
+    // whether the token, protocol and provider indexes of the agents registered before
+    // they existed have been built
+    #[serde(default)]
+    pub indexes_initialized: bool,
+    // the next agent_idx to index while the indexes are built in batches after upgrade
+    #[serde(default)]
+    pub indexes_cursor: u64,
 }
 
-#[derive(Clone, Default, Deserialize, Serialize)]
-struct Indexes {
-    // agent_id -> (agent_idx, challenged_at)
-    id_map: BTreeMap<Principal, (u64, u64)>,
+#[derive(Clone, CandidType, Default, Deserialize, Serialize)]
+pub struct SubscriberCursor {
+    // sequence number of the last event delivered to (or dropped for) the subscriber
+    pub delivered: u64,
+    // failed attempts to deliver the next event
+    pub retries: u32,
+    pub retry_at: u64,
+    pub dropped: u64,
+}
 
-    // handle -> agent_idx
-    by_handle: BTreeMap<String, u64>,
+#[derive(Clone, CandidType, Default, Deserialize, Serialize)]
+pub struct ChallengerUsage {
+    // tokens left in the bucket
+    pub tokens: u64,
+    // time when the bucket was refilled last
+    pub refilled_at: u64,
+    // UTC day (days since the Unix epoch) of the registrations
+    pub day: u64,
+    pub registrations: u64,
+}
 
-    // (health_power, agent_idx), size <= 1000
-    by_health_power: BTreeSet<(u64, u64)>,
+#[derive(Clone, CandidType, Default, Deserialize, Serialize)]
+pub struct ChallengerCounters {
+    pub registrations: u64,
+    pub challenges: u64,
+    pub expired_recoveries: u64,
+    pub last_active_at: u64,
+}
 
-    // (challenged_at, agent_id), size <= 10000
-    last_challenged: BTreeSet<(u64, Principal)>,
+fn default_expired_grace_ms() -> u64 {
+    EXPIRED_GRACE_MS
+}
 
-    health_power_threshold: u64,
+fn default_evicted_grace_ms() -> u64 {
+    EVICTED_GRACE_MS
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
@@ -88,6 +214,132 @@ pub struct AgentLocal {
 
     #[serde(rename = "t")]
     tee: Option<TEEInfoLocal>,
+
+    #[serde(rename = "sq", default)]
+    change_seq: u64,
+
+    #[serde(rename = "st", default)]
+    status: AgentStatus,
+
+    #[serde(rename = "sr", default)]
+    status_reason: Option<String>,
+
+    // the status before the agent was suspended or banned, restored when it is unbanned
+    #[serde(rename = "ps", default)]
+    prev_status: Option<AgentStatus>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ForeignAgentLocal {
+    #[serde(rename = "a")]
+    agent: AgentLocal,
+
+    #[serde(rename = "p")]
+    peer: Principal,
+
+    #[serde(rename = "s")]
+    seq: u64,
+
+    #[serde(rename = "t")]
+    synced_at: u64,
+}
+
+impl From<ForeignAgentLocal> for ForeignAgent {
+    fn from(info: ForeignAgentLocal) -> Self {
+        Self {
+            agent: info.agent.into(),
+            peer: info.peer,
+            seq: info.seq,
+            synced_at: info.synced_at,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct AgentEventLocal {
+    #[serde(rename = "i")]
+    id: Principal,
+
+    #[serde(rename = "k")]
+    kind: AgentEventKind,
+
+    #[serde(rename = "t")]
+    ts: u64,
+}
+
+impl From<AgentEvent> for AgentEventLocal {
+    fn from(event: AgentEvent) -> Self {
+        Self {
+            id: event.id,
+            kind: event.kind,
+            ts: event.ts,
+        }
+    }
+}
+
+impl AgentEventLocal {
+    fn into_event(self, seq: u64) -> AgentEvent {
+        AgentEvent {
+            seq,
+            id: self.id,
+            kind: self.kind,
+            ts: self.ts,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ChallengeRecordLocal {
+    #[serde(rename = "ca")]
+    challenged_at: u64,
+
+    #[serde(rename = "cb")]
+    challenged_by: Principal,
+
+    #[serde(rename = "hp")]
+    health_power: u64,
+
+    #[serde(rename = "hd")]
+    health_delta: i64,
+
+    #[serde(rename = "e")]
+    expired: bool,
+
+    #[serde(rename = "t")]
+    tee: bool,
+}
+
+impl ChallengeRecordLocal {
+    fn into_record(self, id: u64) -> ChallengeRecord {
+        ChallengeRecord {
+            id,
+            challenged_at: self.challenged_at,
+            challenged_by: self.challenged_by,
+            health_power: self.health_power,
+            health_delta: self.health_delta,
+            expired: self.expired,
+            tee: self.tee,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct AgentRevisionLocal {
+    #[serde(rename = "rb")]
+    revised_by: Principal,
+
+    #[serde(rename = "c")]
+    changes: Vec<AgentInfoChange>,
+}
+
+impl AgentRevisionLocal {
+    fn into_revision(self, revised_at: u64) -> AgentRevision {
+        AgentRevision {
+            revised_at,
+            revised_by: self.revised_by,
+            changes: self.changes,
+        }
+    }
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
@@ -190,6 +442,9 @@ pub struct TEEInfoLocal {
 
     #[serde(rename = "u")]
     url: String,
+
+    #[serde(rename = "c", default)]
+    claims: Option<TEEClaimsLocal>,
 }
 
 impl From<TEEInfo> for TEEInfoLocal {
@@ -198,6 +453,58 @@ impl From<TEEInfo> for TEEInfoLocal {
             id: info.id,
             kind: info.kind,
             url: info.url,
+            claims: None,
+        }
+    }
+}
+
+impl From<(TEEInfo, TEEClaims)> for TEEInfoLocal {
+    fn from((info, claims): (TEEInfo, TEEClaims)) -> Self {
+        Self {
+            claims: Some(claims.into()),
+            ..info.into()
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct TEEClaimsLocal {
+    #[serde(rename = "k")]
+    kind: TEEKind,
+
+    #[serde(rename = "mi")]
+    module_id: String,
+
+    #[serde(rename = "m")]
+    measurements: TEEMeasurements,
+
+    #[serde(rename = "a")]
+    attested_at: u64,
+
+    #[serde(rename = "v")]
+    verified_at: u64,
+}
+
+impl From<TEEClaims> for TEEClaimsLocal {
+    fn from(claims: TEEClaims) -> Self {
+        Self {
+            kind: claims.kind,
+            module_id: claims.module_id,
+            measurements: claims.measurements,
+            attested_at: claims.attested_at,
+            verified_at: claims.verified_at,
+        }
+    }
+}
+
+impl From<TEEClaimsLocal> for TEEClaims {
+    fn from(claims: TEEClaimsLocal) -> Self {
+        Self {
+            kind: claims.kind,
+            module_id: claims.module_id,
+            measurements: claims.measurements,
+            attested_at: claims.attested_at,
+            verified_at: claims.verified_at,
         }
     }
 }
@@ -251,11 +558,20 @@ impl From<AgentLocal> for Agent {
             created_at: agent.created_at,
             actived_start: agent.actived_start,
             health_power: agent.health_power,
+            // computed at read time, see Agent::with_effective_health_power
+            effective_health_power: None,
             challenge_code: agent.challenge_code,
             challenged_at: agent.challenged_at,
             challenged_by: agent.challenged_by,
             challenged_expiration: agent.challenged_expiration,
+            tee_claims: agent
+                .tee
+                .as_ref()
+                .and_then(|t| t.claims.clone())
+                .map(|c| c.into()),
             tee: agent.tee.map(|t| t.into()),
+            status: agent.status,
+            status_reason: agent.status_reason,
         }
     }
 }
@@ -272,7 +588,14 @@ impl From<Agent> for AgentLocal {
             challenged_at: agent.challenged_at,
             challenged_by: agent.challenged_by,
             challenged_expiration: agent.challenged_expiration,
-            tee: agent.tee.map(|t| t.into()),
+            tee: match (agent.tee, agent.tee_claims) {
+                (Some(tee), Some(claims)) => Some((tee, claims).into()),
+                (tee, _) => tee.map(|t| t.into()),
+            },
+            change_seq: 0,
+            status: agent.status,
+            status_reason: agent.status_reason,
+            prev_status: None,
         }
     }
 }
@@ -293,14 +616,129 @@ impl Storable for AgentLocal {
     }
 }
 
+impl Storable for AgentEventLocal {
+    const BOUND: Bound = Bound::Unbounded;
+
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(cbor_to_vec(self).expect("failed to encode AgentEventLocal data"))
+    }
+
+    fn into_bytes(self) -> Vec<u8> {
+        cbor_to_vec(&self).expect("failed to encode AgentEventLocal data")
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        from_slice(&bytes).expect("failed to decode AgentEventLocal data")
+    }
+}
+
+impl Storable for ChallengeRecordLocal {
+    const BOUND: Bound = Bound::Unbounded;
+
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(cbor_to_vec(self).expect("failed to encode ChallengeRecordLocal data"))
+    }
+
+    fn into_bytes(self) -> Vec<u8> {
+        cbor_to_vec(&self).expect("failed to encode ChallengeRecordLocal data")
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        from_slice(&bytes).expect("failed to decode ChallengeRecordLocal data")
+    }
+}
+
+impl Storable for AgentRevisionLocal {
+    const BOUND: Bound = Bound::Unbounded;
+
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(cbor_to_vec(self).expect("failed to encode AgentRevisionLocal data"))
+    }
+
+    fn into_bytes(self) -> Vec<u8> {
+        cbor_to_vec(&self).expect("failed to encode AgentRevisionLocal data")
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        from_slice(&bytes).expect("failed to decode AgentRevisionLocal data")
+    }
+}
+
+impl Storable for ForeignAgentLocal {
+    const BOUND: Bound = Bound::Unbounded;
+
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(cbor_to_vec(self).expect("failed to encode ForeignAgentLocal data"))
+    }
+
+    fn into_bytes(self) -> Vec<u8> {
+        cbor_to_vec(&self).expect("failed to encode ForeignAgentLocal data")
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        from_slice(&bytes).expect("failed to decode ForeignAgentLocal data")
+    }
+}
+
+// (name, agent_idx) entry of the token index and the protocol index
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct IndexKey {
+    name: String,
+    idx: u64,
+}
+
+impl Storable for IndexKey {
+    const BOUND: Bound = Bound::Bounded {
+        max_size: MAX_TOKEN_LEN as u32 + 8,
+        is_fixed_size: false,
+    };
+
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(self.clone().into_bytes())
+    }
+
+    fn into_bytes(self) -> Vec<u8> {
+        let mut buf = self.name.into_bytes();
+        buf.extend_from_slice(&self.idx.to_be_bytes());
+        buf
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        let (name, idx) = bytes.split_at(bytes.len() - 8);
+        Self {
+            name: String::from_utf8(name.to_vec()).expect("failed to decode IndexKey name"),
+            idx: u64::from_be_bytes(idx.try_into().expect("failed to decode IndexKey idx")),
+        }
+    }
+}
+
 const STATE_MEMORY_ID: MemoryId = MemoryId::new(0);
 const INDEX_MEMORY_ID: MemoryId = MemoryId::new(1);
 const AGENT_MEMORY_ID: MemoryId = MemoryId::new(2);
+const CHANGE_MEMORY_ID: MemoryId = MemoryId::new(3);
+const FOREIGN_AGENT_MEMORY_ID: MemoryId = MemoryId::new(4);
+const TOKEN_MEMORY_ID: MemoryId = MemoryId::new(5);
+const PROTOCOL_MEMORY_ID: MemoryId = MemoryId::new(6);
+const PROVIDER_MEMORY_ID: MemoryId = MemoryId::new(7);
+const CHALLENGE_MEMORY_ID: MemoryId = MemoryId::new(8);
+const OUTBOX_MEMORY_ID: MemoryId = MemoryId::new(11);
+const ID_INDEX_MEMORY_ID: MemoryId = MemoryId::new(12);
+const HANDLE_INDEX_MEMORY_ID: MemoryId = MemoryId::new(13);
+const HEALTH_POWER_INDEX_MEMORY_ID: MemoryId = MemoryId::new(14);
+const LAST_CHALLENGED_INDEX_MEMORY_ID: MemoryId = MemoryId::new(15);
+const REVISION_MEMORY_ID: MemoryId = MemoryId::new(16);
 
 thread_local! {
     static STATE: RefCell<State> = RefCell::new(State::default());
-    static INDEX : RefCell<Indexes> = RefCell::new(Indexes::default());
     static HTTP_TREE: RefCell<HttpCertificationTree> = RefCell::new(HttpCertificationTree::default());
+    // agent_idx to continue the expiry sweep from
+    static SWEEP_CURSOR: RefCell<u64> = const { RefCell::new(0) };
+    // handle to continue the handle verification after
+    static HANDLE_CURSOR: RefCell<Option<String>> = const { RefCell::new(None) };
+    // agent_id -> certifications of the agent's lookup responses in HTTP_TREE
+    static CERTIFIED: RefCell<BTreeMap<Principal, Vec<HttpCertification>>> = const { RefCell::new(BTreeMap::new()) };
+    // subscribers being delivered to
+    static DELIVERING: RefCell<BTreeSet<Principal>> = const { RefCell::new(BTreeSet::new()) };
 
 
     static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> =
@@ -313,6 +751,7 @@ thread_local! {
         )
     );
 
+    // indexes saved by earlier versions, cleared after they are rebuilt from AGENT_STORE
     static INDEX_STORE: RefCell<StableCell<Vec<u8>, Memory>> = RefCell::new(
         StableCell::init(
             MEMORY_MANAGER.with_borrow(|m| m.get(INDEX_MEMORY_ID)),
@@ -325,6 +764,90 @@ thread_local! {
             MEMORY_MANAGER.with_borrow(|m| m.get(AGENT_MEMORY_ID)),
         )
     );
+
+    // agent_id -> (agent_idx, challenged_at)
+    static ID_INDEX: RefCell<StableBTreeMap<Principal, (u64, u64), Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with_borrow(|m| m.get(ID_INDEX_MEMORY_ID)),
+        )
+    );
+
+    // handle -> agent_idx, the native handles and the handles mapped to a name canister
+    static HANDLE_INDEX: RefCell<StableBTreeMap<String, u64, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with_borrow(|m| m.get(HANDLE_INDEX_MEMORY_ID)),
+        )
+    );
+
+    // (health_power, agent_idx) -> (), size <= MAX_HEALTH_POWER_LIST
+    static HEALTH_POWER_INDEX: RefCell<StableBTreeMap<(u64, u64), (), Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with_borrow(|m| m.get(HEALTH_POWER_INDEX_MEMORY_ID)),
+        )
+    );
+
+    // (challenged_at, agent_id) -> (), the latest challenge of each agent, size <= MAX_LAST_CHALLENGED
+    static LAST_CHALLENGED_INDEX: RefCell<StableBTreeMap<(u64, Principal), (), Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with_borrow(|m| m.get(LAST_CHALLENGED_INDEX_MEMORY_ID)),
+        )
+    );
+
+    // change_seq -> agent_id, only the latest change of each agent is kept
+    static CHANGE_STORE: RefCell<StableBTreeMap<u64, Principal, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with_borrow(|m| m.get(CHANGE_MEMORY_ID)),
+        )
+    );
+
+    // agent_id -> agent synchronized from peers
+    static FOREIGN_AGENT_STORE: RefCell<StableBTreeMap<Principal, ForeignAgentLocal, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with_borrow(|m| m.get(FOREIGN_AGENT_MEMORY_ID)),
+        )
+    );
+
+    // (token, agent_idx) -> (), tokens of the agent's name, description and handle
+    static TOKEN_STORE: RefCell<StableBTreeMap<IndexKey, (), Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with_borrow(|m| m.get(TOKEN_MEMORY_ID)),
+        )
+    );
+
+    // (protocol_name, agent_idx) -> ()
+    static PROTOCOL_STORE: RefCell<StableBTreeMap<IndexKey, (), Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with_borrow(|m| m.get(PROTOCOL_MEMORY_ID)),
+        )
+    );
+
+    // (provider_id, agent_idx) -> ()
+    static PROVIDER_STORE: RefCell<StableBTreeMap<(Principal, u64), (), Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with_borrow(|m| m.get(PROVIDER_MEMORY_ID)),
+        )
+    );
+
+    // (agent_idx, challenge_seq) -> record, the latest MAX_CHALLENGE_RECORDS records of each agent
+    static CHALLENGE_STORE: RefCell<StableBTreeMap<(u64, u64), ChallengeRecordLocal, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with_borrow(|m| m.get(CHALLENGE_MEMORY_ID)),
+        )
+    );
+
+    // (agent_idx, revised_at) -> revision, the latest MAX_AGENT_REVISIONS revisions of each agent
+    static REVISION_STORE: RefCell<StableBTreeMap<(u64, u64), AgentRevisionLocal, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with_borrow(|m| m.get(REVISION_MEMORY_ID)),
+        )
+    );
+
+    // event_seq -> event, the latest MAX_EVENT_LOG events are kept
+    static OUTBOX: RefCell<StableBTreeMap<u64, AgentEventLocal, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with_borrow(|m| m.get(OUTBOX_MEMORY_ID)),
+        )
+    );
 }
 
 pub mod state {
@@ -366,34 +889,352 @@ pub mod state {
         STATE.with_borrow(|s| s.governance_canister.as_ref() == Some(caller))
     }
 
-    pub fn is_challenger(caller: &Principal) -> bool {
-        STATE.with_borrow(|s| s.challengers.contains(caller))
+    /// Checks the permission and the limits of the challenger, and takes a token from its bucket.
+    /// The registration quota is checked if `registering` is true,
+    /// and should be consumed by [`record_registration`] after a successful registration.
+    pub fn check_challenger(
+        challenger: &Principal,
+        registering: bool,
+        now_ms: u64,
+    ) -> Result<(), RegistryError> {
+        STATE.with_borrow_mut(|s| {
+            if !s.challengers.contains(challenger) {
+                return Err(RegistryError::Forbidden {
+                    error: format!("challenger {} has no permission", challenger),
+                });
+            }
+
+            let limits = s
+                .challenger_limit_overrides
+                .get(challenger)
+                .unwrap_or(&s.challenger_limits)
+                .clone();
+            let usage = s
+                .challenger_usage
+                .entry(*challenger)
+                .or_insert_with(|| ChallengerUsage {
+                    tokens: limits.burst,
+                    refilled_at: now_ms,
+                    ..Default::default()
+                });
+
+            if registering
+                && limits.registrations_per_day > 0
+                && usage.day == now_ms / DAY_MS
+                && usage.registrations >= limits.registrations_per_day
+            {
+                return Err(RegistryError::Forbidden {
+                    error: format!(
+                        "challenger {} exceeded the registration quota of {} per day",
+                        challenger, limits.registrations_per_day
+                    ),
+                });
+            }
+
+            if limits.burst > 0 {
+                // the bucket is full when the challenger was unlimited before
+                usage.tokens = usage.tokens.min(limits.burst);
+                if limits.refill_per_minute > 0 && now_ms > usage.refilled_at {
+                    let refill = (now_ms - usage.refilled_at)
+                        .saturating_mul(limits.refill_per_minute)
+                        / MINUTE_MS;
+                    if usage.tokens.saturating_add(refill) >= limits.burst {
+                        usage.tokens = limits.burst;
+                        usage.refilled_at = now_ms;
+                    } else if refill > 0 {
+                        usage.tokens += refill;
+                        // keeps the remainder of the elapsed time for the next refill
+                        usage.refilled_at += refill * MINUTE_MS / limits.refill_per_minute;
+                    }
+                }
+                if usage.tokens == 0 {
+                    return Err(RegistryError::Forbidden {
+                        error: format!(
+                            "challenger {} exceeded the rate limit of {} calls per minute",
+                            challenger, limits.refill_per_minute
+                        ),
+                    });
+                }
+                usage.tokens -= 1;
+            } else {
+                usage.tokens = u64::MAX;
+            }
+            Ok(())
+        })
     }
 
-    pub fn get_state() -> RegistryState {
-        STATE.with_borrow(|s| RegistryState {
-            name: s.name.clone(),
-            max_agent: s.max_agent,
-            agents_total: INDEX.with_borrow(|rs| rs.id_map.len() as u64),
-            challenge_expires_in_ms: s.challenge_expires_in_ms,
-            governance_canister: s.governance_canister,
-            challengers: s.challengers.clone(),
-            peers: s.peers.clone(),
-            name_canisters: s.name_canisters.clone(),
-            subscribers: s.subscribers.clone(),
+    /// Counts a successful registration into the daily quota of the challenger.
+    pub fn record_registration(challenger: &Principal, now_ms: u64) {
+        STATE.with_borrow_mut(|s| {
+            let usage = s.challenger_usage.entry(*challenger).or_default();
+            let day = now_ms / DAY_MS;
+            if usage.day != day {
+                usage.day = day;
+                usage.registrations = 0;
+            }
+            usage.registrations += 1;
         })
     }
 
-    pub fn http_tree_with<R>(f: impl FnOnce(&HttpCertificationTree) -> R) -> R {
-        HTTP_TREE.with(|r| f(&r.borrow()))
+    /// Counts a successful registration or challenge of the challenger.
+    pub fn count_challenger(challenger: Principal, registered: bool, expired: bool, now_ms: u64) {
+        STATE.with_borrow_mut(|s| {
+            let c = s.challenger_counters.entry(challenger).or_default();
+            if registered {
+                c.registrations += 1;
+            } else {
+                c.challenges += 1;
+                if expired {
+                    c.expired_recoveries += 1;
+                }
+            }
+            c.last_active_at = c.last_active_at.max(now_ms);
+        })
     }
 
-    pub fn init_http_certified_data() {
-        HTTP_TREE.with(|r| {
-            let mut tree = r.borrow_mut();
+    /// Gets the statistics of the current challengers and the former challengers with activities.
+    pub fn get_challenger_stats() -> Vec<ChallengerStats> {
+        STATE.with_borrow(|s| {
+            let challengers: BTreeSet<Principal> = s
+                .challengers
+                .iter()
+                .chain(s.challenger_counters.keys())
+                .cloned()
+                .collect();
+            challengers
+                .into_iter()
+                .map(|challenger| {
+                    let c = s
+                        .challenger_counters
+                        .get(&challenger)
+                        .cloned()
+                        .unwrap_or_default();
+                    ChallengerStats {
+                        challenger,
+                        active: s.challengers.contains(&challenger),
+                        registrations: c.registrations,
+                        challenges: c.challenges,
+                        expired_recoveries: c.expired_recoveries,
+                        last_active_at: c.last_active_at,
+                    }
+                })
+                .collect()
+        })
+    }
+
+    /// Adds the Nitro enclave images to the allowlist of the provider, or the global allowlist.
+    pub fn add_nitro_pcrs(provider: Option<Principal>, pcrs: Vec<NitroPcrs>) {
+        STATE.with_borrow_mut(|s| match provider {
+            Some(provider) => s
+                .provider_nitro_pcrs
+                .entry(provider)
+                .or_default()
+                .extend(pcrs),
+            None => s.nitro_pcrs.extend(pcrs),
+        })
+    }
+
+    /// Removes the Nitro enclave images from the allowlist of the provider, or the global allowlist.
+    pub fn remove_nitro_pcrs(provider: Option<Principal>, pcrs: &[NitroPcrs]) {
+        STATE.with_borrow_mut(|s| match provider {
+            Some(provider) => {
+                if let Some(list) = s.provider_nitro_pcrs.get_mut(&provider) {
+                    list.retain(|v| !pcrs.contains(v));
+                    if list.is_empty() {
+                        s.provider_nitro_pcrs.remove(&provider);
+                    }
+                }
+            }
+            None => s.nitro_pcrs.retain(|v| !pcrs.contains(v)),
+        })
+    }
+
+    pub fn get_nitro_pcrs(provider: Option<Principal>) -> Vec<NitroPcrs> {
+        STATE.with_borrow(|s| match provider {
+            Some(provider) => s
+                .provider_nitro_pcrs
+                .get(&provider)
+                .map(|v| v.iter().cloned().collect())
+                .unwrap_or_default(),
+            None => s.nitro_pcrs.iter().cloned().collect(),
+        })
+    }
+
+    /// Checks the PCRs of a Nitro attestation document against the global allowlist
+    /// and the allowlist of the agent's provider. Any enclave image is allowed if both are empty.
+    ///
+    /// The per-provider allowlist is keyed by the provider id that the agent declares about
+    /// itself in its `AgentInfo`, it is not verified by the registry.
+    pub fn is_nitro_pcrs_allowed(
+        provider: Option<Principal>,
+        pcrs: &BTreeMap<usize, ByteBufB64>,
+    ) -> bool {
+        STATE.with_borrow(|s| {
+            let provider_pcrs = provider.and_then(|p| s.provider_nitro_pcrs.get(&p));
+            if s.nitro_pcrs.is_empty() && provider_pcrs.is_none() {
+                return true;
+            }
+            s.nitro_pcrs
+                .iter()
+                .chain(provider_pcrs.into_iter().flatten())
+                .any(|v| v.matches(pcrs))
+        })
+    }
+
+    /// Adds the DER certificates to the roots trusted for the attestations of the kind.
+    pub fn add_tee_roots(kind: TEEKind, certs: Vec<ByteBufB64>) {
+        STATE.with_borrow_mut(|s| s.tee_roots.entry(kind).or_default().extend(certs))
+    }
+
+    /// Removes the DER certificates from the roots trusted for the attestations of the kind.
+    pub fn remove_tee_roots(kind: TEEKind, certs: &[ByteBufB64]) {
+        STATE.with_borrow_mut(|s| {
+            if let Some(roots) = s.tee_roots.get_mut(&kind) {
+                roots.retain(|v| !certs.contains(v));
+                if roots.is_empty() {
+                    s.tee_roots.remove(&kind);
+                }
+            }
+        })
+    }
+
+    pub fn get_tee_roots(kind: &TEEKind) -> Vec<ByteBufB64> {
+        STATE.with_borrow(|s| {
+            s.tee_roots
+                .get(kind)
+                .map(|v| v.iter().cloned().collect())
+                .unwrap_or_default()
+        })
+    }
+
+    /// Adds the measurements of confidential VMs of the kind to the allowlist of the provider,
+    /// or the global allowlist.
+    pub fn add_tee_measurements(
+        kind: TEEKind,
+        provider: Option<Principal>,
+        measurements: Vec<TEEMeasurements>,
+    ) {
+        STATE.with_borrow_mut(|s| {
+            let list = match provider {
+                Some(provider) => s.provider_tee_measurements.entry(provider).or_default(),
+                None => &mut s.tee_measurements,
+            };
+            list.entry(kind).or_default().extend(measurements)
+        })
+    }
+
+    /// Removes the measurements of confidential VMs of the kind from the allowlist of the provider,
+    /// or the global allowlist.
+    pub fn remove_tee_measurements(
+        kind: TEEKind,
+        provider: Option<Principal>,
+        measurements: &[TEEMeasurements],
+    ) {
+        STATE.with_borrow_mut(|s| {
+            let list = match provider {
+                Some(provider) => match s.provider_tee_measurements.get_mut(&provider) {
+                    Some(list) => list,
+                    None => return,
+                },
+                None => &mut s.tee_measurements,
+            };
+            if let Some(entries) = list.get_mut(&kind) {
+                entries.retain(|v| !measurements.contains(v));
+                if entries.is_empty() {
+                    list.remove(&kind);
+                }
+            }
+            if let Some(provider) = provider
+                && s.provider_tee_measurements
+                    .get(&provider)
+                    .is_some_and(|v| v.is_empty())
+            {
+                s.provider_tee_measurements.remove(&provider);
+            }
+        })
+    }
+
+    pub fn get_tee_measurements(
+        kind: &TEEKind,
+        provider: Option<Principal>,
+    ) -> Vec<TEEMeasurements> {
+        STATE.with_borrow(|s| {
+            let list = match provider {
+                Some(provider) => s.provider_tee_measurements.get(&provider),
+                None => Some(&s.tee_measurements),
+            };
+            list.and_then(|v| v.get(kind))
+                .map(|v| v.iter().cloned().collect())
+                .unwrap_or_default()
+        })
+    }
+
+    /// Checks the measurements of a confidential VM attestation against the global allowlist
+    /// and the allowlist of the agent's provider of the kind, like [`is_nitro_pcrs_allowed`].
+    pub fn is_tee_measurements_allowed(
+        kind: &TEEKind,
+        provider: Option<Principal>,
+        measurements: &TEEMeasurements,
+    ) -> bool {
+        STATE.with_borrow(|s| {
+            let global = s.tee_measurements.get(kind);
+            let provider_list = provider
+                .and_then(|p| s.provider_tee_measurements.get(&p))
+                .and_then(|v| v.get(kind));
+            if global.is_none() && provider_list.is_none() {
+                return true;
+            }
+            global
+                .into_iter()
+                .chain(provider_list)
+                .flatten()
+                .any(|v| measurements_match(v, measurements))
+        })
+    }
+
+    /// Sets the limits of the challenger, or the default limits if `challenger` is `None`.
+    /// The override of the challenger is removed if `limits` is `None`.
+    pub fn set_challenger_limits(challenger: Option<Principal>, limits: Option<ChallengerLimits>) {
+        STATE.with_borrow_mut(|s| match (challenger, limits) {
+            (None, Some(limits)) => s.challenger_limits = limits,
+            (None, None) => s.challenger_limits = ChallengerLimits::default(),
+            (Some(challenger), Some(limits)) => {
+                s.challenger_limit_overrides.insert(challenger, limits);
+            }
+            (Some(challenger), None) => {
+                s.challenger_limit_overrides.remove(&challenger);
+            }
+        })
+    }
+
+    pub fn get_state() -> RegistryState {
+        STATE.with_borrow(|s| RegistryState {
+            name: s.name.clone(),
+            max_agent: s.max_agent,
+            agents_total: ID_INDEX.with_borrow(|r| r.len()),
+            challenge_expires_in_ms: s.challenge_expires_in_ms,
+            expired_grace_ms: s.expired_grace_ms,
+            evicted_grace_ms: s.evicted_grace_ms,
+            governance_canister: s.governance_canister,
+            challengers: s.challengers.clone(),
+            peers: s.peers.clone(),
+            name_canisters: s.name_canisters.clone(),
+            subscribers: s.subscribers.clone(),
+            challenger_limits: s.challenger_limits.clone(),
+        })
+    }
+
+    pub fn http_tree_with<R>(f: impl FnOnce(&HttpCertificationTree) -> R) -> R {
+        HTTP_TREE.with(|r| f(&r.borrow()))
+    }
+
+    pub fn init_http_certified_data() {
+        HTTP_TREE.with(|r| {
+            let mut tree = r.borrow_mut();
             tree.insert(&DEFAULT_CERT_ENTRY);
-            ic_cdk::api::certified_data_set(tree.root_hash())
         });
+        cert::init();
+        cert::set_certified_data();
     }
 
     pub fn load() {
@@ -403,18 +1244,7 @@ pub mod state {
                 if bytes.is_empty() {
                     return;
                 }
-                let v: State = from_slice(&bytes).expect("failed to decode STATE_STORE data");
-                *r = v;
-            });
-        });
-        INDEX_STORE.with_borrow(|rs| {
-            INDEX.with_borrow_mut(|r| {
-                let bytes = rs.get();
-                if bytes.is_empty() {
-                    return;
-                }
-                let v: Indexes =
-                    from_slice(&bytes).expect("failed to decode INDEX_STORE data");
+                let v: State = from_slice(bytes).expect("failed to decode STATE_STORE data");
                 *r = v;
             });
         });
@@ -426,26 +1256,14 @@ pub mod state {
                 rs.set(cbor_to_vec(r).expect("failed to encode STATE data"));
             });
         });
-        INDEX.with_borrow(|r| {
-            INDEX_STORE.with_borrow_mut(|rs| {
-                rs.set(cbor_to_vec(r).expect("failed to encode INDEX data"));
-            });
-        });
     }
 
+    /// Records the event in the event log and delivers it to subscribers in the background.
     pub fn notify_subscribers(event: AgentEvent) {
-        let subscribers = STATE.with_borrow(|s| s.subscribers.clone());
-        if subscribers.is_empty() {
-            return;
+        outbox::push(event);
+        if STATE.with_borrow(|s| !s.subscribers.is_empty()) {
+            ic_cdk_timers::set_timer(std::time::Duration::ZERO, outbox::deliver_all());
         }
-
-        ic_cdk::futures::spawn(async move {
-            for subscriber in subscribers {
-                let _ = Call::unbounded_wait(subscriber, AGENT_EVENT_API)
-                    .with_arg(&event)
-                    .oneway();
-            }
-        });
     }
 
     pub async fn check_handle(
@@ -485,36 +1303,97 @@ pub mod state {
             error: format!("handle {handle:?} is not belong to {owner}"),
         })
     }
+
+    pub fn is_reserved_handle(handle: &str) -> bool {
+        RESERVED_HANDLES.contains(&handle)
+            || STATE.with_borrow(|s| s.reserved_handles.contains(handle))
+    }
+
+    pub fn get_reserved_handles() -> BTreeSet<String> {
+        STATE.with_borrow(|s| {
+            let mut handles = s.reserved_handles.clone();
+            handles.extend(RESERVED_HANDLES.iter().map(|h| h.to_string()));
+            handles
+        })
+    }
+
+    /// Reserves the handles, handles already claimed by agents are not affected.
+    pub fn add_reserved_handles(handles: BTreeSet<String>) {
+        STATE.with_borrow_mut(|s| s.reserved_handles.extend(handles))
+    }
+
+    /// Removes the handles reserved by governance, the built-in reserved handles can not be removed.
+    pub fn remove_reserved_handles(handles: &BTreeSet<String>) {
+        STATE.with_borrow_mut(|s| s.reserved_handles.retain(|h| !handles.contains(h)))
+    }
+
+    /// Re-verifies a batch of the mapped handles on their name canisters, continuing from
+    /// the last batch. Handles whose owner changed, or whose name canister is no longer
+    /// trusted, are unmapped. Returns the events of the affected agents.
+    pub async fn verify_handles(take: usize) -> Vec<AgentEvent> {
+        let cursor = HANDLE_CURSOR.with_borrow(|c| c.clone());
+        let (next, handles) = agent::mapped_handles(cursor.as_deref(), take);
+        HANDLE_CURSOR.with_borrow_mut(|c| *c = next);
+
+        let mut events = Vec::new();
+        for (handle, id, canister) in handles {
+            match check_handle(canister, handle.clone(), id).await {
+                // the name canister is unavailable, verify it in the next round
+                Ok(_) | Err(RegistryError::Generic { .. }) => {}
+                Err(_) => {
+                    let now_ms = ic_cdk::api::time() / MILLISECONDS;
+                    events.extend(agent::unmap_handle(id, &handle, now_ms));
+                }
+            }
+        }
+        events
+    }
 }
 
 pub mod agent {
     use super::*;
+    use std::ops::Bound::{Excluded, Unbounded};
 
+    /// Registers the agent.
+    /// Returns the agent whose dMsg handle was taken over by the agent, if any.
     pub fn register(
         id: Principal,
         challenged_by: Principal,
         info: AgentInfo,
-        tee: Option<TEEInfo>,
+        tee: Option<(TEEInfo, TEEClaims)>,
         code: ByteArrayB64<16>,
         now_ms: u64,
-    ) -> Result<(), RegistryError> {
-        INDEX.with_borrow_mut(|ri| {
-            if ri.id_map.contains_key(&id) {
+    ) -> Result<Option<Principal>, RegistryError> {
+        check_ids_indexed()?;
+        ID_INDEX.with_borrow_mut(|ri| {
+            if ri.contains_key(&id) {
                 return Err(RegistryError::AlreadyExists {
                     handle: id.to_string(),
                 });
             }
+            let displaced = AGENT_STORE.with_borrow(|ra| check_handle_claim(ra, None, &info))?;
             let (idx, challenge_expires_in_ms) = state::with_mut(|s| {
                 let idx = s.max_agent;
                 s.max_agent += 1;
                 (idx, s.challenge_expires_in_ms)
             });
 
-            ri.id_map.insert(id, (idx, now_ms));
-            if info.handle_canister.is_some() {
-                ri.by_handle.insert(info.handle.clone(), idx);
-            }
+            ri.insert(id, (idx, now_ms));
+            map_handle(idx, None, &info.handle);
 
+            update_indexes(idx, None, Some(&info));
+            state::count_challenger(challenged_by, true, false, now_ms);
+            record_challenge(
+                idx,
+                ChallengeRecordLocal {
+                    challenged_at: now_ms,
+                    challenged_by,
+                    health_power: 0,
+                    health_delta: 0,
+                    expired: false,
+                    tee: tee.is_some(),
+                },
+            );
             AGENT_STORE.with_borrow_mut(|ra| {
                 let agent = AgentLocal {
                     id,
@@ -527,37 +1406,43 @@ pub mod agent {
                     challenged_by,
                     challenged_expiration: now_ms + challenge_expires_in_ms,
                     tee: tee.map(|t| t.into()),
+                    change_seq: record_change(id, 0),
+                    status: AgentStatus::Active,
+                    status_reason: None,
+                    prev_status: None,
                 };
                 ra.insert(idx, agent.clone());
             });
 
-            Ok(())
+            Ok(displaced)
         })
     }
 
+    /// Challenges the agent and updates its information.
+    /// Returns the agent whose dMsg handle was taken over by the agent, if any.
     pub fn challenge(
         id: Principal,
         challenged_by: Principal,
         info: AgentInfo,
-        tee: Option<TEEInfo>,
+        tee: Option<(TEEInfo, TEEClaims)>,
         code: ByteArrayB64<16>,
         new_code: ByteArrayB64<16>,
         now_ms: u64,
-    ) -> Result<(), RegistryError> {
-        INDEX.with_borrow_mut(|ri| {
-            let (idx, challenged_at) =
-                ri.id_map
-                    .get_mut(&id)
-                    .ok_or_else(|| RegistryError::NotFound {
-                        handle: id.to_string(),
-                    })?;
+    ) -> Result<Option<Principal>, RegistryError> {
+        ID_INDEX.with_borrow_mut(|ri| {
+            let (idx, _) = ri.get(&id).ok_or_else(|| RegistryError::NotFound {
+                handle: id.to_string(),
+            })?;
 
             let challenge_expires_in_ms = state::with(|s| s.challenge_expires_in_ms);
 
             AGENT_STORE.with_borrow_mut(|ra| {
-                let mut agent = ra.get(idx).ok_or_else(|| RegistryError::NotFound {
+                let mut agent = ra.get(&idx).ok_or_else(|| RegistryError::NotFound {
                     handle: id.to_string(),
                 })?;
+                if agent.status.is_moderated() {
+                    return Err(moderated_error(&agent));
+                }
                 if code != agent.challenge_code {
                     return Err(RegistryError::BadRequest {
                         error: format!(
@@ -569,22 +1454,21 @@ pub mod agent {
 
                 if now_ms <= agent.challenged_at {
                     // 幂等保护：不回滚、不改动状态
-                    return Ok(());
+                    return Ok(None);
                 }
 
-                let old_mapped = agent.info.handle_canister.is_some();
-                let new_mapped = info.handle_canister.is_some();
-                if info.handle != agent.info.handle || old_mapped != new_mapped {
-                    if old_mapped {
-                        ri.by_handle.remove(&agent.info.handle);
-                    }
-                    if new_mapped {
-                        ri.by_handle.insert(info.handle.clone(), *idx);
-                    }
+                // an unchanged handle keeps its mapping, released handles are not claimed again
+                let mut displaced = None;
+                if info.handle != agent.info.handle
+                    || info.handle_canister.is_some() != agent.info.handle_canister.is_some()
+                {
+                    displaced = check_handle_claim(ra, Some(idx), &info)?;
+                    map_handle(idx, Some(&agent.info.handle), &info.handle);
                 }
 
-                ri.by_health_power.remove(&(agent.health_power, *idx));
-                if now_ms > agent.challenged_expiration {
+                let prev_health_power = agent.health_power;
+                let expired = now_ms > agent.challenged_expiration;
+                if expired {
                     // The previous challenge has expired, punish the agent
                     // 1. Reset the activation time
                     // 2. Reduce the health power
@@ -596,73 +1480,232 @@ pub mod agent {
                     agent.health_power += now_ms - agent.challenged_at;
                 }
 
-                if agent.health_power > ri.health_power_threshold {
-                    ri.by_health_power.insert((agent.health_power, *idx));
-                    if ri.by_health_power.len() > MAX_HEALTH_POWER_LIST {
-                        for _ in 0..TRIM_STEP {
-                            if ri.by_health_power.is_empty() {
-                                break;
-                            }
-                            ri.by_health_power.pop_first();
-                        }
-                        ri.health_power_threshold =
-                            ri.by_health_power.first().map(|(hp, _)| *hp).unwrap_or(0);
-                    }
-                }
-
-                ri.last_challenged.insert((now_ms, id));
-                if ri.last_challenged.len() > MAX_LAST_CHALLENGED {
-                    for _ in 0..TRIM_STEP {
-                        // remove 100 oldest challenged agent
-                        if ri.last_challenged.is_empty() {
-                            break;
-                        }
-                        ri.last_challenged.pop_first();
-                    }
-                }
-
-                *challenged_at = now_ms;
+                HEALTH_POWER_INDEX.with_borrow_mut(|rh| rh.remove(&(prev_health_power, idx)));
+                index_health_power(idx, agent.health_power);
+
+                LAST_CHALLENGED_INDEX.with_borrow_mut(|rl| rl.remove(&(agent.challenged_at, id)));
+                index_last_challenged(now_ms, id);
+
+                ri.insert(id, (idx, now_ms));
+                state::count_challenger(challenged_by, false, expired, now_ms);
+                record_challenge(
+                    idx,
+                    ChallengeRecordLocal {
+                        challenged_at: now_ms,
+                        challenged_by,
+                        health_power: agent.health_power,
+                        health_delta: agent.health_power as i64 - prev_health_power as i64,
+                        expired,
+                        tee: tee.is_some(),
+                    },
+                );
+                let prev_info: AgentInfo = agent.info.clone().into();
+                record_revision(idx, challenged_by, prev_info.diff(&info), now_ms);
+                update_indexes(idx, Some(&prev_info), Some(&info));
                 agent.challenge_code = new_code;
                 agent.info = info.into();
                 agent.tee = tee.map(|t| t.into());
                 agent.challenged_at = now_ms;
                 agent.challenged_by = challenged_by;
                 agent.challenged_expiration = now_ms + challenge_expires_in_ms;
+                agent.change_seq = record_change(id, agent.change_seq);
+                agent.status = AgentStatus::Active;
 
-                ra.insert(*idx, agent);
+                ra.insert(idx, agent);
 
-                Ok(())
+                Ok(displaced)
             })
         })
     }
 
-    pub fn get_agent(id: Principal) -> Result<Agent, RegistryError> {
-        let agent = INDEX.with_borrow(|ri| {
-            let (idx, _) = ri.id_map.get(&id).ok_or_else(|| RegistryError::NotFound {
+    /// Removes the agent from the registry and all indexes, freeing its handle.
+    /// If `code` is provided, it must match the agent's current challenge code.
+    pub fn unregister(id: Principal, code: Option<&ByteArrayB64<16>>) -> Result<(), RegistryError> {
+        let idx = agent_idx(&id)?;
+
+        AGENT_STORE.with_borrow_mut(|ra| {
+            let agent = ra.get(&idx).ok_or_else(|| RegistryError::NotFound {
                 handle: id.to_string(),
             })?;
-            AGENT_STORE.with_borrow(|ra| {
-                ra.get(idx).ok_or_else(|| RegistryError::NotFound {
-                    handle: id.to_string(),
-                })
+            if let Some(code) = code {
+                if agent.status == AgentStatus::Banned {
+                    return Err(moderated_error(&agent));
+                }
+                if *code != agent.challenge_code {
+                    return Err(RegistryError::BadRequest {
+                        error: format!(
+                            "challenge code is not match, expect {}, got {}",
+                            agent.challenge_code, code
+                        ),
+                    });
+                }
+            }
+
+            remove_agent(ra, idx, &agent);
+            Ok(())
+        })
+    }
+
+    /// Scans a batch of agents and moves long-dead agents through Active -> Expired -> Evicted.
+    ///
+    /// An active agent is marked as expired when it has not been challenged successfully
+    /// for `expired_grace_ms` after its challenge expiration, and an expired agent is evicted
+    /// from the registry after `evicted_grace_ms`. Returns the events of the affected agents.
+    pub fn sweep(take: usize, now_ms: u64) -> Vec<AgentEvent> {
+        let (expired_grace_ms, evicted_grace_ms) =
+            state::with(|s| (s.expired_grace_ms, s.evicted_grace_ms));
+        let cursor = SWEEP_CURSOR.with_borrow(|c| *c);
+
+        let mut events = Vec::new();
+        let next = AGENT_STORE.with_borrow_mut(|ra| {
+            let agents: Vec<(u64, AgentLocal)> = ra
+                .range(cursor..)
+                .take(take)
+                .map(|entry| entry.into_pair())
+                .collect();
+            let next = if agents.len() < take {
+                0
+            } else {
+                agents.last().map(|(idx, _)| idx + 1).unwrap_or(0)
+            };
+
+            for (idx, mut agent) in agents {
+                // moderated agents stay until governance unbans them
+                if evicted_grace_ms > 0
+                    && !agent.status.is_moderated()
+                    && now_ms > agent.challenged_expiration.saturating_add(evicted_grace_ms)
+                {
+                    remove_agent(ra, idx, &agent);
+                    events.push(AgentEvent {
+                        seq: 0,
+                        id: agent.id,
+                        kind: AgentEventKind::Evicted,
+                        ts: now_ms,
+                    });
+                } else if agent.status == AgentStatus::Active
+                    && now_ms > agent.challenged_expiration.saturating_add(expired_grace_ms)
+                {
+                    HEALTH_POWER_INDEX.with_borrow_mut(|rh| rh.remove(&(agent.health_power, idx)));
+                    agent.status = AgentStatus::Expired;
+                    agent.change_seq = record_change(agent.id, agent.change_seq);
+                    events.push(AgentEvent {
+                        seq: 0,
+                        id: agent.id,
+                        kind: AgentEventKind::Expired,
+                        ts: now_ms,
+                    });
+                    ra.insert(idx, agent);
+                }
+            }
+            next
+        });
+
+        SWEEP_CURSOR.with_borrow_mut(|c| *c = next);
+        events
+    }
+
+    // Removes the agent from the store and all indexes, records the removal as a change.
+    fn remove_agent(
+        ra: &mut StableBTreeMap<u64, AgentLocal, Memory>,
+        idx: u64,
+        agent: &AgentLocal,
+    ) {
+        ra.remove(&idx);
+        ID_INDEX.with_borrow_mut(|ri| ri.remove(&agent.id));
+        HANDLE_INDEX.with_borrow_mut(|rh| {
+            if rh.get(&agent.info.handle) == Some(idx) {
+                rh.remove(&agent.info.handle);
+            }
+        });
+        state::with_mut(|s| {
+            s.handle_transfers
+                .retain(|_, (from, to)| *from != agent.id && *to != agent.id)
+        });
+        HEALTH_POWER_INDEX.with_borrow_mut(|rh| rh.remove(&(agent.health_power, idx)));
+        LAST_CHALLENGED_INDEX.with_borrow_mut(|rl| rl.remove(&(agent.challenged_at, agent.id)));
+        update_indexes(idx, Some(&agent.info.clone().into()), None);
+        CHALLENGE_STORE.with_borrow_mut(|rc| {
+            let keys: Vec<(u64, u64)> = rc.keys_range((idx, 0)..=(idx, u64::MAX)).collect();
+            for key in keys {
+                rc.remove(&key);
+            }
+        });
+        REVISION_STORE.with_borrow_mut(|rr| {
+            let keys: Vec<(u64, u64)> = rr.keys_range((idx, 0)..=(idx, u64::MAX)).collect();
+            for key in keys {
+                rr.remove(&key);
+            }
+        });
+        record_change(agent.id, agent.change_seq);
+    }
+
+    fn agent_idx(id: &Principal) -> Result<u64, RegistryError> {
+        ID_INDEX
+            .with_borrow(|ri| ri.get(id))
+            .map(|(idx, _)| idx)
+            .ok_or_else(|| RegistryError::NotFound {
+                handle: id.to_string(),
             })
-        })?;
+    }
+
+    pub fn get_agent(id: Principal) -> Result<Agent, RegistryError> {
+        let agent = ID_INDEX
+            .with_borrow(|ri| ri.get(&id))
+            .and_then(|(idx, _)| AGENT_STORE.with_borrow(|ra| ra.get(&idx)))
+            .ok_or_else(|| RegistryError::NotFound {
+                handle: id.to_string(),
+            })?;
 
         Ok(agent.into())
     }
 
+    /// Gets the agent from the local registry for discovery, suspended and banned agents
+    /// are not found. The records synchronized from peers are served by `get_foreign_agent`.
+    pub fn lookup(id: Principal) -> Result<Agent, RegistryError> {
+        match get_agent(id)? {
+            agent if agent.status.is_moderated() => Err(RegistryError::NotFound {
+                handle: id.to_string(),
+            }),
+            agent => Ok(agent),
+        }
+    }
+
     pub fn get_agent_by_handle(handle: String) -> Result<Agent, RegistryError> {
-        INDEX.with_borrow(|ri| {
-            let idx = ri
-                .by_handle
-                .get(&handle)
-                .ok_or_else(|| RegistryError::NotFound {
-                    handle: handle.clone(),
-                })?;
-            AGENT_STORE
-                .with_borrow(|ra| ra.get(idx).ok_or(RegistryError::NotFound { handle }))
-                .map(|a| a.into())
-        })
+        let agent = HANDLE_INDEX
+            .with_borrow(|rh| rh.get(&handle))
+            .and_then(|idx| AGENT_STORE.with_borrow(|ra| ra.get(&idx)));
+        match agent {
+            Some(agent) if !agent.status.is_moderated() => Ok(agent.into()),
+            _ => Err(RegistryError::NotFound { handle }),
+        }
+    }
+
+    /// Checks the number of items of a batch lookup.
+    pub fn check_batch(len: usize) -> Result<(), RegistryError> {
+        if len > MAX_LOOKUP_BATCH {
+            return Err(RegistryError::BadRequest {
+                error: format!(
+                    "too many items in batch lookup, expect at most {MAX_LOOKUP_BATCH}, got {len}"
+                ),
+            });
+        }
+        Ok(())
+    }
+
+    /// Gets the agents by their ids, with a result for each id in order.
+    pub fn get_agents(
+        ids: Vec<Principal>,
+    ) -> Result<Vec<Result<Agent, RegistryError>>, RegistryError> {
+        check_batch(ids.len())?;
+        Ok(ids.into_iter().map(lookup).collect())
+    }
+
+    /// Gets the agents by their handles, with a result for each handle in order.
+    pub fn get_agents_by_handles(
+        handles: Vec<String>,
+    ) -> Result<Vec<Result<Agent, RegistryError>>, RegistryError> {
+        check_batch(handles.len())?;
+        Ok(handles.into_iter().map(get_agent_by_handle).collect())
     }
 
     pub fn list(prev: Option<u64>, take: usize) -> Result<(u64, Vec<Agent>), RegistryError> {
@@ -674,7 +1717,9 @@ pub mod agent {
         AGENT_STORE.with_borrow(|ra| {
             let mut agents = Vec::with_capacity(take);
             loop {
-                if let Some(agent) = ra.get(&id) {
+                if let Some(agent) = ra.get(&id)
+                    && agent.status == AgentStatus::Active
+                {
                     agents.push(agent.into());
                     if agents.len() >= take {
                         break;
@@ -691,451 +1736,4013 @@ pub mod agent {
     }
 
     pub fn list_by_health_power(take: usize, now_ms: u64) -> Result<Vec<Agent>, RegistryError> {
-        INDEX.with_borrow(|ri| {
+        HEALTH_POWER_INDEX.with_borrow(|rh| {
             AGENT_STORE.with_borrow(|ra| {
+                // HEALTH_POWER_INDEX is ordered by the raw health power, agents whose challenge
+                // has expired are ranked by their decayed effective health power.
+                let mut agents: Vec<Agent> = rh
+                    .keys()
+                    .filter_map(|(_, idx)| ra.get(&idx))
+                    .map(|agent| Agent::from(agent).with_effective_health_power(now_ms))
+                    .filter(|agent| agent.effective_health_power > Some(0))
+                    .collect();
+                agents.sort_by(|a, b| {
+                    b.effective_health_power
+                        .cmp(&a.effective_health_power)
+                        .then_with(|| b.health_power.cmp(&a.health_power))
+                });
+                agents.truncate(take);
+
+                Ok(agents)
+            })
+        })
+    }
+
+    /// Searches active agents whose name, description or handle contain all tokens of the query.
+    ///
+    /// Results are ordered by agent_idx, `cursor` is the agent_idx to continue after.
+    /// Returns the next cursor, or `None` if there are no more results.
+    pub fn search(
+        query: &str,
+        take: usize,
+        cursor: Option<u64>,
+    ) -> Result<(Option<u64>, Vec<Agent>), RegistryError> {
+        let mut tokens: Vec<String> = tokenize(query).into_iter().collect();
+        if tokens.is_empty() {
+            return Err(RegistryError::BadRequest {
+                error: format!("invalid search query: {query:?}"),
+            });
+        }
+        // scan the longest token's entries, it is likely the most selective one
+        tokens.sort_by_key(|t| std::cmp::Reverse(t.len()));
+        let token = tokens.remove(0);
+
+        TOKEN_STORE.with_borrow(|rt| {
+            AGENT_STORE.with_borrow(|ra| {
+                let start = IndexKey {
+                    name: token.clone(),
+                    idx: cursor.map(|v| v.saturating_add(1)).unwrap_or(0),
+                };
                 let mut agents = Vec::with_capacity(take);
-                let iter = ri.by_health_power.iter().rev();
-                for (_, idx) in iter {
-                    if let Some(agent) = ra.get(idx) {
-                        if agent.challenged_expiration <= now_ms {
-                            continue;
-                        }
+                let mut last = None;
+                for (scanned, entry) in rt.range(start..).enumerate() {
+                    let key = entry.key();
+                    if key.name != token {
+                        return Ok((None, agents));
+                    }
+                    if agents.len() >= take || scanned >= MAX_SEARCH_SCAN {
+                        return Ok((last, agents));
+                    }
+                    last = Some(key.idx);
+                    if !tokens.iter().all(|t| {
+                        rt.contains_key(&IndexKey {
+                            name: t.clone(),
+                            idx: key.idx,
+                        })
+                    }) {
+                        continue;
+                    }
+                    if let Some(agent) = ra.get(&key.idx)
+                        && agent.status == AgentStatus::Active
+                    {
+                        agents.push(agent.into());
+                    }
+                }
+                Ok((None, agents))
+            })
+        })
+    }
 
+    /// Lists active agents that support the protocol, newest first.
+    ///
+    /// `prev` is the agent_idx to continue before, returns the agent_idx of the last agent
+    /// in the result as the next `prev`, or 0 if there are no more agents.
+    pub fn list_by_protocol(
+        name: &str,
+        prev: Option<u64>,
+        take: usize,
+    ) -> Result<(u64, Vec<Agent>), RegistryError> {
+        let name = name.to_uppercase();
+        let start = IndexKey {
+            name: name.clone(),
+            idx: 0,
+        };
+        let end = IndexKey {
+            name,
+            idx: prev.unwrap_or(u64::MAX),
+        };
+        PROTOCOL_STORE.with_borrow(|rp| {
+            AGENT_STORE.with_borrow(|ra| {
+                let mut agents = Vec::with_capacity(take);
+                for entry in rp.range(start..end).rev() {
+                    let idx = entry.key().idx;
+                    if let Some(agent) = ra.get(&idx)
+                        && agent.status == AgentStatus::Active
+                    {
                         agents.push(agent.into());
                         if agents.len() >= take {
-                            break;
+                            return Ok((idx, agents));
                         }
                     }
                 }
-
-                Ok(agents)
+                Ok((0, agents))
             })
         })
     }
 
-    pub fn last_challenged(take: usize) -> Result<BTreeMap<Principal, u64>, RegistryError> {
-        INDEX.with_borrow(|ri| {
-            let mut rt = BTreeMap::new();
-            let iter = ri.last_challenged.iter().rev();
-            for (challenged_at, id) in iter.take(take) {
-                rt.insert(*id, *challenged_at);
-            }
-            Ok(rt)
+    /// Lists agents of the provider, newest first. Expired agents are included
+    /// so that providers can see the status of their whole fleet.
+    ///
+    /// `prev` is the agent_idx to continue before, returns the agent_idx of the last agent
+    /// in the result as the next `prev`, or 0 if there are no more agents.
+    pub fn list_by_provider(
+        provider: Principal,
+        prev: Option<u64>,
+        take: usize,
+    ) -> Result<(u64, Vec<Agent>), RegistryError> {
+        let end = prev.unwrap_or(u64::MAX);
+        PROVIDER_STORE.with_borrow(|rp| {
+            AGENT_STORE.with_borrow(|ra| {
+                let mut agents = Vec::with_capacity(take);
+                for entry in rp.range((provider, 0)..(provider, end)).rev() {
+                    let (_, idx) = *entry.key();
+                    if let Some(agent) = ra.get(&idx)
+                        && !agent.status.is_moderated()
+                    {
+                        agents.push(agent.into());
+                        if agents.len() >= take {
+                            return Ok((idx, agents));
+                        }
+                    }
+                }
+                Ok((0, agents))
+            })
         })
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use ic_auth_types::ByteArrayB64;
-    use rand::RngExt;
+    /// Indexes the agents in batches on timers after upgrade, then certifies their
+    /// lookup responses.
+    pub async fn index_all() {
+        if index_batch(INDEX_BATCH_SIZE) {
+            cert::certify_all(None).await;
+        } else {
+            ic_cdk_timers::set_timer(std::time::Duration::ZERO, index_all());
+        }
+    }
 
-    fn setup() {
-        STATE.with_borrow_mut(|s| {
-            s.name = "test_registry".to_string();
-            s.max_agent = 0;
-            s.challenge_expires_in_ms = 3600 * 1000; // 1 hour
-            s.peers = BTreeSet::new();
-            s.challengers = BTreeSet::new();
-            s.subscribers = BTreeSet::new();
-            s.name_canisters = BTreeSet::new();
-            s.governance_canister = None;
+    /// Builds the indexes of the agents stored before the indexes existed, `take` agents
+    /// at a time in registration order from the cursor saved in the state:
+    /// the indexes saved to INDEX_STORE by earlier versions are rebuilt from the stored agents,
+    /// the token, protocol and provider indexes are built, and the registry-native handles
+    /// are mapped first-come. Returns true when all agents are indexed.
+    pub fn index_batch(take: usize) -> bool {
+        let legacy = INDEX_STORE.with_borrow(|rs| !rs.get().is_empty());
+        let (indexed, mapped, cursor) = state::with(|s| {
+            (
+                s.indexes_initialized,
+                s.native_handles_mapped,
+                s.indexes_cursor,
+            )
         });
+        if !legacy && indexed && mapped {
+            return true;
+        }
 
-        INDEX.with_borrow_mut(|i| {
-            i.id_map.clear();
-            i.by_handle.clear();
-            i.by_health_power.clear();
-            i.last_challenged.clear();
-            i.health_power_threshold = 0;
+        let agents: Vec<(u64, AgentLocal)> = AGENT_STORE.with_borrow(|ra| {
+            ra.range(cursor..)
+                .take(take)
+                .map(|entry| entry.into_pair())
+                .collect()
         });
-
-        AGENT_STORE.with_borrow_mut(|a| {
-            // 清空存储
-            a.clear_new();
+        for (idx, agent) in &agents {
+            let idx = *idx;
+            if legacy {
+                ID_INDEX.with_borrow_mut(|ri| ri.insert(agent.id, (idx, agent.challenged_at)));
+                // earlier versions only mapped the handles of name canisters
+                if agent.info.handle_canister.is_some() {
+                    HANDLE_INDEX.with_borrow_mut(|rh| rh.insert(agent.info.handle.clone(), idx));
+                }
+                if agent.status == AgentStatus::Active {
+                    index_health_power(idx, agent.health_power);
+                }
+                // registration is not indexed as a challenge
+                if agent.challenged_at > agent.created_at {
+                    index_last_challenged(agent.challenged_at, agent.id);
+                }
+            }
+            if !indexed {
+                let info: AgentInfo = agent.info.clone().into();
+                TOKEN_STORE.with_borrow_mut(|rt| {
+                    update_index(rt, idx, BTreeSet::new(), info_tokens(&info))
+                });
+                PROTOCOL_STORE.with_borrow_mut(|rp| {
+                    update_index(rp, idx, BTreeSet::new(), info_protocols(&info))
+                });
+                if let Some(provider) = &info.provider {
+                    PROVIDER_STORE.with_borrow_mut(|rp| rp.insert((provider.id, idx), ()));
+                }
+            }
+            if !mapped
+                && agent.info.handle_canister.is_none()
+                && !state::is_reserved_handle(&agent.info.handle)
+            {
+                HANDLE_INDEX.with_borrow_mut(|rh| {
+                    if !rh.contains_key(&agent.info.handle) {
+                        rh.insert(agent.info.handle.clone(), idx);
+                    }
+                });
+            }
+        }
+
+        if agents.len() < take {
+            INDEX_STORE.with_borrow_mut(|rs| rs.set(Vec::new()));
+            state::with_mut(|s| {
+                s.indexes_initialized = true;
+                s.native_handles_mapped = true;
+                s.indexes_cursor = 0;
+            });
+            return true;
+        }
+        state::with_mut(|s| s.indexes_cursor = agents.last().map(|(idx, _)| idx + 1).unwrap_or(0));
+        false
+    }
+
+    /// Gets the challenge history of the agent, newest first.
+    /// `prev` is the record id to continue before.
+    pub fn get_challenge_history(
+        id: Principal,
+        prev: Option<u64>,
+        take: usize,
+    ) -> Result<Vec<ChallengeRecord>, RegistryError> {
+        let idx = agent_idx(&id)?;
+        let end = prev.unwrap_or(u64::MAX);
+        CHALLENGE_STORE.with_borrow(|rc| {
+            Ok(rc
+                .range((idx, 0)..(idx, end))
+                .rev()
+                .take(take)
+                .map(|entry| {
+                    let (_, seq) = *entry.key();
+                    entry.value().into_record(seq)
+                })
+                .collect())
+        })
+    }
+
+    /// Gets the revisions of the agent information, newest first.
+    /// `prev` is the `revised_at` of the last revision of the previous page.
+    pub fn get_agent_revisions(
+        id: Principal,
+        prev: Option<u64>,
+        take: usize,
+    ) -> Result<Vec<AgentRevision>, RegistryError> {
+        let idx = agent_idx(&id)?;
+        let end = prev.unwrap_or(u64::MAX);
+        REVISION_STORE.with_borrow(|rr| {
+            Ok(rr
+                .range((idx, 0)..(idx, end))
+                .rev()
+                .take(take)
+                .map(|entry| {
+                    let (_, revised_at) = *entry.key();
+                    entry.value().into_revision(revised_at)
+                })
+                .collect())
+        })
+    }
+
+    pub fn get_changes(after: Option<u64>, take: usize) -> Result<Vec<AgentChange>, RegistryError> {
+        let start = after.map(|v| v.saturating_add(1)).unwrap_or(0);
+        CHANGE_STORE.with_borrow(|rc| {
+            ID_INDEX.with_borrow(|ri| {
+                AGENT_STORE.with_borrow(|ra| {
+                    let changes = rc
+                        .range(start..)
+                        .take(take)
+                        .map(|entry| {
+                            let (seq, id) = (*entry.key(), entry.value());
+                            // suspended and banned agents are removed from peers
+                            let agent = ri
+                                .get(&id)
+                                .and_then(|(idx, _)| ra.get(&idx))
+                                .filter(|a| !a.status.is_moderated())
+                                .map(|a| a.into());
+                            AgentChange { seq, id, agent }
+                        })
+                        .collect();
+                    Ok(changes)
+                })
+            })
+        })
+    }
+
+    /// Suspends or bans the agent with the reason, or unbans it if `status` is `Active`.
+    /// Returns the event of the moderation.
+    pub fn moderate(
+        id: Principal,
+        status: AgentStatus,
+        reason: Option<String>,
+        now_ms: u64,
+    ) -> Result<AgentEvent, RegistryError> {
+        let idx = agent_idx(&id)?;
+
+        AGENT_STORE.with_borrow_mut(|ra| {
+            let mut agent = ra.get(&idx).ok_or_else(|| RegistryError::NotFound {
+                handle: id.to_string(),
+            })?;
+            let kind = match status {
+                AgentStatus::Suspended if agent.status == AgentStatus::Banned => {
+                    return Err(RegistryError::BadRequest {
+                        error: format!("agent {id} is banned"),
+                    });
+                }
+                AgentStatus::Suspended => AgentEventKind::Suspended,
+                AgentStatus::Banned => AgentEventKind::Banned,
+                AgentStatus::Active if agent.status.is_moderated() => AgentEventKind::Unbanned,
+                AgentStatus::Active => {
+                    return Err(RegistryError::BadRequest {
+                        error: format!("agent {id} is not suspended or banned"),
+                    });
+                }
+                AgentStatus::Expired => {
+                    return Err(RegistryError::BadRequest {
+                        error: "invalid status Expired".to_string(),
+                    });
+                }
+            };
+
+            if status.is_moderated() {
+                HEALTH_POWER_INDEX.with_borrow_mut(|rh| rh.remove(&(agent.health_power, idx)));
+                LAST_CHALLENGED_INDEX.with_borrow_mut(|rl| rl.remove(&(agent.challenged_at, id)));
+                if !agent.status.is_moderated() {
+                    agent.prev_status = Some(agent.status);
+                }
+                agent.status = status;
+            } else {
+                // an expired agent stays expired, it is re-indexed as before it was moderated
+                agent.status = agent.prev_status.take().unwrap_or_default();
+                if agent.status == AgentStatus::Active {
+                    index_health_power(idx, agent.health_power);
+                }
+                // registration is not indexed as a challenge
+                if agent.challenged_at > agent.created_at {
+                    index_last_challenged(agent.challenged_at, id);
+                }
+            }
+            agent.status_reason = reason;
+            agent.change_seq = record_change(id, agent.change_seq);
+            ra.insert(idx, agent);
+
+            Ok(AgentEvent {
+                seq: 0,
+                id,
+                kind,
+                ts: now_ms,
+            })
+        })
+    }
+
+    // Checks that the handle of the agent info can be mapped to the agent `idx`,
+    // `None` for a new agent. A handle verified on the name service takes over the
+    // mapping from other verified agents, registry-native handles are first-come.
+    // Returns the agent whose mapping is taken over.
+    fn check_handle_claim(
+        ra: &StableBTreeMap<u64, AgentLocal, Memory>,
+        idx: Option<u64>,
+        info: &AgentInfo,
+    ) -> Result<Option<Principal>, RegistryError> {
+        let verified = info.handle_canister.is_some();
+        if !verified && state::is_reserved_handle(&info.handle) {
+            return Err(RegistryError::BadRequest {
+                error: format!("handle {:?} is reserved", info.handle),
+            });
+        }
+
+        let holder = HANDLE_INDEX.with_borrow(|rh| rh.get(&info.handle));
+        match holder {
+            Some(holder) if Some(holder) != idx => match ra.get(&holder) {
+                Some(agent) if verified && agent.info.handle_canister.is_some() => {
+                    Ok(Some(agent.id))
+                }
+                _ => Err(RegistryError::AlreadyExists {
+                    handle: info.handle.clone(),
+                }),
+            },
+            _ => Ok(None),
+        }
+    }
+
+    // Maps the handle to the agent, the old handle is unmapped if it is mapped to the agent.
+    fn map_handle(idx: u64, old_handle: Option<&str>, handle: &str) {
+        HANDLE_INDEX.with_borrow_mut(|rh| {
+            if let Some(old_handle) = old_handle {
+                let old_handle = old_handle.to_string();
+                if rh.get(&old_handle) == Some(idx) {
+                    rh.remove(&old_handle);
+                }
+            }
+            rh.insert(handle.to_string(), idx);
+        });
+    }
+
+    // Gets the agent for the handle action requested by itself, it must hold a
+    // registry-native handle if `holding` is true.
+    fn handle_action_agent(
+        id: Principal,
+        code: &ByteArrayB64<16>,
+        holding: bool,
+    ) -> Result<(u64, AgentLocal), RegistryError> {
+        let idx = agent_idx(&id)?;
+        let agent =
+            AGENT_STORE
+                .with_borrow(|ra| ra.get(&idx))
+                .ok_or_else(|| RegistryError::NotFound {
+                    handle: id.to_string(),
+                })?;
+        if agent.status.is_moderated() {
+            return Err(moderated_error(&agent));
+        }
+        if *code != agent.challenge_code {
+            return Err(RegistryError::BadRequest {
+                error: format!(
+                    "challenge code is not match, expect {}, got {}",
+                    agent.challenge_code, code
+                ),
+            });
+        }
+        if holding
+            && (agent.info.handle_canister.is_some()
+                || HANDLE_INDEX.with_borrow(|rh| rh.get(&agent.info.handle)) != Some(idx))
+        {
+            return Err(RegistryError::BadRequest {
+                error: format!("agent {id} does not hold a registry-native handle"),
+            });
+        }
+        Ok((idx, agent))
+    }
+
+    /// Releases the registry-native handle of the agent, so that other agents can claim it.
+    /// The agent keeps its handle text, and does not claim it again until it changes its handle.
+    /// The challenge code is replaced by `next_code`, so that the request can not be replayed.
+    pub fn release_handle(
+        id: Principal,
+        code: &ByteArrayB64<16>,
+        next_code: ByteArrayB64<16>,
+        now_ms: u64,
+    ) -> Result<AgentEvent, RegistryError> {
+        let (idx, mut agent) = handle_action_agent(id, code, true)?;
+        HANDLE_INDEX.with_borrow_mut(|rh| rh.remove(&agent.info.handle));
+        state::with_mut(|s| s.handle_transfers.remove(&agent.info.handle));
+        agent.challenge_code = next_code;
+        agent.change_seq = record_change(id, agent.change_seq);
+        AGENT_STORE.with_borrow_mut(|ra| ra.insert(idx, agent));
+        Ok(AgentEvent {
+            seq: 0,
+            id,
+            kind: AgentEventKind::HandleUnmapped,
+            ts: now_ms,
+        })
+    }
+
+    /// Offers the registry-native handle of the agent to another registered agent,
+    /// replacing the previous offer of the handle.
+    /// The challenge code is replaced by `next_code`, so that the request can not be replayed.
+    pub fn transfer_handle(
+        id: Principal,
+        code: &ByteArrayB64<16>,
+        next_code: ByteArrayB64<16>,
+        to: Principal,
+    ) -> Result<(), RegistryError> {
+        let (idx, mut agent) = handle_action_agent(id, code, true)?;
+        if to == id {
+            return Err(RegistryError::BadRequest {
+                error: "can not transfer the handle to itself".to_string(),
+            });
+        }
+        let recipient = get_agent(to)?;
+        if recipient.status.is_moderated() {
+            return Err(RegistryError::BadRequest {
+                error: format!("agent {to} is {:?}", recipient.status),
+            });
+        }
+        state::with_mut(|s| {
+            s.handle_transfers
+                .insert(agent.info.handle.clone(), (id, to))
+        });
+        agent.challenge_code = next_code;
+        agent.change_seq = record_change(id, agent.change_seq);
+        AGENT_STORE.with_borrow_mut(|ra| ra.insert(idx, agent));
+        Ok(())
+    }
+
+    /// Accepts the registry-native handle offered to the agent, the agent's own
+    /// registry-native handle is released and the sender's handle is cleared.
+    /// Returns the events of the agent and the sender.
+    /// The challenge code is replaced by `next_code`, so that the request can not be replayed.
+    pub fn accept_handle(
+        id: Principal,
+        code: &ByteArrayB64<16>,
+        next_code: ByteArrayB64<16>,
+        handle: String,
+        now_ms: u64,
+    ) -> Result<Vec<AgentEvent>, RegistryError> {
+        let (idx, mut agent) = handle_action_agent(id, code, false)?;
+        if agent.info.handle_canister.is_some() {
+            return Err(RegistryError::BadRequest {
+                error: format!("agent {id} has a handle verified on the name service"),
+            });
+        }
+        let from = match state::with(|s| s.handle_transfers.get(&handle).copied()) {
+            Some((from, to)) if to == id => from,
+            _ => {
+                return Err(RegistryError::NotFound { handle });
+            }
+        };
+        // the offer is stale if the sender does not hold the handle anymore
+        let from_idx = agent_idx(&from)?;
+        let mut sender = AGENT_STORE
+            .with_borrow(|ra| ra.get(&from_idx))
+            .filter(|a| {
+                !a.status.is_moderated()
+                    && a.info.handle == handle
+                    && a.info.handle_canister.is_none()
+                    && HANDLE_INDEX.with_borrow(|rh| rh.get(&handle)) == Some(from_idx)
+            })
+            .ok_or_else(|| RegistryError::NotFound {
+                handle: handle.clone(),
+            })?;
+
+        state::with_mut(|s| {
+            s.handle_transfers.remove(&handle);
+            s.handle_transfers.remove(&agent.info.handle);
+        });
+        map_handle(idx, Some(&agent.info.handle), &handle);
+        let prev_info: AgentInfo = agent.info.clone().into();
+        agent.info.handle = handle;
+        // the sender gave the handle away, it has no handle until it sets a new one
+        let sender_prev_info: AgentInfo = sender.info.clone().into();
+        sender.info.handle = String::new();
+        for (idx, revised_by, prev, info) in [
+            (idx, id, &prev_info, &agent.info),
+            (from_idx, from, &sender_prev_info, &sender.info),
+        ] {
+            let info: AgentInfo = info.clone().into();
+            record_revision(idx, revised_by, prev.diff(&info), now_ms);
+            update_indexes(idx, Some(prev), Some(&info));
+        }
+        agent.challenge_code = next_code;
+        agent.change_seq = record_change(id, agent.change_seq);
+        sender.change_seq = record_change(from, sender.change_seq);
+        AGENT_STORE.with_borrow_mut(|ra| {
+            ra.insert(idx, agent);
+            ra.insert(from_idx, sender);
+        });
+
+        Ok(vec![
+            AgentEvent {
+                seq: 0,
+                id: from,
+                kind: AgentEventKind::HandleUnmapped,
+                ts: now_ms,
+            },
+            AgentEvent {
+                seq: 0,
+                id,
+                kind: AgentEventKind::HandleMapped,
+                ts: now_ms,
+            },
+        ])
+    }
+
+    /// Moves the registration of the agent to the new identity, including its health power,
+    /// creation time, handle and challenge history. The TEE information of the old identity
+    /// is replaced by the attestation of the new identity, or cleared.
+    /// The challenge code is replaced by `next_code`, so that the request can not be replayed.
+    /// Returns the event of the new identity.
+    pub fn rotate(
+        id: Principal,
+        new_id: Principal,
+        code: &ByteArrayB64<16>,
+        next_code: ByteArrayB64<16>,
+        tee: Option<(TEEInfo, TEEClaims)>,
+        now_ms: u64,
+    ) -> Result<AgentEvent, RegistryError> {
+        check_ids_indexed()?;
+        ID_INDEX.with_borrow_mut(|ri| {
+            let (idx, challenged_at) = ri.get(&id).ok_or_else(|| RegistryError::NotFound {
+                handle: id.to_string(),
+            })?;
+            if ri.contains_key(&new_id) {
+                return Err(RegistryError::AlreadyExists {
+                    handle: new_id.to_string(),
+                });
+            }
+
+            AGENT_STORE.with_borrow_mut(|ra| {
+                let mut agent = ra.get(&idx).ok_or_else(|| RegistryError::NotFound {
+                    handle: id.to_string(),
+                })?;
+                if agent.status.is_moderated() {
+                    return Err(moderated_error(&agent));
+                }
+                if *code != agent.challenge_code {
+                    return Err(RegistryError::BadRequest {
+                        error: format!(
+                            "challenge code is not match, expect {}, got {}",
+                            agent.challenge_code, code
+                        ),
+                    });
+                }
+
+                // the indexes keyed by agent_idx are not affected
+                ri.remove(&id);
+                ri.insert(new_id, (idx, challenged_at));
+                LAST_CHALLENGED_INDEX.with_borrow_mut(|rl| {
+                    if rl.remove(&(agent.challenged_at, id)).is_some() {
+                        rl.insert((agent.challenged_at, new_id), ());
+                    }
+                });
+                state::with_mut(|s| {
+                    for (from, to) in s.handle_transfers.values_mut() {
+                        if *from == id {
+                            *from = new_id;
+                        }
+                        if *to == id {
+                            *to = new_id;
+                        }
+                    }
+                });
+
+                // the old identity is removed from peers
+                record_change(id, agent.change_seq);
+                agent.id = new_id;
+                agent.tee = tee.map(|t| t.into());
+                agent.challenge_code = next_code;
+                agent.change_seq = record_change(new_id, 0);
+                ra.insert(idx, agent);
+
+                Ok(AgentEvent {
+                    seq: 0,
+                    id: new_id,
+                    kind: AgentEventKind::Rotated { from: id },
+                    ts: now_ms,
+                })
+            })
+        })
+    }
+
+    /// Gets a batch of the handles mapped to name canisters, ordered by handle.
+    /// `prev` is the handle to continue after, `take` handles of HANDLE_INDEX are scanned and
+    /// the native ones are skipped. Returns the last scanned handle to continue after, `None`
+    /// if the scan reached the end, and the (handle, agent_id, name_canister) of the batch.
+    pub fn mapped_handles(
+        prev: Option<&str>,
+        take: usize,
+    ) -> (Option<String>, Vec<(String, Principal, Principal)>) {
+        HANDLE_INDEX.with_borrow(|rh| {
+            AGENT_STORE.with_borrow(|ra| {
+                let iter = match prev {
+                    Some(prev) => rh.range((Excluded(prev.to_string()), Unbounded)),
+                    None => rh.range(..),
+                };
+                let mut scanned = 0;
+                let mut last = None;
+                let mut handles = Vec::new();
+                for entry in iter.take(take) {
+                    let (handle, idx) = entry.into_pair();
+                    scanned += 1;
+                    if let Some(agent) = ra.get(&idx)
+                        && let Some(canister) = agent.info.handle_canister
+                    {
+                        handles.push((handle.clone(), agent.id, canister));
+                    }
+                    last = Some(handle);
+                }
+                let next = if scanned < take { None } else { last };
+                (next, handles)
+            })
+        })
+    }
+
+    /// Unmaps the handle from the agent, after the handle no longer belongs to the agent
+    /// on the name service. The agent keeps its handle text, but it is not mapped to the
+    /// name canister anymore. Returns `None` if the handle is not mapped to the agent.
+    pub fn unmap_handle(id: Principal, handle: &str, now_ms: u64) -> Option<AgentEvent> {
+        let idx = agent_idx(&id).ok()?;
+        let handle = handle.to_string();
+        if HANDLE_INDEX.with_borrow(|rh| rh.get(&handle)) != Some(idx) {
+            return None;
+        }
+
+        AGENT_STORE.with_borrow_mut(|ra| {
+            let mut agent = ra.get(&idx)?;
+            if agent.info.handle != handle || agent.info.handle_canister.is_none() {
+                return None;
+            }
+
+            HANDLE_INDEX.with_borrow_mut(|rh| rh.remove(&handle));
+            agent.info.handle_canister = None;
+            agent.change_seq = record_change(id, agent.change_seq);
+            ra.insert(idx, agent);
+            Some(AgentEvent {
+                seq: 0,
+                id,
+                kind: AgentEventKind::HandleUnmapped,
+                ts: now_ms,
+            })
+        })
+    }
+
+    pub fn last_challenged(take: usize) -> Result<BTreeMap<Principal, u64>, RegistryError> {
+        LAST_CHALLENGED_INDEX.with_borrow(|rl| {
+            let mut rt = BTreeMap::new();
+            for (challenged_at, id) in rl.keys().rev().take(take) {
+                rt.insert(id, challenged_at);
+            }
+            Ok(rt)
+        })
+    }
+}
+
+// ID_INDEX is incomplete while the indexes saved by earlier versions are rebuilt,
+// new identities are rejected so that an agent can not be registered twice.
+fn check_ids_indexed() -> Result<(), RegistryError> {
+    if INDEX_STORE.with_borrow(|rs| !rs.get().is_empty()) {
+        return Err(RegistryError::Generic {
+            error: "the registry is rebuilding its indexes, try again later".to_string(),
+        });
+    }
+    Ok(())
+}
+
+fn moderated_error(agent: &AgentLocal) -> RegistryError {
+    RegistryError::Forbidden {
+        error: format!(
+            "agent {} is {:?}: {}",
+            agent.id,
+            agent.status,
+            agent.status_reason.as_deref().unwrap_or("no reason")
+        ),
+    }
+}
+
+// Splits the text into lowercase alphanumeric tokens, short or overlong tokens are ignored.
+fn tokenize(text: &str) -> BTreeSet<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|t| t.chars().count() >= 2 && t.len() <= MAX_TOKEN_LEN)
+        .map(|t| t.to_lowercase())
+        .filter(|t| t.len() <= MAX_TOKEN_LEN)
+        .collect()
+}
+
+fn info_tokens(info: &AgentInfo) -> BTreeSet<String> {
+    let mut tokens = tokenize(&info.handle);
+    tokens.extend(tokenize(&info.name));
+    for token in tokenize(&info.description) {
+        if tokens.len() >= MAX_TOKENS_PER_AGENT {
+            break;
+        }
+        tokens.insert(token);
+    }
+    tokens
+}
+
+fn info_protocols(info: &AgentInfo) -> BTreeSet<String> {
+    info.protocols.iter().map(|p| p.name.clone()).collect()
+}
+
+// Updates the token, protocol and provider index entries of the agent from the old info to the new info.
+// Inserts the agent into HEALTH_POWER_INDEX if its health power is above the threshold,
+// the index is trimmed to MAX_HEALTH_POWER_LIST and the threshold is raised.
+fn index_health_power(idx: u64, health_power: u64) {
+    HEALTH_POWER_INDEX.with_borrow_mut(|rh| {
+        let threshold = state::with(|s| s.health_power_threshold);
+        if health_power > threshold {
+            rh.insert((health_power, idx), ());
+            if rh.len() > MAX_HEALTH_POWER_LIST as u64 {
+                for _ in 0..TRIM_STEP {
+                    if rh.pop_first().is_none() {
+                        break;
+                    }
+                }
+                let threshold = rh.first_key_value().map(|((hp, _), _)| hp).unwrap_or(0);
+                state::with_mut(|s| s.health_power_threshold = threshold);
+            }
+        }
+    });
+}
+
+// Inserts the latest challenge of the agent into LAST_CHALLENGED_INDEX,
+// the index is trimmed to MAX_LAST_CHALLENGED.
+fn index_last_challenged(challenged_at: u64, id: Principal) {
+    LAST_CHALLENGED_INDEX.with_borrow_mut(|rl| {
+        rl.insert((challenged_at, id), ());
+        if rl.len() > MAX_LAST_CHALLENGED as u64 {
+            for _ in 0..TRIM_STEP {
+                // remove 100 oldest challenged agent
+                if rl.pop_first().is_none() {
+                    break;
+                }
+            }
+        }
+    });
+}
+
+fn update_indexes(idx: u64, old: Option<&AgentInfo>, new: Option<&AgentInfo>) {
+    let old_provider = old.and_then(|v| v.provider.as_ref()).map(|p| p.id);
+    let new_provider = new.and_then(|v| v.provider.as_ref()).map(|p| p.id);
+    if old_provider != new_provider {
+        PROVIDER_STORE.with_borrow_mut(|rp| {
+            if let Some(provider) = old_provider {
+                rp.remove(&(provider, idx));
+            }
+            if let Some(provider) = new_provider {
+                rp.insert((provider, idx), ());
+            }
+        });
+    }
+    TOKEN_STORE.with_borrow_mut(|rt| {
+        update_index(
+            rt,
+            idx,
+            old.map(info_tokens).unwrap_or_default(),
+            new.map(info_tokens).unwrap_or_default(),
+        )
+    });
+    PROTOCOL_STORE.with_borrow_mut(|rp| {
+        update_index(
+            rp,
+            idx,
+            old.map(info_protocols).unwrap_or_default(),
+            new.map(info_protocols).unwrap_or_default(),
+        )
+    });
+}
+
+fn update_index(
+    store: &mut StableBTreeMap<IndexKey, (), Memory>,
+    idx: u64,
+    old: BTreeSet<String>,
+    new: BTreeSet<String>,
+) {
+    for name in old.difference(&new) {
+        store.remove(&IndexKey {
+            name: name.clone(),
+            idx,
+        });
+    }
+    for name in new.difference(&old) {
+        store.insert(
+            IndexKey {
+                name: name.clone(),
+                idx,
+            },
+            (),
+        );
+    }
+}
+
+// Appends the challenge record to the challenge history of the agent,
+// keeps the latest MAX_CHALLENGE_RECORDS records.
+fn record_challenge(idx: u64, record: ChallengeRecordLocal) {
+    let seq = state::with_mut(|s| {
+        let seq = s.challenge_seq;
+        s.challenge_seq += 1;
+        seq
+    });
+    CHALLENGE_STORE.with_borrow_mut(|rc| {
+        rc.insert((idx, seq), record);
+        let keys: Vec<(u64, u64)> = rc.keys_range((idx, 0)..(idx, seq)).collect();
+        let overflow = (keys.len() + 1).saturating_sub(MAX_CHALLENGE_RECORDS);
+        for key in keys.into_iter().take(overflow) {
+            rc.remove(&key);
+        }
+    });
+}
+
+// Appends the changes of the agent information to the revision history of the agent,
+// keeps the latest MAX_AGENT_REVISIONS revisions.
+fn record_revision(idx: u64, revised_by: Principal, changes: Vec<AgentInfoChange>, now_ms: u64) {
+    if changes.is_empty() {
+        return;
+    }
+    REVISION_STORE.with_borrow_mut(|rr| {
+        rr.insert(
+            (idx, now_ms),
+            AgentRevisionLocal {
+                revised_by,
+                changes,
+            },
+        );
+        let keys: Vec<(u64, u64)> = rr.keys_range((idx, 0)..(idx, now_ms)).collect();
+        let overflow = (keys.len() + 1).saturating_sub(MAX_AGENT_REVISIONS);
+        for key in keys.into_iter().take(overflow) {
+            rr.remove(&key);
+        }
+    });
+}
+
+// Records a new change of the agent and drops its previous change, returns the new sequence number.
+fn record_change(id: Principal, prev_seq: u64) -> u64 {
+    let seq = state::with_mut(|s| {
+        s.change_seq += 1;
+        s.change_seq
+    });
+    CHANGE_STORE.with_borrow_mut(|rc| {
+        if prev_seq > 0 {
+            rc.remove(&prev_seq);
+        }
+        rc.insert(seq, id);
+    });
+    seq
+}
+
+pub mod cert {
+    use super::*;
+    use ic_auth_types::cbor_into_vec;
+    use once_cell::sync::Lazy;
+    use std::ops::Bound::{Excluded, Unbounded};
+
+    pub static LOOKUP_EXPR_PATH: Lazy<HttpCertificationPath<'static>> =
+        Lazy::new(|| HttpCertificationPath::exact("/lookup"));
+
+    // certifies the `id` or `handle` query parameter and the response
+    pub static LOOKUP_CEL_EXPR: Lazy<DefaultFullCelExpression<'static>> = Lazy::new(|| {
+        DefaultCelBuilder::full_certification()
+            .with_request_headers(vec![])
+            .with_request_query_parameters(vec!["id", "handle"])
+            .with_response_certification(DefaultResponseCertification::certified_response_headers(
+                vec!["content-type"],
+            ))
+            .build()
+    });
+
+    // certifies the fixed error responses of lookups that have no certified agent
+    pub static LOOKUP_ERROR_CEL_EXPR: Lazy<DefaultResponseOnlyCelExpression<'static>> =
+        Lazy::new(|| {
+            DefaultCelBuilder::response_only_certification()
+                .with_response_certification(
+                    DefaultResponseCertification::certified_response_headers(vec!["content-type"]),
+                )
+                .build()
+        });
+
+    static NOT_FOUND: Lazy<(HttpResponse<'static>, HttpCertificationTreeEntry<'static>)> =
+        Lazy::new(|| error_response(404, "Agent not found"));
+
+    static BAD_REQUEST: Lazy<(HttpResponse<'static>, HttpCertificationTreeEntry<'static>)> =
+        Lazy::new(|| error_response(400, "Bad request"));
+
+    fn error_response(
+        status: u16,
+        body: &str,
+    ) -> (HttpResponse<'static>, HttpCertificationTreeEntry<'static>) {
+        let res = HttpResponse::builder()
+            .with_status_code(status.try_into().expect("invalid status code"))
+            .with_headers(vec![
+                ("content-type".to_string(), "text/plain".to_string()),
+                (
+                    "ic-certificateexpression".to_string(),
+                    LOOKUP_ERROR_CEL_EXPR.to_string(),
+                ),
+            ])
+            .with_body(body.as_bytes().to_vec())
+            .build();
+        let certification = HttpCertification::response_only(&LOOKUP_ERROR_CEL_EXPR, &res, None)
+            .expect("failed to certify lookup error response");
+        let entry = HttpCertificationTreeEntry::new(LOOKUP_EXPR_PATH.clone(), certification);
+        (res, entry)
+    }
+
+    /// Builds the lookup response of the agent and its certification.
+    pub fn lookup_response(
+        key: &str,
+        value: &str,
+        agent: &Agent,
+        in_cbor: bool,
+    ) -> (HttpResponse<'static>, HttpCertification) {
+        let (content_type, body) = if in_cbor {
+            (
+                "application/cbor",
+                cbor_into_vec(agent).expect("failed to serialize agent in CBOR"),
+            )
+        } else {
+            (
+                "application/json",
+                serde_json::to_vec(agent).expect("failed to serialize agent in JSON"),
+            )
+        };
+        let req = HttpRequest::get(format!("/lookup?{key}={value}")).build();
+        let res = HttpResponse::ok(
+            body,
+            vec![
+                ("content-type".to_string(), content_type.to_string()),
+                (
+                    "ic-certificateexpression".to_string(),
+                    LOOKUP_CEL_EXPR.to_string(),
+                ),
+            ],
+        )
+        .build();
+        let certification = HttpCertification::full(&LOOKUP_CEL_EXPR, &req, &res, None)
+            .expect("failed to certify lookup response");
+        (res, certification)
+    }
+
+    /// Gets the certified response of the lookup request and its tree entry.
+    /// Lookups without a certified agent get a certified error response.
+    pub fn lookup(
+        key: &str,
+        value: &str,
+        in_cbor: bool,
+    ) -> (HttpResponse<'static>, HttpCertificationTreeEntry<'static>) {
+        let agent = match key {
+            "id" => match Principal::from_text(value) {
+                Ok(id) => agent::lookup(id),
+                Err(_) => return BAD_REQUEST.clone(),
+            },
+            "handle" => agent::get_agent_by_handle(value.to_string()),
+            _ => return BAD_REQUEST.clone(),
+        };
+
+        match agent {
+            Ok(agent) => {
+                let (res, certification) = lookup_response(key, value, &agent, in_cbor);
+                (
+                    res,
+                    HttpCertificationTreeEntry::new(LOOKUP_EXPR_PATH.clone(), certification),
+                )
+            }
+            Err(_) => NOT_FOUND.clone(),
+        }
+    }
+
+    /// Inserts the certified error responses into HTTP_TREE.
+    pub fn init() {
+        HTTP_TREE.with_borrow_mut(|t| {
+            t.insert(&NOT_FOUND.1);
+            t.insert(&BAD_REQUEST.1);
+        });
+    }
+
+    /// Refreshes the certified lookup responses of the agents in HTTP_TREE,
+    /// the responses of removed agents are removed.
+    pub fn certify(ids: &[Principal]) {
+        for id in ids {
+            let mut certifications = Vec::new();
+            if let Ok(agent) = agent::lookup(*id) {
+                let id_text = id.to_text();
+                for in_cbor in [false, true] {
+                    certifications.push(lookup_response("id", &id_text, &agent, in_cbor).1);
+                }
+                if agent::get_agent_by_handle(agent.info.handle.clone())
+                    .map(|v| v.id == *id)
+                    .unwrap_or(false)
+                {
+                    for in_cbor in [false, true] {
+                        certifications
+                            .push(lookup_response("handle", &agent.info.handle, &agent, in_cbor).1);
+                    }
+                }
+            }
+
+            let old = CERTIFIED.with_borrow_mut(|c| {
+                if certifications.is_empty() {
+                    c.remove(id)
+                } else {
+                    c.insert(*id, certifications.clone())
+                }
+            });
+            HTTP_TREE.with_borrow_mut(|t| {
+                for certification in old.unwrap_or_default() {
+                    t.delete(&HttpCertificationTreeEntry::new(
+                        LOOKUP_EXPR_PATH.clone(),
+                        certification,
+                    ));
+                }
+                for certification in certifications {
+                    t.insert(&HttpCertificationTreeEntry::new(
+                        LOOKUP_EXPR_PATH.clone(),
+                        certification,
+                    ));
+                }
+            });
+        }
+    }
+
+    /// Refreshes the certified lookup responses of the agents and sets the canister's certified data.
+    pub fn refresh(ids: &[Principal]) {
+        certify(ids);
+        set_certified_data();
+    }
+
+    pub fn set_certified_data() {
+        HTTP_TREE.with_borrow(|t| ic_cdk::api::certified_data_set(t.root_hash()));
+    }
+
+    /// Certifies the lookup responses of all agents in batches on timers,
+    /// used to rebuild HTTP_TREE after upgrade.
+    pub async fn certify_all(prev: Option<Principal>) {
+        let (ids, done) = certify_batch(prev, CERTIFY_BATCH_SIZE);
+        set_certified_data();
+        if !done {
+            ic_cdk_timers::set_timer(std::time::Duration::ZERO, certify_all(ids.last().copied()));
+        }
+    }
+
+    // Certifies a batch of agents ordered by id.
+    fn certify_batch(prev: Option<Principal>, take: usize) -> (Vec<Principal>, bool) {
+        let ids: Vec<Principal> = ID_INDEX.with_borrow(|ri| {
+            let iter = match prev {
+                Some(prev) => ri.keys_range((Excluded(prev), Unbounded)),
+                None => ri.keys(),
+            };
+            iter.take(take).collect()
+        });
+        let done = ids.len() < take;
+        certify(&ids);
+        (ids, done)
+    }
+}
+
+pub mod outbox {
+    use super::*;
+
+    /// Appends the event to the event log, returns its sequence number.
+    pub fn push(event: AgentEvent) -> u64 {
+        let seq = STATE.with_borrow_mut(|s| {
+            s.event_seq += 1;
+            let seq = s.event_seq;
+            // subscribers added before the outbox existed start from this event
+            for subscriber in &s.subscribers {
+                s.subscriber_cursors
+                    .entry(*subscriber)
+                    .or_insert_with(|| SubscriberCursor {
+                        delivered: seq - 1,
+                        ..Default::default()
+                    });
+            }
+            seq
+        });
+        OUTBOX.with_borrow_mut(|ro| ro.insert(seq, event.into()));
+        trim();
+        seq
+    }
+
+    /// Gets the events after the sequence number in order.
+    /// A gap between `after` and the first event's seq means that older events have been trimmed.
+    pub fn get_events(after: Option<u64>, take: usize) -> Vec<AgentEvent> {
+        let start = after.map(|v| v.saturating_add(1)).unwrap_or(0);
+        OUTBOX.with_borrow(|ro| {
+            ro.range(start..)
+                .take(take)
+                .map(|entry| {
+                    let (seq, event) = entry.into_pair();
+                    event.into_event(seq)
+                })
+                .collect()
+        })
+    }
+
+    /// Adds delivery cursors for the subscribers, they will receive events from now on.
+    pub fn add_subscribers(subscribers: &BTreeSet<Principal>) {
+        STATE.with_borrow_mut(|s| {
+            let seq = s.event_seq;
+            for subscriber in subscribers {
+                s.subscriber_cursors
+                    .entry(*subscriber)
+                    .or_insert_with(|| SubscriberCursor {
+                        delivered: seq,
+                        ..Default::default()
+                    });
+            }
+        });
+    }
+
+    /// Removes the delivery cursors of the subscribers and trims the outbox.
+    pub fn remove_subscribers(subscribers: &BTreeSet<Principal>) {
+        STATE.with_borrow_mut(|s| {
+            s.subscriber_cursors.retain(|k, _| !subscribers.contains(k));
+        });
+        trim();
+    }
+
+    /// Gets the events pending delivery to the subscriber if it is due for delivery.
+    pub fn pending(subscriber: Principal, take: usize, now_ms: u64) -> Vec<AgentEvent> {
+        let cursor = STATE.with_borrow(|s| {
+            if !s.subscribers.contains(&subscriber) {
+                return None;
+            }
+            s.subscriber_cursors
+                .get(&subscriber)
+                .filter(|c| c.retry_at <= now_ms)
+                .map(|c| c.delivered)
+        });
+        let Some(cursor) = cursor else {
+            return Vec::new();
+        };
+        get_events(Some(cursor), take)
+    }
+
+    /// Marks the event as delivered to the subscriber.
+    pub fn delivered(subscriber: Principal, seq: u64) {
+        STATE.with_borrow_mut(|s| {
+            if let Some(c) = s.subscriber_cursors.get_mut(&subscriber) {
+                c.delivered = c.delivered.max(seq);
+                c.retries = 0;
+                c.retry_at = 0;
+            }
+        });
+    }
+
+    /// Records a failed delivery of the event to the subscriber and schedules a retry
+    /// with exponential backoff. The event is dropped when the retries are exhausted.
+    pub fn failed(subscriber: Principal, seq: u64, now_ms: u64) {
+        STATE.with_borrow_mut(|s| {
+            if let Some(c) = s.subscriber_cursors.get_mut(&subscriber) {
+                c.retries += 1;
+                if c.retries > MAX_DELIVERY_RETRIES {
+                    c.delivered = c.delivered.max(seq);
+                    c.dropped += 1;
+                    c.retries = 0;
+                    c.retry_at = 0;
+                } else {
+                    let backoff = DELIVERY_BACKOFF_MS
+                        .saturating_mul(1 << (c.retries - 1))
+                        .min(MAX_DELIVERY_BACKOFF_MS);
+                    c.retry_at = now_ms + backoff;
+                }
+            }
+        });
+    }
+
+    /// Removes the events older than the latest MAX_EVENT_LOG events.
+    /// Subscribers lagging behind them are moved forward and the skipped events are
+    /// counted as dropped, so the outbox stays bounded while a subscriber is down.
+    pub fn trim() {
+        let delivered = STATE.with_borrow_mut(|s| {
+            let floor = s.event_seq.saturating_sub(MAX_EVENT_LOG);
+            for c in s.subscriber_cursors.values_mut() {
+                if c.delivered < floor {
+                    c.dropped += floor - c.delivered;
+                    c.delivered = floor;
+                    c.retries = 0;
+                    c.retry_at = 0;
+                }
+            }
+            floor
+        });
+        OUTBOX.with_borrow_mut(|ro| {
+            let seqs: Vec<u64> = ro.range(..=delivered).map(|entry| *entry.key()).collect();
+            for seq in seqs {
+                ro.remove(&seq);
+            }
+        });
+    }
+
+    pub fn get_subscriber_status() -> Vec<SubscriberStatus> {
+        STATE.with_borrow(|s| {
+            s.subscribers
+                .iter()
+                .map(|subscriber| {
+                    let c =
+                        s.subscriber_cursors
+                            .get(subscriber)
+                            .cloned()
+                            .unwrap_or(SubscriberCursor {
+                                delivered: s.event_seq,
+                                ..Default::default()
+                            });
+                    SubscriberStatus {
+                        subscriber: *subscriber,
+                        delivered_seq: c.delivered,
+                        latest_seq: s.event_seq,
+                        lag: s.event_seq.saturating_sub(c.delivered),
+                        retries: c.retries,
+                        next_retry_at: c.retry_at,
+                        dropped: c.dropped,
+                    }
+                })
+                .collect()
+        })
+    }
+
+    /// Delivers pending events to all subscribers, events are delivered to each subscriber in order.
+    pub async fn deliver_all() {
+        let subscribers = state::with(|s| s.subscribers.clone());
+        for subscriber in subscribers {
+            ic_cdk::futures::spawn(deliver(subscriber));
+        }
+    }
+
+    async fn deliver(subscriber: Principal) {
+        if !DELIVERING.with_borrow_mut(|d| d.insert(subscriber)) {
+            return;
+        }
+
+        loop {
+            let now_ms = ic_cdk::api::time() / MILLISECONDS;
+            let events = pending(subscriber, DELIVERY_BATCH_SIZE, now_ms);
+            if events.is_empty() {
+                break;
+            }
+            let mut ok = true;
+            for event in events {
+                match Call::bounded_wait(subscriber, AGENT_EVENT_API)
+                    .with_arg(&event)
+                    .await
+                {
+                    Ok(_) => delivered(subscriber, event.seq),
+                    Err(_) => {
+                        failed(subscriber, event.seq, ic_cdk::api::time() / MILLISECONDS);
+                        ok = false;
+                        break;
+                    }
+                }
+            }
+            if !ok {
+                break;
+            }
+        }
+
+        DELIVERING.with_borrow_mut(|d| d.remove(&subscriber));
+        trim();
+    }
+}
+
+pub mod peer {
+    use super::*;
+    use std::ops::Bound::{Excluded, Unbounded};
+
+    pub async fn sync_peers() {
+        let now_ms = ic_cdk::api::time() / MILLISECONDS;
+        let peers = state::with(|s| s.peers.clone());
+        for peer in peers {
+            for _ in 0..SYNC_MAX_ROUNDS {
+                match pull_changes(peer).await {
+                    Ok(changes) => {
+                        let done = (changes.len() as u64) < SYNC_BATCH_SIZE;
+                        apply_changes(peer, changes, now_ms);
+                        if done {
+                            break;
+                        }
+                    }
+                    Err(_) => break,
+                }
+            }
+        }
+    }
+
+    async fn pull_changes(peer: Principal) -> Result<Vec<AgentChange>, String> {
+        let cursor = state::with(|s| s.peer_cursors.get(&peer).copied());
+        let rt: Result<Vec<AgentChange>, RegistryError> = Call::bounded_wait(peer, "get_changes")
+            .with_args(&(cursor, Some(SYNC_BATCH_SIZE)))
+            .await
+            .map_err(|err| format!("{err:?}"))?
+            .candid()
+            .map_err(|err| format!("{err:?}"))?;
+        rt.map_err(|err| err.to_string())
+    }
+
+    /// Applies the changes pulled from the peer.
+    pub fn apply_changes(peer: Principal, changes: Vec<AgentChange>, now_ms: u64) {
+        // the peer may be removed while pulling
+        if !state::with(|s| s.peers.contains(&peer)) {
+            return;
+        }
+
+        let mut cursor = state::with(|s| s.peer_cursors.get(&peer).copied().unwrap_or(0));
+        FOREIGN_AGENT_STORE.with_borrow_mut(|rf| {
+            for change in changes {
+                cursor = cursor.max(change.seq);
+                let existing = rf.get(&change.id);
+                match change.agent {
+                    Some(agent) if agent.id == change.id => {
+                        // local records take precedence over foreign records
+                        if ID_INDEX.with_borrow(|ri| ri.contains_key(&change.id)) {
+                            continue;
+                        }
+                        // keep the fresher record when multiple peers have the same agent
+                        if let Some(existing) = &existing
+                            && existing.peer != peer
+                            && existing.agent.challenged_at > agent.challenged_at
+                        {
+                            continue;
+                        }
+                        rf.insert(
+                            change.id,
+                            ForeignAgentLocal {
+                                agent: agent.into(),
+                                peer,
+                                seq: change.seq,
+                                synced_at: now_ms,
+                            },
+                        );
+                    }
+                    Some(_) => {}
+                    None => {
+                        if existing.map(|v| v.peer == peer).unwrap_or(false) {
+                            rf.remove(&change.id);
+                        }
+                    }
+                }
+            }
+        });
+
+        state::with_mut(|s| {
+            s.peer_cursors.insert(peer, cursor);
+        });
+    }
+
+    /// Removes the cursors and the foreign records of the peers.
+    pub fn remove_peers(peers: &BTreeSet<Principal>) {
+        state::with_mut(|s| {
+            s.peer_cursors.retain(|k, _| !peers.contains(k));
+        });
+        FOREIGN_AGENT_STORE.with_borrow_mut(|rf| {
+            let ids: Vec<Principal> = rf
+                .iter()
+                .filter(|entry| peers.contains(&entry.value().peer))
+                .map(|entry| *entry.key())
+                .collect();
+            for id in &ids {
+                rf.remove(id);
+            }
+        })
+    }
+
+    pub fn get_foreign_agent(id: Principal) -> Result<ForeignAgent, RegistryError> {
+        FOREIGN_AGENT_STORE.with_borrow(|rf| {
+            rf.get(&id)
+                .map(|v| v.into())
+                .ok_or_else(|| RegistryError::NotFound {
+                    handle: id.to_string(),
+                })
+        })
+    }
+
+    pub fn list_foreign_agents(
+        prev: Option<Principal>,
+        take: usize,
+    ) -> Result<Vec<ForeignAgent>, RegistryError> {
+        FOREIGN_AGENT_STORE.with_borrow(|rf| {
+            let iter = match prev {
+                Some(prev) => rf.range((Excluded(prev), Unbounded)),
+                None => rf.range(..),
+            };
+            Ok(iter.take(take).map(|entry| entry.value().into()).collect())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ic_auth_types::ByteArrayB64;
+    use rand::RngExt;
+
+    fn setup() {
+        STATE.with_borrow_mut(|s| {
+            s.name = "test_registry".to_string();
+            s.max_agent = 0;
+            s.challenge_expires_in_ms = 3600 * 1000; // 1 hour
+            s.peers = BTreeSet::new();
+            s.challengers = BTreeSet::new();
+            s.subscribers = BTreeSet::new();
+            s.name_canisters = BTreeSet::new();
+            s.governance_canister = None;
+            s.change_seq = 0;
+            s.peer_cursors = BTreeMap::new();
+            s.expired_grace_ms = EXPIRED_GRACE_MS;
+            s.evicted_grace_ms = EVICTED_GRACE_MS;
+            s.challenge_seq = 0;
+        });
+        SWEEP_CURSOR.with_borrow_mut(|c| *c = 0);
+        HANDLE_CURSOR.with_borrow_mut(|c| *c = None);
+        STATE.with_borrow_mut(|s| {
+            s.event_seq = 0;
+            s.subscriber_cursors = BTreeMap::new();
+            s.challenger_limits = ChallengerLimits::default();
+            s.challenger_limit_overrides = BTreeMap::new();
+            s.challenger_usage = BTreeMap::new();
+            s.challenger_counters = BTreeMap::new();
+            s.nitro_pcrs = BTreeSet::new();
+            s.provider_nitro_pcrs = BTreeMap::new();
+            s.tee_roots = BTreeMap::new();
+            s.tee_measurements = BTreeMap::new();
+            s.provider_tee_measurements = BTreeMap::new();
+            s.health_power_threshold = 0;
+            s.reserved_handles = BTreeSet::new();
+            s.handle_transfers = BTreeMap::new();
+            s.native_handles_mapped = false;
+            s.indexes_initialized = false;
+            s.indexes_cursor = 0;
+        });
+
+        INDEX_STORE.with_borrow_mut(|i| i.set(Vec::new()));
+        ID_INDEX.with_borrow_mut(|i| i.clear_new());
+        HANDLE_INDEX.with_borrow_mut(|h| h.clear_new());
+        HEALTH_POWER_INDEX.with_borrow_mut(|h| h.clear_new());
+        LAST_CHALLENGED_INDEX.with_borrow_mut(|l| l.clear_new());
+
+        AGENT_STORE.with_borrow_mut(|a| {
+            // 清空存储
+            a.clear_new();
+        });
+        CHANGE_STORE.with_borrow_mut(|c| c.clear_new());
+        FOREIGN_AGENT_STORE.with_borrow_mut(|f| f.clear_new());
+        TOKEN_STORE.with_borrow_mut(|t| t.clear_new());
+        PROTOCOL_STORE.with_borrow_mut(|p| p.clear_new());
+        PROVIDER_STORE.with_borrow_mut(|p| p.clear_new());
+        CHALLENGE_STORE.with_borrow_mut(|c| c.clear_new());
+        REVISION_STORE.with_borrow_mut(|r| r.clear_new());
+        OUTBOX.with_borrow_mut(|o| o.clear_new());
+        CERTIFIED.with_borrow_mut(|c| c.clear());
+        HTTP_TREE.with_borrow_mut(|t| *t = HttpCertificationTree::default());
+    }
+
+    fn random_principal() -> Principal {
+        let mut rng = rand::rng();
+        let mut bytes = [0u8; 29];
+        rng.fill(&mut bytes);
+        Principal::from_slice(&bytes)
+    }
+
+    fn random_code() -> ByteArrayB64<16> {
+        let mut rng = rand::rng();
+        let mut bytes = [0u8; 16];
+        rng.fill(&mut bytes);
+        ByteArrayB64::from(bytes)
+    }
+
+    fn create_agent_info(handle: String, handle_canister: Option<Principal>) -> AgentInfo {
+        AgentInfo {
+            handle,
+            handle_canister,
+            name: "Test Agent".to_string(),
+            image: "https://example.com/image.png".to_string(),
+            description: "Test Description".to_string(),
+            endpoint: "https://example.com".to_string(),
+            protocols: Vec::new(),
+            provider: None,
+        }
+    }
+
+    #[test]
+    fn test_register() {
+        setup();
+
+        let id = random_principal();
+        let challenger = random_principal();
+        let code = random_code();
+        let info = create_agent_info(
+            "test_handle".to_string(),
+            Principal::from_text("nscli-qiaaa-aaaaj-qa4pa-cai").ok(),
+        );
+        let now_ms = 1000;
+
+        // 测试注册
+        let result = agent::register(id, challenger, info.clone(), None, code.clone(), now_ms);
+        assert!(result.is_ok());
+
+        // 测试重复注册
+        let result = agent::register(id, challenger, info.clone(), None, code.clone(), now_ms);
+        assert!(matches!(result, Err(RegistryError::AlreadyExists { .. })));
+
+        // 测试获取已注册的代理
+        let agent = agent::get_agent(id);
+        assert!(agent.is_ok());
+        let agent = agent.unwrap();
+        assert_eq!(agent.id, id);
+        assert_eq!(agent.health_power, 0);
+        assert_eq!(agent.challenged_at, now_ms);
+        assert_eq!(agent.challenged_by, challenger);
+        assert_eq!(agent.challenge_code, code);
+
+        let agent_by_handle = agent::get_agent_by_handle(info.handle);
+        assert!(agent_by_handle.is_ok());
+        assert_eq!(agent_by_handle.unwrap().id, id);
+    }
+
+    #[test]
+    fn test_challenge() {
+        setup();
+
+        let id = random_principal();
+        let challenger = random_principal();
+        let code = random_code();
+        let info = create_agent_info(
+            "test_handle".to_string(),
+            Principal::from_text("nscli-qiaaa-aaaaj-qa4pa-cai").ok(),
+        );
+        let now_ms = 1000;
+
+        // 先注册代理
+        let result = agent::register(id, challenger, info.clone(), None, code.clone(), now_ms);
+        assert!(result.is_ok());
+
+        // 测试挑战
+        let new_code = random_code();
+        let new_info = create_agent_info(
+            "new_handle".to_string(),
+            Principal::from_text("nscli-qiaaa-aaaaj-qa4pa-cai").ok(),
+        );
+        let new_now_ms = 2000;
+
+        // 测试错误的挑战码
+        let wrong_code = random_code();
+        let result = agent::challenge(
+            id,
+            challenger,
+            new_info.clone(),
+            None,
+            wrong_code,
+            new_code.clone(),
+            new_now_ms,
+        );
+        assert!(matches!(result, Err(RegistryError::BadRequest { .. })));
+
+        // 测试正确的挑战码
+        let result = agent::challenge(
+            id,
+            challenger,
+            new_info.clone(),
+            None,
+            code,
+            new_code.clone(),
+            new_now_ms,
+        );
+        assert!(result.is_ok());
+
+        // 验证挑战后的状态
+        let agent = agent::get_agent(id).unwrap();
+        assert_eq!(agent.challenge_code, new_code);
+        assert_eq!(agent.challenged_at, new_now_ms);
+        assert_eq!(agent.health_power, new_now_ms - now_ms); // 健康值应该增加
+
+        // 测试通过新句柄获取代理
+        let result = agent::get_agent_by_handle(info.handle);
+        assert!(matches!(result, Err(RegistryError::NotFound { .. })));
+
+        let result = agent::get_agent_by_handle(new_info.handle);
+        assert!(result.is_ok());
+        assert_eq!(result.unwrap().id, id);
+    }
+
+    #[test]
+    fn test_challenge_expired() {
+        setup();
+
+        let id = random_principal();
+        let challenger = random_principal();
+        let code = random_code();
+        let info = create_agent_info("test_handle".to_string(), None);
+        let now_ms = 1000;
+
+        // 设置过期时间为1小时
+        STATE.with_borrow_mut(|s| {
+            s.challenge_expires_in_ms = 3600 * 1000;
+        });
+
+        // 先注册代理
+        let result = agent::register(id, challenger, info.clone(), None, code.clone(), now_ms);
+        assert!(result.is_ok());
+
+        // 测试过期挑战（2小时后）
+        let new_code = random_code();
+        let new_info = create_agent_info("new_handle".to_string(), None);
+        let new_now_ms = now_ms + 7200 * 1000; // 2小时后
+
+        // 挑战
+        let result = agent::challenge(
+            id,
+            challenger,
+            new_info.clone(),
+            None,
+            code,
+            new_code.clone(),
+            new_now_ms,
+        );
+        assert!(result.is_ok());
+
+        // 验证挑战后的状态
+        let agent = agent::get_agent(id).unwrap();
+        assert_eq!(agent.challenge_code, new_code);
+        assert_eq!(agent.challenged_at, new_now_ms);
+        assert_eq!(agent.actived_start, new_now_ms); // 激活时间应该重置
+        assert_eq!(agent.health_power, 0); // 健康值应该被扣减
+    }
+
+    #[test]
+    fn test_handle_canister_toggle_without_handle_change() {
+        setup();
+
+        let id = random_principal();
+        let challenger = random_principal();
+        let code = random_code();
+
+        // 初始：handle 有 canister，建立 by_handle 映射
+        let info_v1 = create_agent_info(
+            "same_handle".to_string(),
+            Principal::from_text("nscli-qiaaa-aaaaj-qa4pa-cai").ok(),
+        );
+        let now_ms = 1_000;
+        agent::register(id, challenger, info_v1.clone(), None, code, now_ms).unwrap();
+
+        assert!(agent::get_agent_by_handle("same_handle".to_string()).is_ok());
+
+        // 切换为无 canister，但 handle 不变，转为注册表原生句柄，映射保留
+        let new_code = random_code();
+        let info_v2 = create_agent_info("same_handle".to_string(), None);
+        let t2 = now_ms + 5_000;
+        agent::challenge(
+            id,
+            challenger,
+            info_v2.clone(),
+            None,
+            agent::get_agent(id).unwrap().challenge_code,
+            new_code,
+            t2,
+        )
+        .unwrap();
+
+        assert_eq!(
+            agent::get_agent_by_handle("same_handle".to_string())
+                .unwrap()
+                .id,
+            id
+        );
+
+        // 再次切回有 canister，映射应保留
+        let new_code2 = random_code();
+        let info_v3 = create_agent_info(
+            "same_handle".to_string(),
+            Principal::from_text("nscli-qiaaa-aaaaj-qa4pa-cai").ok(),
+        );
+        let t3 = t2 + 5_000;
+        agent::challenge(
+            id,
+            challenger,
+            info_v3.clone(),
+            None,
+            agent::get_agent(id).unwrap().challenge_code,
+            new_code2,
+            t3,
+        )
+        .unwrap();
+
+        assert!(agent::get_agent_by_handle("same_handle".to_string()).is_ok());
+    }
+
+    #[test]
+    fn test_challenge_idempotent_when_timestamp_not_advanced() {
+        setup();
+
+        let id = random_principal();
+        let challenger = random_principal();
+        let code = random_code();
+        let info = create_agent_info("h".to_string(), None);
+        let t = 1000;
+
+        agent::register(id, challenger, info.clone(), None, code, t).unwrap();
+
+        let before = agent::get_agent(id).unwrap();
+        // 使用相同或更小的 now_ms，按照逻辑应直接 Ok 且不改变状态
+        let res = agent::challenge(
+            id,
+            challenger,
+            info.clone(),
+            None,
+            before.challenge_code.clone(),
+            random_code(),
+            t, // not advanced
+        );
+        assert!(res.is_ok());
+
+        let after = agent::get_agent(id).unwrap();
+        assert_eq!(before.challenge_code, after.challenge_code);
+        assert_eq!(before.challenged_at, after.challenged_at);
+        assert_eq!(before.health_power, after.health_power);
+    }
+
+    #[test]
+    fn test_by_health_power_trim_and_threshold() {
+        setup();
+
+        // 设置更小阈值，便于触发修剪
+        STATE.with_borrow_mut(|s| s.challenge_expires_in_ms = 60_000);
+
+        let challenger = random_principal();
+        let base = 1_000u64;
+
+        // 注册超过 MAX_HEALTH_POWER_LIST 的代理，并逐步提升 health_power
+        let total = MAX_HEALTH_POWER_LIST + 50;
+        let mut ids = Vec::new();
+        for i in 0..total {
+            let id = random_principal();
+            ids.push(id);
+            agent::register(
+                id,
+                challenger,
+                create_agent_info(format!("h{i}"), None),
+                None,
+                random_code(),
+                base + i as u64,
+            )
+            .unwrap();
+        }
+
+        // 对全部做一次挑战，保证 health_power > 0
+        for (i, id) in ids.iter().enumerate() {
+            let a = agent::get_agent(*id).unwrap();
+            let _ = agent::challenge(
+                *id,
+                challenger,
+                create_agent_info(format!("h{i}"), None),
+                None,
+                a.challenge_code,
+                random_code(),
+                base + 10_000 + i as u64,
+            );
+        }
+
+        // 触发修剪分支（内部每次最多移除100个最低值）
+        let _top = agent::list_by_health_power(10, base + 20_000).unwrap();
+        // 无直接对内阈值可见的断言，但至少不应 panic 或越界
+        // 可通过再次挑战提高某个 agent 的分数，验证仍能进入榜单
+        let pick = ids[0];
+        let a = agent::get_agent(pick).unwrap();
+        agent::challenge(
+            pick,
+            challenger,
+            create_agent_info("h_boost".to_string(), None),
+            None,
+            a.challenge_code,
+            random_code(),
+            base + 50_000,
+        )
+        .unwrap();
+
+        let top = agent::list_by_health_power(100, base + 60_000).unwrap();
+        assert!(top.iter().any(|ag| ag.id == pick));
+    }
+
+    #[test]
+    fn test_list_functions() {
+        setup();
+
+        // 注册多个代理
+        let mut ids = Vec::new();
+        let challenger = random_principal();
+        let now_ms = 1000;
+
+        for i in 0..5 {
+            let id = random_principal();
+            ids.push(id);
+            let code = random_code();
+            let info = create_agent_info(format!("handle_{}", i), None);
+            let _ = agent::register(id, challenger, info, None, code, now_ms + i);
+        }
+
+        // 测试列表功能
+        let (last_id, agents) = agent::list(None, 3).unwrap();
+        assert_eq!(agents.len(), 3);
+        assert_eq!(last_id, 2);
+
+        let (last_id, agents) = agent::list(Some(2), 3).unwrap();
+        assert_eq!(agents.len(), 2);
+        assert_eq!(last_id, 0);
+
+        // 测试健康值列表
+        // 先进行一些挑战来增加健康值
+        for (i, id) in ids.iter().enumerate() {
+            if i % 2 == 0 {
+                let code = agent::get_agent(*id).unwrap().challenge_code;
+                let new_code = random_code();
+                let info = create_agent_info(format!("handle_{}_updated", i), None);
+                let new_now_ms = now_ms + 1000 + i as u64;
+                let _ = agent::challenge(*id, challenger, info, None, code, new_code, new_now_ms);
+            }
+        }
+
+        let agents = agent::list_by_health_power(10, now_ms + 10000).unwrap();
+        assert!(!agents.is_empty());
+
+        // 测试最近挑战列表
+        let last_challenged = agent::last_challenged(10).unwrap();
+        assert!(!last_challenged.is_empty());
+    }
+
+    #[test]
+    fn test_unregister() {
+        setup();
+
+        let id = random_principal();
+        let challenger = random_principal();
+        let code = random_code();
+        let info = create_agent_info(
+            "test_handle".to_string(),
+            Principal::from_text("nscli-qiaaa-aaaaj-qa4pa-cai").ok(),
+        );
+        let now_ms = 1000;
+
+        agent::register(id, challenger, info.clone(), None, code.clone(), now_ms).unwrap();
+        agent::challenge(
+            id,
+            challenger,
+            info.clone(),
+            None,
+            code,
+            random_code(),
+            now_ms + 1000,
+        )
+        .unwrap();
+
+        // 错误的挑战码
+        let result = agent::unregister(id, Some(&random_code()));
+        assert!(matches!(result, Err(RegistryError::BadRequest { .. })));
+        assert!(agent::get_agent(id).is_ok());
+
+        let code = agent::get_agent(id).unwrap().challenge_code;
+        agent::unregister(id, Some(&code)).unwrap();
+
+        assert!(matches!(
+            agent::get_agent(id),
+            Err(RegistryError::NotFound { .. })
+        ));
+        assert!(matches!(
+            agent::get_agent_by_handle(info.handle.clone()),
+            Err(RegistryError::NotFound { .. })
+        ));
+        assert!(agent::last_challenged(10).unwrap().is_empty());
+        assert!(
+            agent::list_by_health_power(10, now_ms + 2000)
+                .unwrap()
+                .is_empty()
+        );
+        assert_eq!(state::get_state().agents_total, 0);
+
+        // 句柄已释放，可被其他代理注册
+        let other = random_principal();
+        agent::register(other, challenger, info.clone(), None, random_code(), now_ms).unwrap();
+        assert_eq!(agent::get_agent_by_handle(info.handle).unwrap().id, other);
+
+        // 重复注销
+        let result = agent::unregister(id, None);
+        assert!(matches!(result, Err(RegistryError::NotFound { .. })));
+    }
+
+    #[test]
+    fn test_changes_and_peer_sync() {
+        setup();
+
+        let challenger = random_principal();
+        let a = random_principal();
+        let b = random_principal();
+        let code = random_code();
+        agent::register(
+            a,
+            challenger,
+            create_agent_info("a".to_string(), None),
+            None,
+            code.clone(),
+            1000,
+        )
+        .unwrap();
+        agent::register(
+            b,
+            challenger,
+            create_agent_info("b".to_string(), None),
+            None,
+            random_code(),
+            1000,
+        )
+        .unwrap();
+        agent::challenge(
+            a,
+            challenger,
+            create_agent_info("a".to_string(), None),
+            None,
+            code,
+            random_code(),
+            2000,
+        )
+        .unwrap();
+
+        // 每个代理只保留最新的变更
+        let changes = agent::get_changes(None, 10).unwrap();
+        assert_eq!(changes.len(), 2);
+        assert_eq!((changes[0].seq, changes[0].id), (2, b));
+        assert_eq!((changes[1].seq, changes[1].id), (3, a));
+        assert_eq!(agent::get_changes(Some(2), 10).unwrap().len(), 1);
+
+        // 注销后保留墓碑记录
+        let agent_a = agent::get_agent(a).unwrap();
+        agent::unregister(a, None).unwrap();
+        let changes = agent::get_changes(Some(2), 10).unwrap();
+        assert_eq!(changes.len(), 1);
+        assert_eq!((changes[0].seq, changes[0].id), (4, a));
+        assert!(changes[0].agent.is_none());
+
+        // 从对等注册中心同步
+        let peer = random_principal();
+        let other = random_principal();
+        agent::lookup(a).unwrap_err();
+        peer::apply_changes(
+            peer,
+            vec![AgentChange {
+                seq: 7,
+                id: a,
+                agent: Some(agent_a.clone()),
+            }],
+            5000,
+        );
+        // 不是对等节点，忽略
+        assert!(peer::get_foreign_agent(a).is_err());
+
+        STATE.with_borrow_mut(|s| {
+            s.peers.insert(peer);
+            s.peers.insert(other);
         });
+        peer::apply_changes(
+            peer,
+            vec![
+                AgentChange {
+                    seq: 7,
+                    id: a,
+                    agent: Some(agent_a.clone()),
+                },
+                // 本地记录优先
+                AgentChange {
+                    seq: 8,
+                    id: b,
+                    agent: Some(agent::get_agent(b).unwrap()),
+                },
+            ],
+            5000,
+        );
+        assert_eq!(
+            STATE.with_borrow(|s| s.peer_cursors.get(&peer).copied()),
+            Some(8)
+        );
+        let foreign = peer::get_foreign_agent(a).unwrap();
+        assert_eq!(foreign.peer, peer);
+        assert_eq!(foreign.seq, 7);
+        assert_eq!(foreign.synced_at, 5000);
+        assert!(peer::get_foreign_agent(b).is_err());
+        assert!(matches!(
+            agent::get_agent(a),
+            Err(RegistryError::NotFound { .. })
+        ));
+        // 外部记录只通过 get_foreign_agent 提供
+        assert!(agent::lookup(a).is_err());
+        assert_eq!(peer::list_foreign_agents(None, 10).unwrap().len(), 1);
+
+        // 其他节点的删除不影响该记录
+        peer::apply_changes(
+            other,
+            vec![AgentChange {
+                seq: 1,
+                id: a,
+                agent: None,
+            }],
+            6000,
+        );
+        assert!(peer::get_foreign_agent(a).is_ok());
+
+        peer::apply_changes(
+            peer,
+            vec![AgentChange {
+                seq: 9,
+                id: a,
+                agent: None,
+            }],
+            6000,
+        );
+        assert!(peer::get_foreign_agent(a).is_err());
+
+        peer::apply_changes(
+            peer,
+            vec![AgentChange {
+                seq: 10,
+                id: a,
+                agent: Some(agent_a),
+            }],
+            7000,
+        );
+        peer::remove_peers(&BTreeSet::from([peer]));
+        assert!(peer::get_foreign_agent(a).is_err());
+        assert!(STATE.with_borrow(|s| !s.peer_cursors.contains_key(&peer)));
     }
 
-    fn random_principal() -> Principal {
-        let mut rng = rand::rng();
-        let mut bytes = [0u8; 29];
-        rng.fill(&mut bytes);
-        Principal::from_slice(&bytes)
+    #[test]
+    fn test_sweep() {
+        setup();
+
+        let challenger = random_principal();
+        let a = random_principal();
+        let b = random_principal();
+        let now_ms = 1000;
+        let expires_in = STATE.with_borrow(|s| s.challenge_expires_in_ms);
+        agent::register(
+            a,
+            challenger,
+            create_agent_info("a".to_string(), None),
+            None,
+            random_code(),
+            now_ms,
+        )
+        .unwrap();
+        agent::register(
+            b,
+            challenger,
+            create_agent_info("b".to_string(), None),
+            None,
+            random_code(),
+            now_ms,
+        )
+        .unwrap();
+
+        // 宽限期内不处理
+        let t1 = now_ms + expires_in + EXPIRED_GRACE_MS;
+        assert!(agent::sweep(100, t1).is_empty());
+        assert_eq!(agent::list(None, 10).unwrap().1.len(), 2);
+
+        // 标记为过期
+        let t2 = t1 + 1;
+        let events = agent::sweep(100, t2);
+        assert_eq!(events.len(), 2);
+        assert!(events.iter().all(|e| e.kind == AgentEventKind::Expired));
+        assert_eq!(agent::get_agent(a).unwrap().status, AgentStatus::Expired);
+        assert!(agent::list(None, 10).unwrap().1.is_empty());
+        assert!(agent::sweep(100, t2 + 1).is_empty());
+
+        // 挑战成功后恢复为活跃
+        let code = agent::get_agent(a).unwrap().challenge_code;
+        agent::challenge(
+            a,
+            challenger,
+            create_agent_info("a".to_string(), None),
+            None,
+            code,
+            random_code(),
+            t2 + 2,
+        )
+        .unwrap();
+        assert_eq!(agent::get_agent(a).unwrap().status, AgentStatus::Active);
+        assert_eq!(agent::list(None, 10).unwrap().1.len(), 1);
+
+        // 驱逐长期失效的代理
+        let t3 = now_ms + expires_in + EVICTED_GRACE_MS + 1;
+        let events = agent::sweep(100, t3);
+        assert_eq!(events.len(), 2);
+        assert!(
+            events
+                .iter()
+                .any(|e| e.id == b && e.kind == AgentEventKind::Evicted)
+        );
+        assert!(
+            events
+                .iter()
+                .any(|e| e.id == a && e.kind == AgentEventKind::Expired)
+        );
+        assert!(matches!(
+            agent::get_agent(b),
+            Err(RegistryError::NotFound { .. })
+        ));
+        assert!(agent::get_agent(a).is_ok());
+        assert_eq!(state::get_state().agents_total, 1);
     }
 
-    fn random_code() -> ByteArrayB64<16> {
-        let mut rng = rand::rng();
-        let mut bytes = [0u8; 16];
-        rng.fill(&mut bytes);
-        ByteArrayB64::from(bytes)
+    #[test]
+    fn test_search() {
+        setup();
+
+        let challenger = random_principal();
+        let now_ms = 1000;
+        let mut ids = Vec::new();
+        for (handle, name, description) in [
+            ("chef_bot", "Recipe Chef", "Finds recipes for dinner"),
+            ("trader", "Trading Agent", "Crypto trading signals"),
+            ("cook", "Recipe Helper", "Step-by-step recipe guide"),
+        ] {
+            let id = random_principal();
+            let mut info = create_agent_info(handle.to_string(), None);
+            info.name = name.to_string();
+            info.description = description.to_string();
+            agent::register(id, challenger, info, None, random_code(), now_ms).unwrap();
+            ids.push(id);
+        }
+
+        // 关键词大小写不敏感
+        let (cursor, agents) = agent::search("RECIPE", 10, None).unwrap();
+        assert!(cursor.is_none());
+        let got: Vec<Principal> = agents.iter().map(|a| a.id).collect();
+        assert_eq!(got, vec![ids[0], ids[2]]);
+
+        // 多个关键词取交集
+        let (_, agents) = agent::search("recipe guide", 10, None).unwrap();
+        assert_eq!(agents.len(), 1);
+        assert_eq!(agents[0].id, ids[2]);
+
+        // 按句柄搜索
+        let (_, agents) = agent::search("trader", 10, None).unwrap();
+        assert_eq!(agents[0].id, ids[1]);
+
+        // 分页
+        let (cursor, agents) = agent::search("recipe", 1, None).unwrap();
+        assert_eq!(agents[0].id, ids[0]);
+        assert_eq!(cursor, Some(0));
+        let (cursor, agents) = agent::search("recipe", 1, cursor).unwrap();
+        assert_eq!(agents[0].id, ids[2]);
+        assert!(cursor.is_none());
+
+        assert!(matches!(
+            agent::search(" - ", 10, None),
+            Err(RegistryError::BadRequest { .. })
+        ));
+
+        // 挑战时更新索引
+        let mut info = create_agent_info("trader".to_string(), None);
+        info.name = "Market Maker".to_string();
+        let code = agent::get_agent(ids[1]).unwrap().challenge_code;
+        agent::challenge(
+            ids[1],
+            challenger,
+            info,
+            None,
+            code,
+            random_code(),
+            now_ms + 1000,
+        )
+        .unwrap();
+        assert!(agent::search("trading", 10, None).unwrap().1.is_empty());
+        assert_eq!(agent::search("market", 10, None).unwrap().1[0].id, ids[1]);
+
+        // 注销时移除索引
+        agent::unregister(ids[0], None).unwrap();
+        let (_, agents) = agent::search("recipe", 10, None).unwrap();
+        assert_eq!(agents.len(), 1);
+        assert!(TOKEN_STORE.with_borrow(|rt| rt.iter().all(|entry| entry.key().idx != 0)));
     }
 
-    fn create_agent_info(handle: String, handle_canister: Option<Principal>) -> AgentInfo {
-        AgentInfo {
-            handle,
-            handle_canister,
-            name: "Test Agent".to_string(),
-            image: "https://example.com/image.png".to_string(),
-            description: "Test Description".to_string(),
-            endpoint: "https://example.com".to_string(),
-            protocols: Vec::new(),
-            provider: None,
+    #[test]
+    fn test_list_by_protocol() {
+        setup();
+
+        let challenger = random_principal();
+        let now_ms = 1000;
+        let protocol = |name: &str| AgentProtocol {
+            name: name.to_string(),
+            endpoint: "https://example.com/protocol".to_string(),
+            version: None,
+        };
+        let mut ids = Vec::new();
+        for protocols in [
+            vec![protocol("MCP")],
+            vec![protocol("A2A"), protocol("MCP")],
+            vec![protocol("A2A")],
+        ] {
+            let id = random_principal();
+            let mut info = create_agent_info(format!("agent_{}", ids.len()), None);
+            info.protocols = protocols;
+            agent::register(id, challenger, info, None, random_code(), now_ms).unwrap();
+            ids.push(id);
+        }
+
+        // 新注册的代理排在前面，名称大小写不敏感
+        let (prev, agents) = agent::list_by_protocol("mcp", None, 10).unwrap();
+        assert_eq!(prev, 0);
+        let got: Vec<Principal> = agents.iter().map(|a| a.id).collect();
+        assert_eq!(got, vec![ids[1], ids[0]]);
+
+        // 分页
+        let (prev, agents) = agent::list_by_protocol("A2A", None, 1).unwrap();
+        assert_eq!(agents[0].id, ids[2]);
+        assert_eq!(prev, 2);
+        let (prev, agents) = agent::list_by_protocol("A2A", Some(prev), 1).unwrap();
+        assert_eq!(agents[0].id, ids[1]);
+        let (_, agents) = agent::list_by_protocol("A2A", Some(prev), 1).unwrap();
+        assert!(agents.is_empty());
+        assert!(
+            agent::list_by_protocol("ANDA", None, 10)
+                .unwrap()
+                .1
+                .is_empty()
+        );
+
+        // 挑战时协议变更，索引随之更新
+        let mut info = create_agent_info("agent_1".to_string(), None);
+        info.protocols = vec![protocol("ANDA")];
+        let code = agent::get_agent(ids[1]).unwrap().challenge_code;
+        agent::challenge(
+            ids[1],
+            challenger,
+            info,
+            None,
+            code,
+            random_code(),
+            now_ms + 1000,
+        )
+        .unwrap();
+        let (_, agents) = agent::list_by_protocol("MCP", None, 10).unwrap();
+        assert_eq!(agents.len(), 1);
+        assert_eq!(agents[0].id, ids[0]);
+        let (_, agents) = agent::list_by_protocol("ANDA", None, 10).unwrap();
+        assert_eq!(agents[0].id, ids[1]);
+
+        // 注销时移除索引
+        agent::unregister(ids[2], None).unwrap();
+        assert!(
+            agent::list_by_protocol("A2A", None, 10)
+                .unwrap()
+                .1
+                .is_empty()
+        );
+        assert!(PROTOCOL_STORE.with_borrow(|rp| rp.len() == 2));
+    }
+
+    #[test]
+    fn test_list_by_provider() {
+        setup();
+
+        let challenger = random_principal();
+        let now_ms = 1000;
+        let provider = |id: Principal| AgentProvider {
+            id,
+            name: "Test Provider".to_string(),
+            logo: "https://example.com/logo.png".to_string(),
+            url: "https://example.com".to_string(),
+        };
+        let p1 = random_principal();
+        let p2 = random_principal();
+        let mut ids = Vec::new();
+        for p in [Some(p1), Some(p2), Some(p1), None] {
+            let id = random_principal();
+            let mut info = create_agent_info(format!("agent_{}", ids.len()), None);
+            info.provider = p.map(provider);
+            agent::register(id, challenger, info, None, random_code(), now_ms).unwrap();
+            ids.push(id);
+        }
+
+        let (prev, agents) = agent::list_by_provider(p1, None, 10).unwrap();
+        assert_eq!(prev, 0);
+        let got: Vec<Principal> = agents.iter().map(|a| a.id).collect();
+        assert_eq!(got, vec![ids[2], ids[0]]);
+
+        // 分页
+        let (prev, agents) = agent::list_by_provider(p1, None, 1).unwrap();
+        assert_eq!(agents[0].id, ids[2]);
+        assert_eq!(prev, 2);
+        let (_, agents) = agent::list_by_provider(p1, Some(prev), 1).unwrap();
+        assert_eq!(agents[0].id, ids[0]);
+
+        // 挑战时更换提供者
+        let mut info = create_agent_info("agent_1".to_string(), None);
+        info.provider = Some(provider(p1));
+        let code = agent::get_agent(ids[1]).unwrap().challenge_code;
+        agent::challenge(
+            ids[1],
+            challenger,
+            info,
+            None,
+            code,
+            random_code(),
+            now_ms + 1000,
+        )
+        .unwrap();
+        assert!(agent::list_by_provider(p2, None, 10).unwrap().1.is_empty());
+        assert_eq!(agent::list_by_provider(p1, None, 10).unwrap().1.len(), 3);
+
+        // 注销时移除索引
+        agent::unregister(ids[0], None).unwrap();
+        let (_, agents) = agent::list_by_provider(p1, None, 10).unwrap();
+        let got: Vec<Principal> = agents.iter().map(|a| a.id).collect();
+        assert_eq!(got, vec![ids[2], ids[1]]);
+        assert!(PROVIDER_STORE.with_borrow(|rp| rp.len() == 2));
+    }
+
+    #[test]
+    fn test_challenge_history() {
+        setup();
+
+        let id = random_principal();
+        let c1 = random_principal();
+        let c2 = random_principal();
+        let info = create_agent_info("test_handle".to_string(), None);
+        let now_ms = 1000;
+        let expires_in = state::with(|s| s.challenge_expires_in_ms);
+
+        let mut code = random_code();
+        agent::register(id, c1, info.clone(), None, code.clone(), now_ms).unwrap();
+
+        // 正常挑战，健康值增加
+        let new_code = random_code();
+        agent::challenge(
+            id,
+            c2,
+            info.clone(),
+            None,
+            code,
+            new_code.clone(),
+            now_ms + 1000,
+        )
+        .unwrap();
+        code = new_code;
+
+        // 过期挑战，健康值减少
+        let new_code = random_code();
+        let t3 = now_ms + 1000 + expires_in + 500;
+        agent::challenge(id, c1, info.clone(), None, code, new_code, t3).unwrap();
+
+        let history = agent::get_challenge_history(id, None, 10).unwrap();
+        assert_eq!(history.len(), 3);
+        assert_eq!(history[0].challenged_at, t3);
+        assert_eq!(history[0].challenged_by, c1);
+        assert!(history[0].expired);
+        assert_eq!(history[0].health_delta, -1000);
+        assert_eq!(history[0].health_power, 0);
+        assert_eq!(history[1].challenged_by, c2);
+        assert!(!history[1].expired);
+        assert_eq!(history[1].health_delta, 1000);
+        assert_eq!(history[1].health_power, 1000);
+        assert_eq!(history[2].challenged_at, now_ms);
+        assert!(!history[2].tee);
+
+        // 分页
+        let page = agent::get_challenge_history(id, Some(history[0].id), 1).unwrap();
+        assert_eq!(page.len(), 1);
+        assert_eq!(page[0].id, history[1].id);
+
+        // 其他代理的历史互不影响
+        let other = random_principal();
+        let other_info = create_agent_info("other".to_string(), None);
+        agent::register(other, c2, other_info, None, random_code(), now_ms).unwrap();
+        assert_eq!(
+            agent::get_challenge_history(other, None, 10).unwrap().len(),
+            1
+        );
+        assert_eq!(agent::get_challenge_history(id, None, 10).unwrap().len(), 3);
+
+        // 注销后历史不可查询
+        agent::unregister(id, None).unwrap();
+        assert!(matches!(
+            agent::get_challenge_history(id, None, 10),
+            Err(RegistryError::NotFound { .. })
+        ));
+        assert_eq!(CHALLENGE_STORE.with_borrow(|rc| rc.len()), 1);
+
+        // 每个代理最多保留 MAX_CHALLENGE_RECORDS 条
+        for i in 1..=MAX_CHALLENGE_RECORDS as u64 {
+            let code = agent::get_agent(other).unwrap().challenge_code;
+            let info = create_agent_info("other".to_string(), None);
+            agent::challenge(other, c1, info, None, code, random_code(), now_ms + i).unwrap();
+        }
+        let history = agent::get_challenge_history(other, None, 1000).unwrap();
+        assert_eq!(history.len(), MAX_CHALLENGE_RECORDS);
+        assert_eq!(
+            history[0].challenged_at,
+            now_ms + MAX_CHALLENGE_RECORDS as u64
+        );
+        assert_eq!(history.last().unwrap().challenged_at, now_ms + 1);
+        assert!(history.windows(2).all(|w| w[0].id > w[1].id));
+    }
+
+    #[test]
+    fn test_outbox() {
+        setup();
+
+        let event = |ts: u64| AgentEvent {
+            seq: 0,
+            id: random_principal(),
+            kind: AgentEventKind::Registered,
+            ts,
+        };
+
+        // 没有订阅者时也记录事件
+        assert_eq!(outbox::push(event(1)), 1);
+
+        let s1 = random_principal();
+        let s2 = random_principal();
+        let subscribers = BTreeSet::from([s1, s2]);
+        outbox::add_subscribers(&subscribers);
+        STATE.with_borrow_mut(|s| s.subscribers.extend(subscribers));
+
+        // 订阅者只接收订阅之后的事件
+        assert_eq!(outbox::push(event(2)), 2);
+        assert_eq!(outbox::push(event(3)), 3);
+        let pending = outbox::pending(s1, 10, 0);
+        assert_eq!(pending.len(), 2);
+        assert_eq!(pending[0].seq, 2);
+        assert_eq!(pending[0].ts, 2);
+
+        // s1 投递成功
+        outbox::delivered(s1, 2);
+        outbox::delivered(s1, 3);
+        assert!(outbox::pending(s1, 10, 0).is_empty());
+
+        // s2 投递失败，按退避时间重试
+        let now_ms = 1000;
+        outbox::failed(s2, 2, now_ms);
+        assert!(outbox::pending(s2, 10, now_ms).is_empty());
+        let pending = outbox::pending(s2, 10, now_ms + DELIVERY_BACKOFF_MS);
+        assert_eq!(pending.len(), 2);
+        outbox::failed(s2, 2, now_ms);
+        let status = outbox::get_subscriber_status();
+        let st = status.iter().find(|v| v.subscriber == s2).unwrap();
+        assert_eq!(st.lag, 2);
+        assert_eq!(st.retries, 2);
+        assert_eq!(st.next_retry_at, now_ms + DELIVERY_BACKOFF_MS * 2);
+        let st = status.iter().find(|v| v.subscriber == s1).unwrap();
+        assert_eq!(st.lag, 0);
+        assert_eq!(st.latest_seq, 3);
+
+        // 重试次数耗尽后丢弃该事件
+        for _ in 2..MAX_DELIVERY_RETRIES {
+            outbox::failed(s2, 2, now_ms);
+        }
+        assert_eq!(
+            outbox::get_subscriber_status()
+                .iter()
+                .find(|v| v.subscriber == s2)
+                .unwrap()
+                .dropped,
+            0
+        );
+        outbox::failed(s2, 2, now_ms);
+        let pending = outbox::pending(s2, 10, now_ms);
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].seq, 3);
+        let status = outbox::get_subscriber_status();
+        let st = status.iter().find(|v| v.subscriber == s2).unwrap();
+        assert_eq!(st.dropped, 1);
+        assert_eq!(st.lag, 1);
+
+        // 落后超过 MAX_EVENT_LOG 的订阅者被推进，跳过的事件计为丢弃
+        outbox::failed(s2, 3, now_ms);
+        STATE.with_borrow_mut(|s| s.event_seq += MAX_EVENT_LOG + 5);
+        let seq = outbox::push(event(4));
+        let status = outbox::get_subscriber_status();
+        let st = status.iter().find(|v| v.subscriber == s2).unwrap();
+        assert_eq!(st.delivered_seq, seq - MAX_EVENT_LOG);
+        assert_eq!(st.dropped, 1 + seq - MAX_EVENT_LOG - 2);
+        assert_eq!(st.retries, 0);
+        assert_eq!(st.lag, MAX_EVENT_LOG);
+        let events = outbox::get_events(None, 10);
+        assert_eq!(events.iter().map(|e| e.seq).collect::<Vec<_>>(), vec![seq]);
+        let pending = outbox::pending(s2, 10, now_ms);
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].seq, seq);
+
+        // 移除订阅者
+        STATE.with_borrow_mut(|s| s.subscribers.remove(&s2));
+        outbox::remove_subscribers(&BTreeSet::from([s2]));
+        assert!(outbox::pending(s2, 10, now_ms).is_empty());
+        assert!(
+            outbox::get_subscriber_status()
+                .iter()
+                .all(|v| v.subscriber != s2)
+        );
+    }
+
+    #[test]
+    fn test_get_events() {
+        setup();
+
+        for ts in 0..10 {
+            outbox::push(AgentEvent {
+                seq: 0,
+                id: random_principal(),
+                kind: AgentEventKind::Challenged,
+                ts,
+            });
+        }
+
+        let events = outbox::get_events(None, 3);
+        let seqs: Vec<u64> = events.iter().map(|e| e.seq).collect();
+        assert_eq!(seqs, vec![1, 2, 3]);
+        let events = outbox::get_events(Some(8), 10);
+        let seqs: Vec<u64> = events.iter().map(|e| e.seq).collect();
+        assert_eq!(seqs, vec![9, 10]);
+        assert_eq!(events[1].ts, 9);
+        assert!(outbox::get_events(Some(10), 10).is_empty());
+
+        // 超出保留数量的事件被裁剪，落后的订阅者被推进
+        let subscriber = random_principal();
+        STATE.with_borrow_mut(|s| {
+            s.subscribers.insert(subscriber);
+            s.subscriber_cursors.insert(
+                subscriber,
+                SubscriberCursor {
+                    delivered: 5,
+                    ..Default::default()
+                },
+            );
+            s.event_seq += MAX_EVENT_LOG;
+        });
+        outbox::trim();
+        assert!(outbox::get_events(None, 10).is_empty());
+        let status = outbox::get_subscriber_status();
+        assert_eq!(status[0].delivered_seq, 10);
+        assert_eq!(status[0].dropped, 5);
+    }
+
+    #[test]
+    fn test_get_nonexistent_agent() {
+        setup();
+
+        let id = random_principal();
+        let result = agent::get_agent(id);
+        assert!(matches!(result, Err(RegistryError::NotFound { .. })));
+
+        let result = agent::get_agent_by_handle("nonexistent".to_string());
+        assert!(matches!(result, Err(RegistryError::NotFound { .. })));
+    }
+
+    #[test]
+    fn test_get_agents() {
+        setup();
+
+        let challenger = random_principal();
+        let ids: Vec<Principal> = (0..3).map(|_| random_principal()).collect();
+        for (i, id) in ids.iter().enumerate() {
+            agent::register(
+                *id,
+                challenger,
+                create_agent_info(format!("agent_{i}"), None),
+                None,
+                random_code(),
+                1_000,
+            )
+            .unwrap();
+        }
+        agent::moderate(ids[1], AgentStatus::Suspended, Some("spam".into()), 2_000).unwrap();
+        agent::moderate(ids[2], AgentStatus::Banned, Some("phishing".into()), 2_000).unwrap();
+
+        // 按顺序返回每个 id 的结果，未知、暂停和封禁的代理返回 NotFound
+        let unknown = random_principal();
+        let rt = agent::get_agents(vec![unknown, ids[0], ids[1], ids[2], ids[0]]).unwrap();
+        assert_eq!(rt.len(), 5);
+        assert!(matches!(rt[0], Err(RegistryError::NotFound { .. })));
+        assert_eq!(rt[1].as_ref().unwrap().id, ids[0]);
+        assert!(matches!(rt[2], Err(RegistryError::NotFound { .. })));
+        assert!(matches!(rt[3], Err(RegistryError::NotFound { .. })));
+        assert_eq!(rt[4].as_ref().unwrap().id, ids[0]);
+
+        let handles = ["agent_2", "agent_0", "unknown", "agent_1"];
+        let rt =
+            agent::get_agents_by_handles(handles.iter().map(|h| h.to_string()).collect()).unwrap();
+        assert_eq!(rt.len(), 4);
+        assert!(matches!(rt[0], Err(RegistryError::NotFound { .. })));
+        assert_eq!(rt[1].as_ref().unwrap().id, ids[0]);
+        assert!(matches!(rt[2], Err(RegistryError::NotFound { .. })));
+        assert!(matches!(rt[3], Err(RegistryError::NotFound { .. })));
+
+        assert!(agent::get_agents(Vec::new()).unwrap().is_empty());
+
+        // 批量上限
+        let rt = agent::get_agents(vec![ids[0]; MAX_LOOKUP_BATCH]).unwrap();
+        assert_eq!(rt.len(), MAX_LOOKUP_BATCH);
+        assert!(matches!(
+            agent::get_agents(vec![ids[0]; MAX_LOOKUP_BATCH + 1]),
+            Err(RegistryError::BadRequest { .. })
+        ));
+        assert!(matches!(
+            agent::get_agents_by_handles(vec!["agent_0".to_string(); MAX_LOOKUP_BATCH + 1]),
+            Err(RegistryError::BadRequest { .. })
+        ));
+    }
+
+    #[test]
+    fn test_certified_lookup() {
+        setup();
+        cert::init();
+        let root = || HTTP_TREE.with_borrow(|t| t.root_hash());
+        // 插入已存在的条目不改变根哈希
+        let certified = |entry: &HttpCertificationTreeEntry| {
+            let hash = root();
+            HTTP_TREE.with_borrow_mut(|t| t.insert(entry));
+            hash == root()
+        };
+        let empty_hash = root();
+
+        let id = random_principal();
+        let challenger = random_principal();
+        let info = create_agent_info(
+            "test_handle".to_string(),
+            Principal::from_text("nscli-qiaaa-aaaaj-qa4pa-cai").ok(),
+        );
+        agent::register(id, challenger, info, None, random_code(), 1000).unwrap();
+        cert::certify(&[id]);
+        assert_ne!(root(), empty_hash);
+        assert_eq!(CERTIFIED.with_borrow(|c| c.get(&id).unwrap().len()), 4);
+
+        for in_cbor in [false, true] {
+            let (res, entry) = cert::lookup("id", &id.to_text(), in_cbor);
+            assert_eq!(res.status_code(), 200);
+            assert!(certified(&entry));
+            let (res, entry) = cert::lookup("handle", "test_handle", in_cbor);
+            assert_eq!(res.status_code(), 200);
+            assert!(certified(&entry));
+        }
+
+        // 不存在的智能体返回已认证的 404
+        let (res, entry) = cert::lookup("id", &random_principal().to_text(), false);
+        assert_eq!(res.status_code(), 404);
+        assert!(certified(&entry));
+        let (res, entry) = cert::lookup("id", "invalid", false);
+        assert_eq!(res.status_code(), 400);
+        assert!(certified(&entry));
+
+        // 更换 handle 后旧 handle 不再可查
+        let info = create_agent_info(
+            "new_handle".to_string(),
+            Principal::from_text("nscli-qiaaa-aaaaj-qa4pa-cai").ok(),
+        );
+        let code = agent::get_agent(id).unwrap().challenge_code;
+        agent::challenge(id, challenger, info, None, code, random_code(), 2000).unwrap();
+        cert::certify(&[id]);
+        let (res, _) = cert::lookup("handle", "test_handle", false);
+        assert_eq!(res.status_code(), 404);
+        let (res, entry) = cert::lookup("handle", "new_handle", false);
+        assert_eq!(res.status_code(), 200);
+        assert!(certified(&entry));
+        let (_, entry) = cert::lookup("id", &id.to_text(), false);
+        assert!(certified(&entry));
+
+        // 其他 dMsg 验证的代理接管 handle 后，原持有者的 handle 认证被移除
+        let other = random_principal();
+        let info = create_agent_info(
+            "new_handle".to_string(),
+            Principal::from_text("nscli-qiaaa-aaaaj-qa4pa-cai").ok(),
+        );
+        let displaced =
+            agent::register(other, challenger, info, None, random_code(), 3000).unwrap();
+        assert_eq!(displaced, Some(id));
+        cert::certify(&[other, id]);
+        let (res, entry) = cert::lookup("handle", "new_handle", false);
+        assert_eq!(res.status_code(), 200);
+        assert!(certified(&entry));
+        let agent: Agent = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(agent.id, other);
+        // 认证响应不含随时间变化的有效健康值
+        assert_eq!(agent.effective_health_power, None);
+        let body: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+        assert!(body.get("effective_health_power").is_none());
+        assert_eq!(CERTIFIED.with_borrow(|c| c.get(&id).unwrap().len()), 2);
+        let stale = cert::lookup_response(
+            "handle",
+            "new_handle",
+            &agent::get_agent(id).unwrap(),
+            false,
+        )
+        .1;
+        let hash = root();
+        HTTP_TREE.with_borrow_mut(|t| {
+            t.delete(&HttpCertificationTreeEntry::new(
+                cert::LOOKUP_EXPR_PATH.clone(),
+                stale,
+            ))
+        });
+        assert_eq!(root(), hash);
+        agent::unregister(other, None).unwrap();
+        cert::certify(&[other]);
+
+        // 注销后移除全部认证条目
+        agent::unregister(id, None).unwrap();
+        cert::certify(&[id]);
+        assert!(CERTIFIED.with_borrow(|c| c.is_empty()));
+        assert_eq!(root(), empty_hash);
+    }
+
+    #[test]
+    fn test_challenger_limits() {
+        setup();
+
+        let challenger = random_principal();
+        let now_ms = DAY_MS * 100;
+        assert!(matches!(
+            state::check_challenger(&challenger, false, now_ms),
+            Err(RegistryError::Forbidden { .. })
+        ));
+
+        state::with_mut(|s| {
+            s.challengers.insert(challenger);
+        });
+        // 默认不限制
+        for _ in 0..200 {
+            state::check_challenger(&challenger, true, now_ms).unwrap();
+        }
+        state::set_challenger_limits(
+            None,
+            Some(ChallengerLimits {
+                burst: 3,
+                refill_per_minute: 2,
+                registrations_per_day: 2,
+            }),
+        );
+
+        // 令牌桶耗尽
+        for _ in 0..3 {
+            state::check_challenger(&challenger, false, now_ms).unwrap();
+        }
+        assert!(matches!(
+            state::check_challenger(&challenger, false, now_ms),
+            Err(RegistryError::Forbidden { .. })
+        ));
+
+        // 每 30 秒补充一个令牌，余下的时间计入下一次补充
+        assert!(state::check_challenger(&challenger, false, now_ms + 29_000).is_err());
+        state::check_challenger(&challenger, false, now_ms + 45_000).unwrap();
+        assert!(state::check_challenger(&challenger, false, now_ms + 45_000).is_err());
+        state::check_challenger(&challenger, false, now_ms + 60_000).unwrap();
+
+        // 补充不超过容量
+        let later = now_ms + MINUTE_MS * 10;
+        for _ in 0..3 {
+            state::check_challenger(&challenger, true, later).unwrap();
+        }
+        assert!(state::check_challenger(&challenger, true, later).is_err());
+
+        // 每日注册配额
+        let later = later + MINUTE_MS * 10;
+        state::record_registration(&challenger, later);
+        state::record_registration(&challenger, later);
+        let err = state::check_challenger(&challenger, true, later).unwrap_err();
+        assert!(err.to_string().contains("registration quota"));
+        state::check_challenger(&challenger, false, later).unwrap();
+        state::check_challenger(&challenger, true, later + DAY_MS).unwrap();
+
+        // 单独覆盖挑战者的限制
+        state::set_challenger_limits(
+            Some(challenger),
+            Some(ChallengerLimits {
+                burst: 0,
+                refill_per_minute: 0,
+                registrations_per_day: 0,
+            }),
+        );
+        for _ in 0..10 {
+            state::check_challenger(&challenger, true, later).unwrap();
         }
+        state::set_challenger_limits(Some(challenger), None);
+        assert!(state::check_challenger(&challenger, true, later).is_err());
     }
 
     #[test]
-    fn test_register() {
+    fn test_challenger_stats() {
         setup();
 
+        let c1 = random_principal();
+        let c2 = random_principal();
+        state::with_mut(|s| {
+            s.challengers.insert(c1);
+        });
+        let expires_in = state::with(|s| s.challenge_expires_in_ms);
+
         let id = random_principal();
-        let challenger = random_principal();
-        let code = random_code();
-        let info = create_agent_info(
-            "test_handle".to_string(),
-            Principal::from_text("nscli-qiaaa-aaaaj-qa4pa-cai").ok(),
+        let info = create_agent_info("test_handle".to_string(), None);
+        agent::register(id, c1, info.clone(), None, random_code(), 1000).unwrap();
+
+        // 正常挑战
+        let code = agent::get_agent(id).unwrap().challenge_code;
+        agent::challenge(id, c2, info.clone(), None, code, random_code(), 2000).unwrap();
+        // 过期后恢复
+        let now_ms = 2000 + expires_in + 1;
+        let code = agent::get_agent(id).unwrap().challenge_code;
+        agent::challenge(id, c2, info.clone(), None, code, random_code(), now_ms).unwrap();
+        // 挑战码错误不计数
+        assert!(
+            agent::challenge(id, c1, info, None, random_code(), random_code(), now_ms + 1).is_err()
         );
-        let now_ms = 1000;
 
-        // 测试注册
-        let result = agent::register(id, challenger, info.clone(), None, code.clone(), now_ms);
-        assert!(result.is_ok());
+        let stats = state::get_challenger_stats();
+        assert_eq!(stats.len(), 2);
+        let s1 = stats.iter().find(|s| s.challenger == c1).unwrap();
+        assert!(s1.active);
+        assert_eq!(s1.registrations, 1);
+        assert_eq!(s1.challenges, 0);
+        assert_eq!(s1.last_active_at, 1000);
+        let s2 = stats.iter().find(|s| s.challenger == c2).unwrap();
+        assert!(!s2.active);
+        assert_eq!(s2.registrations, 0);
+        assert_eq!(s2.challenges, 2);
+        assert_eq!(s2.expired_recoveries, 1);
+        assert_eq!(s2.last_active_at, now_ms);
+    }
 
-        // 测试重复注册
-        let result = agent::register(id, challenger, info.clone(), None, code.clone(), now_ms);
-        assert!(matches!(result, Err(RegistryError::AlreadyExists { .. })));
+    #[test]
+    fn test_migrate_legacy_indexes() {
+        setup();
 
-        // 测试获取已注册的代理
-        let agent = agent::get_agent(id);
-        assert!(agent.is_ok());
-        let agent = agent.unwrap();
-        assert_eq!(agent.id, id);
-        assert_eq!(agent.health_power, 0);
-        assert_eq!(agent.challenged_at, now_ms);
-        assert_eq!(agent.challenged_by, challenger);
-        assert_eq!(agent.challenge_code, code);
+        let challenger = random_principal();
+        let a = random_principal();
+        let b = random_principal();
+        let handle_canister = Principal::from_text("nscli-qiaaa-aaaaj-qa4pa-cai").ok();
+        agent::register(
+            a,
+            challenger,
+            create_agent_info("agent_a".to_string(), handle_canister),
+            None,
+            random_code(),
+            1_000,
+        )
+        .unwrap();
+        agent::register(
+            b,
+            challenger,
+            create_agent_info("agent_b".to_string(), None),
+            None,
+            random_code(),
+            1_000,
+        )
+        .unwrap();
+        let code = agent::get_agent(a).unwrap().challenge_code;
+        agent::challenge(
+            a,
+            challenger,
+            create_agent_info("agent_a".to_string(), handle_canister),
+            None,
+            code,
+            random_code(),
+            2_000,
+        )
+        .unwrap();
 
-        let agent_by_handle = agent::get_agent_by_handle(info.handle);
-        assert!(agent_by_handle.is_ok());
-        assert_eq!(agent_by_handle.unwrap().id, id);
+        // 模拟旧版本：索引保存在 INDEX_STORE 中，稳定索引为空
+        ID_INDEX.with_borrow_mut(|i| i.clear_new());
+        HANDLE_INDEX.with_borrow_mut(|h| h.clear_new());
+        HEALTH_POWER_INDEX.with_borrow_mut(|h| h.clear_new());
+        LAST_CHALLENGED_INDEX.with_borrow_mut(|l| l.clear_new());
+        INDEX_STORE.with_borrow_mut(|i| i.set(vec![0xa0]));
+        state::with_mut(|s| {
+            s.indexes_initialized = true;
+            s.native_handles_mapped = true;
+        });
+
+        // 重建期间拒绝新的身份
+        assert!(matches!(
+            agent::register(
+                random_principal(),
+                challenger,
+                create_agent_info("agent_c".to_string(), None),
+                None,
+                random_code(),
+                3_000,
+            ),
+            Err(RegistryError::Generic { .. })
+        ));
+
+        // 分批重建，游标保存在状态中
+        assert!(!agent::index_batch(1));
+        assert_eq!(STATE.with_borrow(|s| s.indexes_cursor), 1);
+        assert_eq!(state::get_state().agents_total, 1);
+        assert!(!agent::index_batch(1));
+        assert!(agent::index_batch(1));
+        assert_eq!(STATE.with_borrow(|s| s.indexes_cursor), 0);
+        assert!(INDEX_STORE.with_borrow(|i| i.get().is_empty()));
+        assert_eq!(state::get_state().agents_total, 2);
+        assert_eq!(agent::get_agent(b).unwrap().id, b);
+        assert_eq!(
+            agent::get_agent_by_handle("agent_a".to_string())
+                .unwrap()
+                .id,
+            a
+        );
+        assert_eq!(
+            agent::list_by_health_power(10, 2_000)
+                .unwrap()
+                .iter()
+                .map(|ag| ag.id)
+                .collect::<Vec<_>>(),
+            vec![a]
+        );
+        assert_eq!(
+            agent::last_challenged(10).unwrap(),
+            BTreeMap::from([(a, 2_000)])
+        );
+        assert_eq!(LAST_CHALLENGED_INDEX.with_borrow(|l| l.len()), 1);
+
+        // 迁移只执行一次
+        assert!(agent::index_batch(1));
+        assert_eq!(STATE.with_borrow(|s| s.indexes_cursor), 0);
+        assert_eq!(state::get_state().agents_total, 2);
+
+        // 迁移后的索引可以继续维护
+        agent::unregister(a, None).unwrap();
+        assert!(agent::get_agent_by_handle("agent_a".to_string()).is_err());
+        assert!(agent::last_challenged(10).unwrap().is_empty());
+        assert!(agent::list_by_health_power(10, 2_000).unwrap().is_empty());
+        assert_eq!(state::get_state().agents_total, 1);
     }
 
     #[test]
-    fn test_challenge() {
+    fn test_mapped_handles_sweep() {
         setup();
 
-        let id = random_principal();
         let challenger = random_principal();
-        let code = random_code();
-        let info = create_agent_info(
-            "test_handle".to_string(),
-            Principal::from_text("nscli-qiaaa-aaaaj-qa4pa-cai").ok(),
-        );
-        let now_ms = 1000;
+        let canister = Principal::from_text("nscli-qiaaa-aaaaj-qa4pa-cai").unwrap();
+        let mut mapped = Vec::new();
+        for i in 0..10 {
+            // 原生句柄与 dMsg 句柄交错，且原生句柄占满前几个批次
+            let (handle, canister) = if i % 4 == 3 {
+                (format!("handle_{i}"), Some(canister))
+            } else {
+                (format!("handle_{i}"), None)
+            };
+            let id = random_principal();
+            if canister.is_some() {
+                mapped.push(handle.clone());
+            }
+            agent::register(
+                id,
+                challenger,
+                create_agent_info(handle, canister),
+                None,
+                random_code(),
+                1_000,
+            )
+            .unwrap();
+        }
 
-        // 先注册代理
-        let result = agent::register(id, challenger, info.clone(), None, code.clone(), now_ms);
-        assert!(result.is_ok());
+        // 首个批次只有原生句柄，游标仍需前进
+        let (next, batch) = agent::mapped_handles(None, 3);
+        assert!(batch.is_empty());
+        assert_eq!(next.as_deref(), Some("handle_2"));
+
+        let mut cursor = None;
+        let mut swept = Vec::new();
+        for _ in 0..10 {
+            let (next, batch) = agent::mapped_handles(cursor.as_deref(), 3);
+            swept.extend(batch.into_iter().map(|(handle, _, _)| handle));
+            cursor = next;
+            if cursor.is_none() {
+                break;
+            }
+        }
+        assert!(cursor.is_none());
+        assert_eq!(swept, mapped);
+    }
 
-        // 测试挑战
-        let new_code = random_code();
-        let new_info = create_agent_info(
-            "new_handle".to_string(),
-            Principal::from_text("nscli-qiaaa-aaaaj-qa4pa-cai").ok(),
-        );
-        let new_now_ms = 2000;
+    #[test]
+    fn test_unmap_handle() {
+        setup();
 
-        // 测试错误的挑战码
-        let wrong_code = random_code();
-        let result = agent::challenge(
-            id,
+        let challenger = random_principal();
+        let canister = Principal::from_text("nscli-qiaaa-aaaaj-qa4pa-cai").unwrap();
+        let mut ids = Vec::new();
+        for i in 0..3 {
+            let id = random_principal();
+            ids.push(id);
+            agent::register(
+                id,
+                challenger,
+                create_agent_info(format!("handle_{i}"), Some(canister)),
+                None,
+                random_code(),
+                1_000,
+            )
+            .unwrap();
+        }
+        // 未映射到名称服务的句柄不参与校验
+        agent::register(
+            random_principal(),
             challenger,
-            new_info.clone(),
+            create_agent_info("unmapped".to_string(), None),
             None,
-            wrong_code,
-            new_code.clone(),
-            new_now_ms,
-        );
-        assert!(matches!(result, Err(RegistryError::BadRequest { .. })));
+            random_code(),
+            1_000,
+        )
+        .unwrap();
 
-        // 测试正确的挑战码
-        let result = agent::challenge(
-            id,
+        // 分批遍历已映射的句柄
+        let (next, batch) = agent::mapped_handles(None, 2);
+        assert_eq!(
+            batch,
+            vec![
+                ("handle_0".to_string(), ids[0], canister),
+                ("handle_1".to_string(), ids[1], canister),
+            ]
+        );
+        assert_eq!(next.as_deref(), Some("handle_1"));
+        let (next, batch) = agent::mapped_handles(next.as_deref(), 2);
+        assert_eq!(batch, vec![("handle_2".to_string(), ids[2], canister)]);
+        assert_eq!(next.as_deref(), Some("unmapped"));
+        let (next, batch) = agent::mapped_handles(next.as_deref(), 2);
+        assert!(batch.is_empty());
+        assert!(next.is_none());
+
+        // 句柄不属于该代理时不处理
+        assert!(agent::unmap_handle(ids[1], "handle_0", 2_000).is_none());
+
+        let seq = state::with(|s| s.change_seq);
+        let event = agent::unmap_handle(ids[0], "handle_0", 2_000).unwrap();
+        assert_eq!(event.id, ids[0]);
+        assert_eq!(event.kind, AgentEventKind::HandleUnmapped);
+        assert_eq!(event.ts, 2_000);
+        assert!(agent::get_agent_by_handle("handle_0".to_string()).is_err());
+        let stored = agent::get_agent(ids[0]).unwrap();
+        assert_eq!(stored.info.handle, "handle_0");
+        assert!(stored.info.handle_canister.is_none());
+        let changes = agent::get_changes(Some(seq), 10).unwrap();
+        assert_eq!(changes.len(), 1);
+        assert_eq!(changes[0].id, ids[0]);
+
+        // 重复解除映射
+        assert!(agent::unmap_handle(ids[0], "handle_0", 3_000).is_none());
+        assert_eq!(agent::mapped_handles(None, 10).1.len(), 2);
+
+        // 重新通过校验的挑战可以再次映射句柄
+        let code = stored.challenge_code;
+        agent::challenge(
+            ids[0],
             challenger,
-            new_info.clone(),
+            create_agent_info("handle_0".to_string(), Some(canister)),
             None,
             code,
-            new_code.clone(),
-            new_now_ms,
+            random_code(),
+            4_000,
+        )
+        .unwrap();
+        assert_eq!(
+            agent::get_agent_by_handle("handle_0".to_string())
+                .unwrap()
+                .id,
+            ids[0]
         );
-        assert!(result.is_ok());
-
-        // 验证挑战后的状态
-        let agent = agent::get_agent(id).unwrap();
-        assert_eq!(agent.challenge_code, new_code);
-        assert_eq!(agent.challenged_at, new_now_ms);
-        assert_eq!(agent.health_power, new_now_ms - now_ms); // 健康值应该增加
-
-        // 测试通过新句柄获取代理
-        let result = agent::get_agent_by_handle(info.handle);
-        assert!(matches!(result, Err(RegistryError::NotFound { .. })));
-
-        let result = agent::get_agent_by_handle(new_info.handle);
-        assert!(result.is_ok());
-        assert_eq!(result.unwrap().id, id);
     }
 
     #[test]
-    fn test_challenge_expired() {
+    fn test_native_handles() {
         setup();
 
-        let id = random_principal();
         let challenger = random_principal();
-        let code = random_code();
-        let info = create_agent_info("test_handle".to_string(), None);
-        let now_ms = 1000;
+        let register = |id: Principal, handle: &str, canister: Option<Principal>| {
+            agent::register(
+                id,
+                challenger,
+                create_agent_info(handle.to_string(), canister),
+                None,
+                random_code(),
+                1_000,
+            )
+        };
+        let code_of = |id: Principal| agent::get_agent(id).unwrap().challenge_code;
+
+        // 先到先得
+        let a = random_principal();
+        register(a, "alice", None).unwrap();
+        assert_eq!(
+            agent::get_agent_by_handle("alice".to_string()).unwrap().id,
+            a
+        );
+        let b = random_principal();
+        assert!(matches!(
+            register(b, "alice", None),
+            Err(RegistryError::AlreadyExists { .. })
+        ));
+        // dMsg 句柄也不能占用原生句柄
+        let canister = Principal::from_text("nscli-qiaaa-aaaaj-qa4pa-cai").ok();
+        assert!(matches!(
+            register(b, "alice", canister),
+            Err(RegistryError::AlreadyExists { .. })
+        ));
 
-        // 设置过期时间为1小时
-        STATE.with_borrow_mut(|s| {
-            s.challenge_expires_in_ms = 3600 * 1000;
-        });
+        // 保留字
+        assert!(matches!(
+            register(b, "admin", None),
+            Err(RegistryError::BadRequest { .. })
+        ));
+        state::add_reserved_handles(BTreeSet::from(["bob".to_string()]));
+        assert!(state::get_reserved_handles().contains("bob"));
+        assert!(state::get_reserved_handles().contains("admin"));
+        assert!(matches!(
+            register(b, "bob", None),
+            Err(RegistryError::BadRequest { .. })
+        ));
+        state::remove_reserved_handles(&BTreeSet::from(["bob".to_string(), "admin".to_string()]));
+        assert!(state::get_reserved_handles().contains("admin"));
+        register(b, "bob", None).unwrap();
+        assert_eq!(state::get_state().agents_total, 2);
 
-        // 先注册代理
-        let result = agent::register(id, challenger, info.clone(), None, code.clone(), now_ms);
-        assert!(result.is_ok());
+        // 挑战时更换为已被占用的句柄
+        assert!(matches!(
+            agent::challenge(
+                b,
+                challenger,
+                create_agent_info("alice".to_string(), None),
+                None,
+                code_of(b),
+                random_code(),
+                2_000,
+            ),
+            Err(RegistryError::AlreadyExists { .. })
+        ));
 
-        // 测试过期挑战（2小时后）
-        let new_code = random_code();
-        let new_info = create_agent_info("new_handle".to_string(), None);
-        let new_now_ms = now_ms + 7200 * 1000; // 2小时后
+        // 释放需要正确的挑战码
+        assert!(matches!(
+            agent::release_handle(a, &random_code(), random_code(), 2_000),
+            Err(RegistryError::BadRequest { .. })
+        ));
+        let next_code = random_code();
+        let event = agent::release_handle(a, &code_of(a), next_code.clone(), 2_000).unwrap();
+        assert_eq!(event.kind, AgentEventKind::HandleUnmapped);
+        // 挑战码被更换
+        assert_eq!(code_of(a), next_code);
+        assert!(agent::get_agent_by_handle("alice".to_string()).is_err());
+        assert!(matches!(
+            agent::release_handle(a, &code_of(a), random_code(), 2_000),
+            Err(RegistryError::BadRequest { .. })
+        ));
+        // 句柄不变的挑战不会重新占用已释放的句柄
+        agent::challenge(
+            a,
+            challenger,
+            create_agent_info("alice".to_string(), None),
+            None,
+            code_of(a),
+            random_code(),
+            3_000,
+        )
+        .unwrap();
+        assert!(agent::get_agent_by_handle("alice".to_string()).is_err());
 
-        // 挑战
-        let result = agent::challenge(
-            id,
+        // 其他代理可以通过挑战更换为该句柄，旧句柄被释放
+        agent::challenge(
+            b,
             challenger,
-            new_info.clone(),
+            create_agent_info("alice".to_string(), None),
             None,
-            code,
-            new_code.clone(),
-            new_now_ms,
+            code_of(b),
+            random_code(),
+            3_000,
+        )
+        .unwrap();
+        assert_eq!(
+            agent::get_agent_by_handle("alice".to_string()).unwrap().id,
+            b
         );
-        assert!(result.is_ok());
+        assert!(agent::get_agent_by_handle("bob".to_string()).is_err());
 
-        // 验证挑战后的状态
-        let agent = agent::get_agent(id).unwrap();
-        assert_eq!(agent.challenge_code, new_code);
-        assert_eq!(agent.challenged_at, new_now_ms);
-        assert_eq!(agent.actived_start, new_now_ms); // 激活时间应该重置
-        assert_eq!(agent.health_power, 0); // 健康值应该被扣减
+        // 转让：b 将 alice 转让给 c
+        let c = random_principal();
+        register(c, "carol", None).unwrap();
+        assert!(matches!(
+            agent::transfer_handle(a, &code_of(a), random_code(), c),
+            Err(RegistryError::BadRequest { .. })
+        ));
+        assert!(matches!(
+            agent::transfer_handle(b, &code_of(b), random_code(), b),
+            Err(RegistryError::BadRequest { .. })
+        ));
+        assert!(matches!(
+            agent::transfer_handle(b, &code_of(b), random_code(), random_principal()),
+            Err(RegistryError::NotFound { .. })
+        ));
+        let code = code_of(b);
+        agent::transfer_handle(b, &code, random_code(), c).unwrap();
+        // 请求不能重放
+        assert!(matches!(
+            agent::transfer_handle(b, &code, random_code(), c),
+            Err(RegistryError::BadRequest { .. })
+        ));
+        // 只有接收方可以接受
+        assert!(matches!(
+            agent::accept_handle(a, &code_of(a), random_code(), "alice".to_string(), 4_000),
+            Err(RegistryError::NotFound { .. })
+        ));
+        let seq = state::with(|s| s.change_seq);
+        let events =
+            agent::accept_handle(c, &code_of(c), random_code(), "alice".to_string(), 4_000)
+                .unwrap();
+        assert_eq!(
+            events
+                .iter()
+                .map(|e| (e.id, e.kind.clone()))
+                .collect::<Vec<_>>(),
+            vec![
+                (b, AgentEventKind::HandleUnmapped),
+                (c, AgentEventKind::HandleMapped)
+            ]
+        );
+        assert_eq!(
+            agent::get_agent_by_handle("alice".to_string()).unwrap().id,
+            c
+        );
+        assert_eq!(agent::get_agent(c).unwrap().info.handle, "alice");
+        assert!(agent::get_agent_by_handle("carol".to_string()).is_err());
+        // 转出方的句柄被清空，不再与接收方重名
+        assert_eq!(agent::get_agent(b).unwrap().info.handle, "");
+        let (_, found) = agent::search("alice", 10, None).unwrap();
+        assert!(found.iter().any(|v| v.id == c));
+        assert!(!found.iter().any(|v| v.id == b));
+        let revisions = agent::get_agent_revisions(b, None, 10).unwrap();
+        assert_eq!(revisions[0].revised_by, b);
+        assert_eq!(agent::get_changes(Some(seq), 10).unwrap().len(), 2);
+        assert!(state::with(|s| s.handle_transfers.is_empty()));
+        // 重复接受
+        assert!(matches!(
+            agent::accept_handle(c, &code_of(c), random_code(), "alice".to_string(), 4_000),
+            Err(RegistryError::NotFound { .. })
+        ));
+
+        // 注销时移除其发出和收到的句柄转让
+        agent::transfer_handle(c, &code_of(c), random_code(), a).unwrap();
+        let d = random_principal();
+        register(d, "dave", None).unwrap();
+        agent::transfer_handle(d, &code_of(d), random_code(), c).unwrap();
+        assert_eq!(state::with(|s| s.handle_transfers.len()), 2);
+
+        // 注销后句柄被释放
+        agent::unregister(c, None).unwrap();
+        assert!(state::with(|s| s.handle_transfers.is_empty()));
+        register(random_principal(), "alice", None).unwrap();
+    }
+
+    #[test]
+    fn test_init_native_handles() {
+        setup();
+
+        let challenger = random_principal();
+        let ids: Vec<Principal> = (0..3).map(|_| random_principal()).collect();
+        for (i, id) in ids.iter().enumerate() {
+            agent::register(
+                *id,
+                challenger,
+                create_agent_info(format!("agent_{i}"), None),
+                None,
+                random_code(),
+                1_000,
+            )
+            .unwrap();
+        }
+
+        // 模拟旧版本：原生句柄未建立映射
+        HANDLE_INDEX.with_borrow_mut(|h| h.clear_new());
+        state::with_mut(|s| s.indexes_initialized = true);
+        while !agent::index_batch(2) {}
+        for (i, id) in ids.iter().enumerate() {
+            assert_eq!(
+                agent::get_agent_by_handle(format!("agent_{i}")).unwrap().id,
+                *id
+            );
+        }
+
+        // 只执行一次
+        HANDLE_INDEX.with_borrow_mut(|h| h.clear_new());
+        assert!(agent::index_batch(2));
+        assert!(agent::get_agent_by_handle("agent_0".to_string()).is_err());
+    }
+
+    #[test]
+    fn test_init_indexes() {
+        setup();
+
+        let challenger = random_principal();
+        let p = random_principal();
+        let ids: Vec<Principal> = (0..3).map(|_| random_principal()).collect();
+        for (i, id) in ids.iter().enumerate() {
+            let mut info = create_agent_info(format!("agent_{i}"), None);
+            info.provider = Some(AgentProvider {
+                id: p,
+                name: "Test Provider".to_string(),
+                logo: "https://example.com/logo.png".to_string(),
+                url: "https://example.com".to_string(),
+            });
+            agent::register(*id, challenger, info, None, random_code(), 1_000).unwrap();
+        }
+
+        // 模拟旧版本：索引尚未建立
+        PROVIDER_STORE.with_borrow_mut(|rp| rp.clear_new());
+        state::with_mut(|s| s.native_handles_mapped = true);
+        assert!(!agent::index_batch(2));
+        assert_eq!(agent::list_by_provider(p, None, 10).unwrap().1.len(), 2);
+        assert!(agent::index_batch(2));
+        let (_, agents) = agent::list_by_provider(p, None, 10).unwrap();
+        assert_eq!(agents.len(), 3);
+
+        // 只执行一次，索引为空（如所有代理都已注销）时不再重建
+        PROVIDER_STORE.with_borrow_mut(|rp| rp.clear_new());
+        assert!(agent::index_batch(2));
+        assert!(agent::list_by_provider(p, None, 10).unwrap().1.is_empty());
     }
 
     #[test]
-    fn test_handle_canister_toggle_without_handle_change() {
+    fn test_agent_revisions() {
         setup();
 
         let id = random_principal();
         let challenger = random_principal();
-        let code = random_code();
+        let info = create_agent_info("revised".to_string(), None);
+        agent::register(id, challenger, info.clone(), None, random_code(), 1_000).unwrap();
+        let challenge = |info: AgentInfo, now_ms: u64| {
+            let code = agent::get_agent(id).unwrap().challenge_code;
+            agent::challenge(id, challenger, info, None, code, random_code(), now_ms).unwrap();
+        };
+
+        // 信息未变化时不记录修订
+        challenge(info.clone(), 2_000);
+        assert!(agent::get_agent_revisions(id, None, 10).unwrap().is_empty());
+
+        let mut new_info = info.clone();
+        new_info.endpoint = "https://evil.example.com".to_string();
+        challenge(new_info.clone(), 3_000);
+        let revisions = agent::get_agent_revisions(id, None, 10).unwrap();
+        assert_eq!(revisions.len(), 1);
+        assert_eq!(revisions[0].revised_at, 3_000);
+        assert_eq!(revisions[0].revised_by, challenger);
+        assert_eq!(
+            revisions[0].changes,
+            vec![AgentInfoChange::Endpoint {
+                from: info.endpoint.clone(),
+                to: new_info.endpoint.clone(),
+            }]
+        );
 
-        // 初始：handle 有 canister，建立 by_handle 映射
-        let info_v1 = create_agent_info(
-            "same_handle".to_string(),
-            Principal::from_text("nscli-qiaaa-aaaaj-qa4pa-cai").ok(),
+        // 每个代理最多保留 MAX_AGENT_REVISIONS 条，按时间倒序分页
+        for i in 0..MAX_AGENT_REVISIONS as u64 {
+            let mut info = new_info.clone();
+            info.name = format!("Agent {i}");
+            challenge(info, 4_000 + i);
+        }
+        let revisions = agent::get_agent_revisions(id, None, 1000).unwrap();
+        assert_eq!(revisions.len(), MAX_AGENT_REVISIONS);
+        assert_eq!(
+            revisions[0].revised_at,
+            4_000 + MAX_AGENT_REVISIONS as u64 - 1
         );
-        let now_ms = 1_000;
-        agent::register(id, challenger, info_v1.clone(), None, code, now_ms).unwrap();
+        assert_eq!(revisions.last().unwrap().revised_at, 4_000);
+        let page = agent::get_agent_revisions(id, Some(revisions[9].revised_at), 10).unwrap();
+        assert_eq!(page[0].revised_at, revisions[10].revised_at);
+
+        // 注销后修订历史被清除
+        let code = agent::get_agent(id).unwrap().challenge_code;
+        agent::unregister(id, Some(&code)).unwrap();
+        assert!(agent::get_agent_revisions(id, None, 10).is_err());
+        assert_eq!(REVISION_STORE.with_borrow(|rr| rr.len()), 0);
+    }
 
-        assert!(agent::get_agent_by_handle("same_handle".to_string()).is_ok());
+    #[test]
+    fn test_rotate_identity() {
+        setup();
 
-        // 切换为无 canister，但 handle 不变，应移除映射
-        let new_code = random_code();
-        let info_v2 = create_agent_info("same_handle".to_string(), None);
-        let t2 = now_ms + 5_000;
+        let challenger = random_principal();
+        let old = random_principal();
+        agent::register(
+            old,
+            challenger,
+            create_agent_info("rotating".to_string(), None),
+            None,
+            random_code(),
+            1_000,
+        )
+        .unwrap();
+        let code = agent::get_agent(old).unwrap().challenge_code;
         agent::challenge(
-            id,
+            old,
             challenger,
-            info_v2.clone(),
+            create_agent_info("rotating".to_string(), None),
             None,
-            agent::get_agent(id).unwrap().challenge_code,
-            new_code,
-            t2,
+            code,
+            random_code(),
+            2_000,
+        )
+        .unwrap();
+        let before = agent::get_agent(old).unwrap();
+        let other = random_principal();
+        agent::register(
+            other,
+            challenger,
+            create_agent_info("other".to_string(), None),
+            None,
+            random_code(),
+            3_000,
         )
         .unwrap();
+        agent::transfer_handle(old, &before.challenge_code, random_code(), other).unwrap();
+        let before = agent::get_agent(old).unwrap();
 
+        let new = random_principal();
+        // 挑战码错误
         assert!(matches!(
-            agent::get_agent_by_handle("same_handle".to_string()),
-            Err(RegistryError::NotFound { .. })
+            agent::rotate(old, new, &random_code(), random_code(), None, 4_000),
+            Err(RegistryError::BadRequest { .. })
+        ));
+        // 新身份已注册
+        assert!(matches!(
+            agent::rotate(
+                old,
+                other,
+                &before.challenge_code,
+                random_code(),
+                None,
+                4_000
+            ),
+            Err(RegistryError::AlreadyExists { .. })
         ));
 
-        // 再次切回有 canister，映射应恢复
-        let new_code2 = random_code();
-        let info_v3 = create_agent_info(
-            "same_handle".to_string(),
-            Principal::from_text("nscli-qiaaa-aaaaj-qa4pa-cai").ok(),
-        );
-        let t3 = t2 + 5_000;
-        agent::challenge(
-            id,
-            challenger,
-            info_v3.clone(),
+        let next_code = random_code();
+        let event = agent::rotate(
+            old,
+            new,
+            &before.challenge_code,
+            next_code.clone(),
             None,
-            agent::get_agent(id).unwrap().challenge_code,
-            new_code2,
-            t3,
+            4_000,
         )
         .unwrap();
+        assert_eq!(event.id, new);
+        assert_eq!(event.kind, AgentEventKind::Rotated { from: old });
+        // 挑战码已轮换，A→B→A 的请求不能重放
+        assert!(matches!(
+            agent::rotate(new, old, &before.challenge_code, random_code(), None, 4_500),
+            Err(RegistryError::BadRequest { .. })
+        ));
 
-        assert!(agent::get_agent_by_handle("same_handle".to_string()).is_ok());
+        // 状态迁移到新身份
+        assert!(agent::get_agent(old).is_err());
+        let after = agent::get_agent(new).unwrap();
+        assert_eq!(after.created_at, before.created_at);
+        assert_eq!(after.health_power, before.health_power);
+        assert_eq!(after.challenged_at, before.challenged_at);
+        assert_eq!(after.challenge_code, next_code);
+        assert_eq!(
+            agent::get_agent_by_handle("rotating".to_string())
+                .unwrap()
+                .id,
+            new
+        );
+        assert_eq!(
+            agent::get_challenge_history(new, None, 10).unwrap().len(),
+            2
+        );
+        assert!(agent::get_challenge_history(old, None, 10).is_err());
+        let recent = agent::last_challenged(10).unwrap();
+        assert_eq!(recent.get(&new), Some(&before.challenged_at));
+        assert!(!recent.contains_key(&old));
+        assert_eq!(state::get_state().agents_total, 2);
+
+        // 旧身份从对等节点移除
+        let changes = agent::get_changes(None, 100).unwrap();
+        let n = changes.len();
+        assert_eq!(changes[n - 2].id, old);
+        assert!(changes[n - 2].agent.is_none());
+        assert_eq!(changes[n - 1].id, new);
+        assert!(changes[n - 1].agent.is_some());
+
+        // 待接受的句柄转让指向新身份
+        let other_code = agent::get_agent(other).unwrap().challenge_code;
+        let events = agent::accept_handle(
+            other,
+            &other_code,
+            random_code(),
+            "rotating".to_string(),
+            5_000,
+        )
+        .unwrap();
+        assert_eq!(events[0].id, new);
+        assert_eq!(
+            agent::get_agent_by_handle("rotating".to_string())
+                .unwrap()
+                .id,
+            other
+        );
+
+        // 被暂停的代理不能轮换身份
+        agent::moderate(new, AgentStatus::Suspended, Some("spam".into()), 6_000).unwrap();
+        assert!(matches!(
+            agent::rotate(
+                new,
+                random_principal(),
+                &after.challenge_code,
+                random_code(),
+                None,
+                7_000
+            ),
+            Err(RegistryError::Forbidden { .. })
+        ));
     }
 
     #[test]
-    fn test_challenge_idempotent_when_timestamp_not_advanced() {
+    fn test_effective_health_power() {
         setup();
+        STATE.with_borrow_mut(|s| s.challenge_expires_in_ms = 10_000);
 
-        let id = random_principal();
         let challenger = random_principal();
-        let code = random_code();
-        let info = create_agent_info("h".to_string(), None);
-        let t = 1000;
-
-        agent::register(id, challenger, info.clone(), None, code, t).unwrap();
+        // a 在高分时消失，b 分数较低但仍然在线
+        let a = random_principal();
+        agent::register(
+            a,
+            challenger,
+            create_agent_info("vanished".to_string(), None),
+            None,
+            random_code(),
+            1_000,
+        )
+        .unwrap();
+        let code = agent::get_agent(a).unwrap().challenge_code;
+        agent::challenge(
+            a,
+            challenger,
+            create_agent_info("vanished".to_string(), None),
+            None,
+            code,
+            random_code(),
+            9_000,
+        )
+        .unwrap();
 
-        let before = agent::get_agent(id).unwrap();
-        // 使用相同或更小的 now_ms，按照逻辑应直接 Ok 且不改变状态
-        let res = agent::challenge(
-            id,
+        let b = random_principal();
+        agent::register(
+            b,
             challenger,
-            info.clone(),
+            create_agent_info("alive".to_string(), None),
             None,
-            before.challenge_code.clone(),
             random_code(),
-            t, // not advanced
-        );
-        assert!(res.is_ok());
+            20_000,
+        )
+        .unwrap();
+        let code = agent::get_agent(b).unwrap().challenge_code;
+        agent::challenge(
+            b,
+            challenger,
+            create_agent_info("alive".to_string(), None),
+            None,
+            code,
+            random_code(),
+            25_000,
+        )
+        .unwrap();
 
-        let after = agent::get_agent(id).unwrap();
-        assert_eq!(before.challenge_code, after.challenge_code);
-        assert_eq!(before.challenged_at, after.challenged_at);
-        assert_eq!(before.health_power, after.health_power);
+        let stored = agent::get_agent(a).unwrap();
+        assert_eq!(stored.health_power, 8_000);
+        assert_eq!(stored.challenged_expiration, 19_000);
+        // 存储中读取的代理不含有效健康值，读取时才计算
+        assert_eq!(stored.effective_health_power, None);
+        assert_eq!(
+            stored
+                .clone()
+                .with_effective_health_power(19_000)
+                .effective_health_power,
+            Some(8_000)
+        );
+        // 未过期时有效健康值等于原始值
+        assert_eq!(stored.effective_health_power_at(19_000), 8_000);
+        // 过期后按超出时间衰减
+        assert_eq!(stored.effective_health_power_at(26_000), 1_000);
+        assert_eq!(stored.effective_health_power_at(100_000), 0);
+
+        // a 的原始分数更高，但衰减后排在 b 之后
+        let top = agent::list_by_health_power(10, 26_000).unwrap();
+        assert_eq!(top.iter().map(|ag| ag.id).collect::<Vec<_>>(), vec![b, a]);
+        assert_eq!(top[0].health_power, 5_000);
+        assert_eq!(top[0].effective_health_power, Some(5_000));
+        assert_eq!(top[1].health_power, 8_000);
+        assert_eq!(top[1].effective_health_power, Some(1_000));
+
+        let top = agent::list_by_health_power(1, 26_000).unwrap();
+        assert_eq!(top.len(), 1);
+        assert_eq!(top[0].id, b);
+
+        // 衰减到 0 的代理不再上榜
+        let top = agent::list_by_health_power(10, 28_000).unwrap();
+        assert_eq!(top.iter().map(|ag| ag.id).collect::<Vec<_>>(), vec![b]);
     }
 
     #[test]
-    fn test_by_health_power_trim_and_threshold() {
+    fn test_moderation() {
         setup();
 
-        // 设置更小阈值，便于触发修剪
-        STATE.with_borrow_mut(|s| s.challenge_expires_in_ms = 60_000);
-
         let challenger = random_principal();
-        let base = 1_000u64;
-
-        // 注册超过 MAX_HEALTH_POWER_LIST 的代理，并逐步提升 health_power
-        let total = MAX_HEALTH_POWER_LIST + 50;
+        let canister = Principal::from_text("nscli-qiaaa-aaaaj-qa4pa-cai").ok();
         let mut ids = Vec::new();
-        for i in 0..total {
+        for i in 0..2 {
             let id = random_principal();
+            let mut info = create_agent_info(format!("agent_{i}"), canister);
+            info.protocols.push(AgentProtocol {
+                name: "MCP".to_string(),
+                endpoint: "https://example.com/mcp".to_string(),
+                version: None,
+            });
+            agent::register(id, challenger, info, None, random_code(), 1000).unwrap();
             ids.push(id);
-            agent::register(
-                id,
-                challenger,
-                create_agent_info(format!("h{i}"), None),
-                None,
-                random_code(),
-                base + i as u64,
-            )
-            .unwrap();
-        }
-
-        // 对全部做一次挑战，保证 health_power > 0
-        for (i, id) in ids.iter().enumerate() {
-            let a = agent::get_agent(*id).unwrap();
-            let _ = agent::challenge(
-                *id,
-                challenger,
-                create_agent_info(format!("h{i}"), None),
-                None,
-                a.challenge_code,
-                random_code(),
-                base + 10_000 + i as u64,
-            );
         }
+        let (a, b) = (ids[0], ids[1]);
+
+        // 暂停后从查询和列表中隐藏
+        let event =
+            agent::moderate(a, AgentStatus::Suspended, Some("phishing".into()), 2000).unwrap();
+        assert_eq!(event.kind, AgentEventKind::Suspended);
+        assert!(agent::lookup(a).is_err());
+        assert!(agent::get_agent_by_handle("agent_0".to_string()).is_err());
+        let (_, agents) = agent::list(None, 10).unwrap();
+        assert_eq!(agents.iter().map(|a| a.id).collect::<Vec<_>>(), vec![b]);
+        assert_eq!(agent::list_by_protocol("MCP", None, 10).unwrap().1.len(), 1);
+        assert_eq!(agent::search("agent", 10, None).unwrap().1.len(), 1);
+        let changes = agent::get_changes(None, 100).unwrap();
+        assert!(changes.last().unwrap().agent.is_none());
+
+        let stored = agent::get_agent(a).unwrap();
+        assert_eq!(stored.status, AgentStatus::Suspended);
+        assert_eq!(stored.status_reason.as_deref(), Some("phishing"));
+
+        // 挑战被拒绝
+        let info = create_agent_info("agent_0".to_string(), canister);
+        let err = agent::challenge(
+            a,
+            challenger,
+            info.clone(),
+            None,
+            stored.challenge_code.clone(),
+            random_code(),
+            3000,
+        )
+        .unwrap_err();
+        assert!(matches!(err, RegistryError::Forbidden { .. }));
 
-        // 触发修剪分支（内部每次最多移除100个最低值）
-        let _top = agent::list_by_health_power(10, base + 20_000).unwrap();
-        // 无直接对内阈值可见的断言，但至少不应 panic 或越界
-        // 可通过再次挑战提高某个 agent 的分数，验证仍能进入榜单
-        let pick = ids[0];
-        let a = agent::get_agent(pick).unwrap();
+        // 封禁后不能自行注销，也不会被清除
+        agent::moderate(a, AgentStatus::Banned, Some("phishing".into()), 4000).unwrap();
+        assert!(agent::moderate(a, AgentStatus::Suspended, Some("x".into()), 4000).is_err());
+        assert!(matches!(
+            agent::unregister(a, Some(&stored.challenge_code)),
+            Err(RegistryError::Forbidden { .. })
+        ));
+        let far = stored.challenged_expiration + EVICTED_GRACE_MS + 1;
+        agent::sweep(10, far);
+        assert_eq!(agent::get_agent(a).unwrap().status, AgentStatus::Banned);
+
+        // 解封后恢复
+        let event = agent::moderate(a, AgentStatus::Active, None, 5000).unwrap();
+        assert_eq!(event.kind, AgentEventKind::Unbanned);
+        assert!(agent::lookup(a).is_ok());
+        assert!(agent::get_agent(a).unwrap().status_reason.is_none());
+        assert!(agent::moderate(a, AgentStatus::Active, None, 5000).is_err());
         agent::challenge(
-            pick,
+            a,
             challenger,
-            create_agent_info("h_boost".to_string(), None),
+            info,
             None,
-            a.challenge_code,
+            stored.challenge_code,
             random_code(),
-            base + 50_000,
+            6000,
         )
         .unwrap();
-
-        let top = agent::list_by_health_power(100, base + 60_000).unwrap();
-        assert!(top.iter().any(|ag| ag.id == pick));
     }
 
     #[test]
-    fn test_list_functions() {
+    fn test_moderation_restores_status() {
         setup();
 
-        // 注册多个代理
-        let mut ids = Vec::new();
         let challenger = random_principal();
         let now_ms = 1000;
-
-        for i in 0..5 {
+        let expires_in = STATE.with_borrow(|s| s.challenge_expires_in_ms);
+        let mut ids = Vec::new();
+        for handle in ["a", "b"] {
             let id = random_principal();
-            ids.push(id);
             let code = random_code();
-            let info = create_agent_info(format!("handle_{}", i), None);
-            let _ = agent::register(id, challenger, info, None, code, now_ms + i);
+            agent::register(
+                id,
+                challenger,
+                create_agent_info(handle.to_string(), None),
+                None,
+                code.clone(),
+                now_ms,
+            )
+            .unwrap();
+            agent::challenge(
+                id,
+                challenger,
+                create_agent_info(handle.to_string(), None),
+                None,
+                code,
+                random_code(),
+                now_ms + 1000,
+            )
+            .unwrap();
+            ids.push(id);
         }
+        let (a, b) = (ids[0], ids[1]);
+        let challenged_at = now_ms + 1000;
+        assert_eq!(
+            agent::list_by_health_power(10, challenged_at)
+                .unwrap()
+                .len(),
+            2
+        );
 
-        // 测试列表功能
-        let (last_id, agents) = agent::list(None, 3).unwrap();
-        assert_eq!(agents.len(), 3);
-        assert_eq!(last_id, 2);
+        // b 在活跃时被暂停，a 过期后被暂停
+        agent::moderate(b, AgentStatus::Suspended, None, challenged_at).unwrap();
+        let t1 = challenged_at + expires_in + EXPIRED_GRACE_MS + 1;
+        agent::sweep(100, t1);
+        assert_eq!(agent::get_agent(a).unwrap().status, AgentStatus::Expired);
+        assert_eq!(agent::get_agent(b).unwrap().status, AgentStatus::Suspended);
+        agent::moderate(a, AgentStatus::Suspended, None, t1).unwrap();
+        agent::moderate(a, AgentStatus::Banned, None, t1).unwrap();
+        assert!(agent::last_challenged(10).unwrap().is_empty());
+        assert!(agent::list_by_health_power(10, t1).unwrap().is_empty());
+
+        // 被管理的代理不会被驱逐
+        let t2 = challenged_at + expires_in + EVICTED_GRACE_MS + 1;
+        assert!(agent::sweep(100, t2).is_empty());
+        assert_eq!(state::get_state().agents_total, 2);
+
+        // 解封后恢复之前的状态和索引
+        agent::moderate(a, AgentStatus::Active, None, t2).unwrap();
+        agent::moderate(b, AgentStatus::Active, None, t2).unwrap();
+        assert_eq!(agent::get_agent(a).unwrap().status, AgentStatus::Expired);
+        assert_eq!(agent::get_agent(b).unwrap().status, AgentStatus::Active);
+        assert_eq!(
+            agent::last_challenged(10).unwrap(),
+            BTreeMap::from([(a, challenged_at), (b, challenged_at)])
+        );
+        assert_eq!(
+            agent::list_by_health_power(10, challenged_at)
+                .unwrap()
+                .iter()
+                .map(|ag| ag.id)
+                .collect::<Vec<_>>(),
+            vec![b]
+        );
+    }
 
-        let (last_id, agents) = agent::list(Some(2), 3).unwrap();
-        assert_eq!(agents.len(), 2);
-        assert_eq!(last_id, 0);
+    #[test]
+    fn test_nitro_pcrs_allowlist() {
+        setup();
 
-        // 测试健康值列表
-        // 先进行一些挑战来增加健康值
-        for (i, id) in ids.iter().enumerate() {
-            if i % 2 == 0 {
-                let code = agent::get_agent(*id).unwrap().challenge_code;
-                let new_code = random_code();
-                let info = create_agent_info(format!("handle_{}_updated", i), None);
-                let new_now_ms = now_ms + 1000 + i as u64;
-                let _ = agent::challenge(*id, challenger, info, None, code, new_code, new_now_ms);
-            }
-        }
+        let pcrs = |v: u8| NitroPcrs {
+            pcr0: vec![v; 48].into(),
+            pcr1: vec![v; 48].into(),
+            pcr2: vec![v; 48].into(),
+        };
+        let doc = |v: u8| -> BTreeMap<usize, ByteBufB64> {
+            (0..3).map(|i| (i, vec![v; 48].into())).collect()
+        };
+        let p1 = random_principal();
+        let p2 = random_principal();
+
+        // 未配置时允许任意镜像
+        assert!(state::is_nitro_pcrs_allowed(None, &doc(1)));
+
+        // 提供者白名单仅对该提供者生效
+        state::add_nitro_pcrs(Some(p1), vec![pcrs(1)]);
+        assert!(state::is_nitro_pcrs_allowed(Some(p1), &doc(1)));
+        assert!(!state::is_nitro_pcrs_allowed(Some(p1), &doc(2)));
+        assert!(state::is_nitro_pcrs_allowed(Some(p2), &doc(2)));
+        assert!(state::is_nitro_pcrs_allowed(None, &doc(2)));
+
+        // 全局白名单对所有智能体生效
+        state::add_nitro_pcrs(None, vec![pcrs(2)]);
+        assert!(state::is_nitro_pcrs_allowed(Some(p1), &doc(2)));
+        assert!(!state::is_nitro_pcrs_allowed(Some(p2), &doc(1)));
+        assert!(!state::is_nitro_pcrs_allowed(None, &doc(3)));
+        assert_eq!(state::get_nitro_pcrs(Some(p1)), vec![pcrs(1)]);
+        assert_eq!(state::get_nitro_pcrs(None), vec![pcrs(2)]);
+
+        state::remove_nitro_pcrs(None, &[pcrs(2)]);
+        state::remove_nitro_pcrs(Some(p1), &[pcrs(1)]);
+        assert!(state::get_nitro_pcrs(Some(p1)).is_empty());
+        assert!(state::is_nitro_pcrs_allowed(Some(p1), &doc(3)));
+    }
 
-        let agents = agent::list_by_health_power(10, now_ms + 10000).unwrap();
-        assert!(!agents.is_empty());
+    #[test]
+    fn test_tee_measurements_allowlist() {
+        setup();
 
-        // 测试最近挑战列表
-        let last_challenged = agent::last_challenged(10).unwrap();
-        assert!(!last_challenged.is_empty());
+        let entry = |v: u8| -> TEEMeasurements {
+            BTreeMap::from([("MRTD".to_string(), vec![v; 48].into())])
+        };
+        let quote = |v: u8| -> TEEMeasurements {
+            BTreeMap::from([
+                ("MRTD".to_string(), vec![v; 48].into()),
+                ("RTMR0".to_string(), vec![0u8; 48].into()),
+            ])
+        };
+        let p1 = random_principal();
+        let p2 = random_principal();
+
+        // 未配置时允许任意度量值
+        assert!(state::is_tee_measurements_allowed(
+            &TEEKind::TDX,
+            None,
+            &quote(1)
+        ));
+
+        // 提供者白名单仅对该提供者和该类型生效
+        state::add_tee_measurements(TEEKind::TDX, Some(p1), vec![entry(1)]);
+        assert!(state::is_tee_measurements_allowed(
+            &TEEKind::TDX,
+            Some(p1),
+            &quote(1)
+        ));
+        assert!(!state::is_tee_measurements_allowed(
+            &TEEKind::TDX,
+            Some(p1),
+            &quote(2)
+        ));
+        assert!(state::is_tee_measurements_allowed(
+            &TEEKind::TDX,
+            Some(p2),
+            &quote(2)
+        ));
+        assert!(state::is_tee_measurements_allowed(
+            &TEEKind::SEV_SNP,
+            Some(p1),
+            &quote(2)
+        ));
+
+        // 全局白名单对所有智能体生效
+        state::add_tee_measurements(TEEKind::TDX, None, vec![entry(2)]);
+        assert!(state::is_tee_measurements_allowed(
+            &TEEKind::TDX,
+            Some(p1),
+            &quote(2)
+        ));
+        assert!(!state::is_tee_measurements_allowed(
+            &TEEKind::TDX,
+            Some(p2),
+            &quote(1)
+        ));
+        assert_eq!(
+            state::get_tee_measurements(&TEEKind::TDX, Some(p1)),
+            vec![entry(1)]
+        );
+        assert_eq!(
+            state::get_tee_measurements(&TEEKind::TDX, None),
+            vec![entry(2)]
+        );
+        assert!(state::get_tee_measurements(&TEEKind::SEV_SNP, None).is_empty());
+
+        state::remove_tee_measurements(TEEKind::TDX, None, &[entry(2)]);
+        state::remove_tee_measurements(TEEKind::TDX, Some(p1), &[entry(1)]);
+        assert!(state::get_tee_measurements(&TEEKind::TDX, Some(p1)).is_empty());
+        assert!(STATE.with_borrow(|s| s.provider_tee_measurements.is_empty()));
+        assert!(state::is_tee_measurements_allowed(
+            &TEEKind::TDX,
+            Some(p1),
+            &quote(3)
+        ));
+
+        // 信任根
+        state::add_tee_roots(TEEKind::TDX, vec![vec![1u8; 8].into()]);
+        assert_eq!(state::get_tee_roots(&TEEKind::TDX).len(), 1);
+        assert!(state::get_tee_roots(&TEEKind::SEV_SNP).is_empty());
+        state::remove_tee_roots(TEEKind::TDX, &[vec![1u8; 8].into()]);
+        assert!(state::get_tee_roots(&TEEKind::TDX).is_empty());
     }
 
     #[test]
-    fn test_get_nonexistent_agent() {
+    fn test_tee_claims() {
         setup();
 
         let id = random_principal();
-        let result = agent::get_agent(id);
-        assert!(matches!(result, Err(RegistryError::NotFound { .. })));
+        let challenger = random_principal();
+        let info = create_agent_info("test_handle".to_string(), None);
+        let tee = TEEInfo {
+            id: random_principal(),
+            kind: TEEKind::NITRO,
+            url: "https://example.com/.well-known/tee".to_string(),
+            attestation: Some(vec![1u8; 8].into()),
+        };
+        let claims = TEEClaims {
+            kind: TEEKind::NITRO,
+            module_id: "i-0123456789abcdef0-enc0123456789abcdef".to_string(),
+            measurements: (0..3)
+                .map(|i| (format!("PCR{i}"), vec![i as u8; 48].into()))
+                .collect(),
+            attested_at: 900,
+            verified_at: 1000,
+        };
+        agent::register(
+            id,
+            challenger,
+            info.clone(),
+            Some((tee, claims.clone())),
+            random_code(),
+            1000,
+        )
+        .unwrap();
 
-        let result = agent::get_agent_by_handle("nonexistent".to_string());
-        assert!(matches!(result, Err(RegistryError::NotFound { .. })));
+        let agent = agent::get_agent(id).unwrap();
+        assert_eq!(agent.tee_claims, Some(claims.clone()));
+        // 原始证明不会被存储
+        assert!(agent.tee.as_ref().unwrap().attestation.is_none());
+
+        // 同步到其他注册中心时保留声明
+        let local: AgentLocal = agent.clone().into();
+        let agent: Agent = local.into();
+        assert_eq!(agent.tee_claims, Some(claims));
+
+        // 不带 TEE 的挑战会清除声明
+        agent::challenge(
+            id,
+            challenger,
+            info,
+            None,
+            agent.challenge_code,
+            random_code(),
+            2000,
+        )
+        .unwrap();
+        assert!(agent::get_agent(id).unwrap().tee_claims.is_none());
     }
 }
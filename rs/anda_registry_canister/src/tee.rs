@@ -0,0 +1,789 @@
+use anda_cloud_cdk::{
+    SevSnpAttestation, TEEClaims, TEEInfo, TEEKind, TEEMeasurements, registry::RegistryError,
+    tee_report_data,
+};
+use candid::Principal;
+use ic_auth_types::ByteBufB64;
+use ic_tee_nitro_attestation::parse_and_verify;
+use ring::{digest, signature};
+use x509_parser::{certificate::X509Certificate, pem::Pem, prelude::FromDer, time::ASN1Time};
+
+use crate::store;
+
+/// Verifies the attestation of the TEE with the verifier of its kind, and returns the
+/// normalized claims decoded from the attestation.
+///
+/// The attestation must bind the agent's public key and the challenge code as the nonce.
+/// `provider` is the provider id declared by the agent itself, whose allowlist of images applies.
+/// Returns `None` if the enclave image is not allowed, the agent is then not TEE-verified.
+pub fn verify(
+    tee: &TEEInfo,
+    pubkey: &[u8],
+    nonce: &[u8],
+    provider: Option<Principal>,
+    now_ms: u64,
+) -> Result<Option<TEEClaims>, RegistryError> {
+    let attestation = tee
+        .attestation
+        .as_ref()
+        .ok_or_else(|| RegistryError::BadRequest {
+            error: "attestation is not provided".to_string(),
+        })?;
+
+    match tee.kind {
+        TEEKind::NITRO => verify_nitro(attestation, pubkey, nonce, provider, now_ms),
+        TEEKind::SEV_SNP => verify_sev_snp(attestation, pubkey, nonce, provider, now_ms),
+        TEEKind::TDX => verify_tdx(attestation, pubkey, nonce, provider, now_ms),
+    }
+}
+
+/// Validates a root certificate of the kind before it is trusted by the registry.
+pub fn validate_root(kind: &TEEKind, der: &[u8]) -> Result<(), String> {
+    if *kind == TEEKind::NITRO {
+        return Err("the root of NITRO is built in".to_string());
+    }
+    let cert = x509_cert(der)?;
+    if cert.issuer() != cert.subject() || cert.verify_signature(None).is_err() {
+        return Err("root certificate should be self-signed".to_string());
+    }
+    Ok(())
+}
+
+fn verify_nitro(
+    attestation: &[u8],
+    pubkey: &[u8],
+    nonce: &[u8],
+    provider: Option<Principal>,
+    now_ms: u64,
+) -> Result<Option<TEEClaims>, RegistryError> {
+    let attestation = parse_and_verify(attestation).map_err(|error| RegistryError::BadRequest {
+        error: format!("attestation is not valid: {}", error),
+    })?;
+
+    if attestation.public_key.as_ref().map(|v| v.as_slice()) != Some(pubkey) {
+        return Err(RegistryError::BadRequest {
+            error: "attestation public key is not equal to agent public key".to_string(),
+        });
+    }
+    if attestation.nonce.as_ref().map(|v| v.as_slice()) != Some(nonce) {
+        return Err(RegistryError::BadRequest {
+            error: "attestation nonce is not equal to chanllenge code".to_string(),
+        });
+    }
+    if !store::state::is_nitro_pcrs_allowed(provider, &attestation.pcrs) {
+        return Ok(None);
+    }
+
+    Ok(Some(TEEClaims {
+        kind: TEEKind::NITRO,
+        module_id: attestation.module_id,
+        measurements: attestation
+            .pcrs
+            .into_iter()
+            .filter(|(i, _)| *i <= 2)
+            .map(|(i, v)| (format!("PCR{i}"), v))
+            .collect(),
+        attested_at: attestation.timestamp,
+        verified_at: now_ms,
+    }))
+}
+
+// Layout of the SEV-SNP attestation report, see the SEV Secure Nested Paging Firmware ABI
+// Specification, the signature covers the bytes before it.
+const SNP_REPORT_LEN: usize = 0x4A0;
+const SNP_POLICY: usize = 0x08;
+const SNP_POLICY_DEBUG: u64 = 1 << 19;
+const SNP_SIGNATURE_ALGO: usize = 0x34;
+const SNP_REPORT_DATA: usize = 0x50;
+const SNP_MEASUREMENT: usize = 0x90;
+const SNP_CHIP_ID: usize = 0x1A0;
+const SNP_SIGNATURE: usize = 0x2A0;
+
+fn verify_sev_snp(
+    attestation: &[u8],
+    pubkey: &[u8],
+    nonce: &[u8],
+    provider: Option<Principal>,
+    now_ms: u64,
+) -> Result<Option<TEEClaims>, RegistryError> {
+    let attestation: SevSnpAttestation = cbor2::from_slice(attestation).map_err(bad_attestation)?;
+    let report = attestation.report.as_slice();
+    if report.len() != SNP_REPORT_LEN {
+        return Err(bad_attestation(format!(
+            "report should be {SNP_REPORT_LEN} bytes, got {}",
+            report.len()
+        )));
+    }
+    if read_u32(report, 0) < 2 || read_u32(report, SNP_SIGNATURE_ALGO) != 1 {
+        return Err(bad_attestation(
+            "report version or signature algorithm is not supported",
+        ));
+    }
+    if read_u64(report, SNP_POLICY) & SNP_POLICY_DEBUG != 0 {
+        return Err(bad_attestation("guest policy allows debugging"));
+    }
+
+    let chain = attestation
+        .certs
+        .iter()
+        .map(|v| x509_cert(v))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(bad_attestation)?;
+    verify_cert_chain(&TEEKind::SEV_SNP, &chain, now_ms)?;
+
+    // r and s are little-endian and zero-extended to 72 bytes
+    let mut sig = [0u8; 96];
+    for (i, v) in sig.iter_mut().enumerate() {
+        let offset = if i < 48 { 47 - i } else { 72 + 95 - i };
+        *v = report[SNP_SIGNATURE + offset];
+    }
+    signature::UnparsedPublicKey::new(
+        &signature::ECDSA_P384_SHA384_FIXED,
+        chain[0].public_key().subject_public_key.as_ref(),
+    )
+    .verify(&report[..SNP_SIGNATURE], &sig)
+    .map_err(|_| bad_attestation("report signature is not valid"))?;
+
+    if report[SNP_REPORT_DATA..SNP_REPORT_DATA + 64] != tee_report_data(pubkey, nonce) {
+        return Err(RegistryError::BadRequest {
+            error: "attestation report data does not bind agent public key and chanllenge code"
+                .to_string(),
+        });
+    }
+
+    let measurements = TEEMeasurements::from([(
+        "MEASUREMENT".to_string(),
+        report[SNP_MEASUREMENT..SNP_MEASUREMENT + 48]
+            .to_vec()
+            .into(),
+    )]);
+    if !store::state::is_tee_measurements_allowed(&TEEKind::SEV_SNP, provider, &measurements) {
+        return Ok(None);
+    }
+
+    Ok(Some(TEEClaims {
+        kind: TEEKind::SEV_SNP,
+        module_id: hex_string(&report[SNP_CHIP_ID..SNP_CHIP_ID + 64]),
+        measurements,
+        attested_at: now_ms,
+        verified_at: now_ms,
+    }))
+}
+
+// Layout of the TDX DCAP quote version 4, see the Intel TDX DCAP Quoting Library API,
+// the quote signature covers the header and the TD report body.
+const TDX_HEADER_LEN: usize = 48;
+const TDX_BODY_LEN: usize = 584;
+const TDX_TEE_TYPE: u32 = 0x81;
+const TDX_ATTRIBUTES: usize = TDX_HEADER_LEN + 120;
+const TDX_ATTRIBUTES_DEBUG: u64 = 1;
+const TDX_MRSEAM: usize = TDX_HEADER_LEN + 16;
+const TDX_MRTD: usize = TDX_HEADER_LEN + 136;
+const TDX_RTMR0: usize = TDX_HEADER_LEN + 328;
+const TDX_REPORT_DATA: usize = TDX_HEADER_LEN + 520;
+const QE_REPORT_LEN: usize = 384;
+const QE_REPORT_DATA: usize = 320;
+const CERT_TYPE_QE_REPORT: u16 = 6;
+const CERT_TYPE_PCK_CHAIN: u16 = 5;
+
+fn verify_tdx(
+    quote: &[u8],
+    pubkey: &[u8],
+    nonce: &[u8],
+    provider: Option<Principal>,
+    now_ms: u64,
+) -> Result<Option<TEEClaims>, RegistryError> {
+    let signed = slice(quote, 0, TDX_HEADER_LEN + TDX_BODY_LEN)?;
+    if read_u16(quote, 0) != 4 || read_u16(quote, 2) != 2 || read_u32(quote, 4) != TDX_TEE_TYPE {
+        return Err(bad_attestation(
+            "quote version, attestation key type or TEE type is not supported",
+        ));
+    }
+    if read_u64(quote, TDX_ATTRIBUTES) & TDX_ATTRIBUTES_DEBUG != 0 {
+        return Err(bad_attestation("trust domain is debuggable"));
+    }
+
+    let sig_len = read_u32(slice(quote, signed.len(), 4)?, 0) as usize;
+    let sig_data = slice(quote, signed.len() + 4, sig_len)?;
+    let quote_sig = slice(sig_data, 0, 64)?;
+    let attest_key = slice(sig_data, 64, 64)?;
+    let qe_cert_data = certification_data(sig_data, 128, CERT_TYPE_QE_REPORT)?;
+    let qe_report = slice(qe_cert_data, 0, QE_REPORT_LEN)?;
+    let qe_sig = slice(qe_cert_data, QE_REPORT_LEN, 64)?;
+    let auth_len = read_u16(slice(qe_cert_data, QE_REPORT_LEN + 64, 2)?, 0) as usize;
+    let auth_data = slice(qe_cert_data, QE_REPORT_LEN + 66, auth_len)?;
+    let pck_chain = certification_data(
+        qe_cert_data,
+        QE_REPORT_LEN + 66 + auth_len,
+        CERT_TYPE_PCK_CHAIN,
+    )?;
+
+    let pems = Pem::iter_from_buffer(pck_chain)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(bad_attestation)?;
+    let chain = pems
+        .iter()
+        .map(|v| x509_cert(&v.contents))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(bad_attestation)?;
+    verify_cert_chain(&TEEKind::TDX, &chain, now_ms)?;
+
+    // the PCK certificate signs the report of the quoting enclave,
+    // which binds the attestation key that signs the quote
+    signature::UnparsedPublicKey::new(
+        &signature::ECDSA_P256_SHA256_FIXED,
+        chain[0].public_key().subject_public_key.as_ref(),
+    )
+    .verify(qe_report, qe_sig)
+    .map_err(|_| bad_attestation("QE report signature is not valid"))?;
+    let mut ctx = digest::Context::new(&digest::SHA256);
+    ctx.update(attest_key);
+    ctx.update(auth_data);
+    if qe_report[QE_REPORT_DATA..QE_REPORT_DATA + 32] != *ctx.finish().as_ref()
+        || qe_report[QE_REPORT_DATA + 32..].iter().any(|v| *v != 0)
+    {
+        return Err(bad_attestation(
+            "QE report does not bind the attestation key",
+        ));
+    }
+    let mut key = Vec::with_capacity(65);
+    key.push(0x04);
+    key.extend_from_slice(attest_key);
+    signature::UnparsedPublicKey::new(&signature::ECDSA_P256_SHA256_FIXED, key)
+        .verify(signed, quote_sig)
+        .map_err(|_| bad_attestation("quote signature is not valid"))?;
+
+    if quote[TDX_REPORT_DATA..TDX_REPORT_DATA + 64] != tee_report_data(pubkey, nonce) {
+        return Err(RegistryError::BadRequest {
+            error: "attestation report data does not bind agent public key and chanllenge code"
+                .to_string(),
+        });
+    }
+
+    let mut measurements = TEEMeasurements::from([(
+        "MRTD".to_string(),
+        quote[TDX_MRTD..TDX_MRTD + 48].to_vec().into(),
+    )]);
+    for i in 0..4 {
+        let offset = TDX_RTMR0 + i * 48;
+        measurements.insert(
+            format!("RTMR{i}"),
+            ByteBufB64::from(quote[offset..offset + 48].to_vec()),
+        );
+    }
+    if !store::state::is_tee_measurements_allowed(&TEEKind::TDX, provider, &measurements) {
+        return Ok(None);
+    }
+
+    Ok(Some(TEEClaims {
+        kind: TEEKind::TDX,
+        module_id: hex_string(&quote[TDX_MRSEAM..TDX_MRSEAM + 48]),
+        measurements,
+        attested_at: now_ms,
+        verified_at: now_ms,
+    }))
+}
+
+/// Verifies that each certificate of the chain, from the leaf first, is valid now and
+/// signed by the next one, and that the last one is or is signed by a trusted root of the kind.
+fn verify_cert_chain(
+    kind: &TEEKind,
+    chain: &[X509Certificate<'_>],
+    now_ms: u64,
+) -> Result<(), RegistryError> {
+    let roots = store::state::get_tee_roots(kind);
+    if roots.is_empty() {
+        return Err(RegistryError::NotSupported {
+            error: format!("no trusted roots of {kind} are configured"),
+        });
+    }
+    let last = chain
+        .last()
+        .ok_or_else(|| bad_attestation("certificate chain is empty"))?;
+
+    let now = ASN1Time::from_timestamp((now_ms / 1000) as i64).map_err(bad_attestation)?;
+    for (i, cert) in chain.iter().enumerate() {
+        if !cert.validity().is_valid_at(now) {
+            return Err(bad_attestation(format!(
+                "certificate {} is not valid now",
+                cert.subject()
+            )));
+        }
+        if let Some(issuer) = chain.get(i + 1)
+            && (cert.issuer() != issuer.subject()
+                || cert.verify_signature(Some(issuer.public_key())).is_err())
+        {
+            return Err(bad_attestation(format!(
+                "certificate {} is not signed by {}",
+                cert.subject(),
+                issuer.subject()
+            )));
+        }
+    }
+
+    let trusted = roots.iter().any(|der| {
+        x509_cert(der).is_ok_and(|root| {
+            root.public_key().raw == last.public_key().raw
+                || (last.issuer() == root.subject()
+                    && last.verify_signature(Some(root.public_key())).is_ok())
+        })
+    });
+    if !trusted {
+        return Err(bad_attestation(format!(
+            "certificate {} is not issued by a trusted root of {kind}",
+            last.subject()
+        )));
+    }
+    Ok(())
+}
+
+fn x509_cert(der: &[u8]) -> Result<X509Certificate<'_>, String> {
+    let (rest, cert) = X509Certificate::from_der(der).map_err(|err| err.to_string())?;
+    if !rest.is_empty() {
+        return Err("trailing data after certificate".to_string());
+    }
+    Ok(cert)
+}
+
+/// Returns the data of the certification data structure at `offset`, which must be of `cert_type`.
+fn certification_data(data: &[u8], offset: usize, cert_type: u16) -> Result<&[u8], RegistryError> {
+    let header = slice(data, offset, 6)?;
+    if read_u16(header, 0) != cert_type {
+        return Err(bad_attestation(format!(
+            "certification data type should be {cert_type}, got {}",
+            read_u16(header, 0)
+        )));
+    }
+    slice(data, offset + 6, read_u32(header, 2) as usize)
+}
+
+fn slice(data: &[u8], offset: usize, len: usize) -> Result<&[u8], RegistryError> {
+    offset
+        .checked_add(len)
+        .and_then(|end| data.get(offset..end))
+        .ok_or_else(|| bad_attestation("quote is truncated"))
+}
+
+// Callers ensure the bounds
+fn read_u16(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(data[offset..offset + 2].try_into().unwrap())
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_u64(data: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap())
+}
+
+fn hex_string(data: &[u8]) -> String {
+    data.iter().map(|v| format!("{v:02x}")).collect()
+}
+
+fn bad_attestation(error: impl ToString) -> RegistryError {
+    RegistryError::BadRequest {
+        error: format!("attestation is not valid: {}", error.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use base64::{Engine, engine::general_purpose::STANDARD as BASE64};
+    use ring::{
+        rand::SystemRandom,
+        signature::{EcdsaKeyPair, KeyPair},
+    };
+
+    const NOW_MS: u64 = 1_760_000_000_000;
+
+    struct Key {
+        pkcs8: Vec<u8>,
+        p384: bool,
+    }
+
+    impl Key {
+        fn new(p384: bool) -> Self {
+            let alg = if p384 {
+                &signature::ECDSA_P384_SHA384_ASN1_SIGNING
+            } else {
+                &signature::ECDSA_P256_SHA256_ASN1_SIGNING
+            };
+            let pkcs8 = EcdsaKeyPair::generate_pkcs8(alg, &SystemRandom::new()).unwrap();
+            Key {
+                pkcs8: pkcs8.as_ref().to_vec(),
+                p384,
+            }
+        }
+
+        fn sign(&self, fixed: bool, msg: &[u8]) -> Vec<u8> {
+            let alg = match (self.p384, fixed) {
+                (false, false) => &signature::ECDSA_P256_SHA256_ASN1_SIGNING,
+                (false, true) => &signature::ECDSA_P256_SHA256_FIXED_SIGNING,
+                (true, false) => &signature::ECDSA_P384_SHA384_ASN1_SIGNING,
+                (true, true) => &signature::ECDSA_P384_SHA384_FIXED_SIGNING,
+            };
+            let rng = SystemRandom::new();
+            let pair = EcdsaKeyPair::from_pkcs8(alg, &self.pkcs8, &rng).unwrap();
+            pair.sign(&rng, msg).unwrap().as_ref().to_vec()
+        }
+
+        fn public_key(&self) -> Vec<u8> {
+            let alg = if self.p384 {
+                &signature::ECDSA_P384_SHA384_ASN1_SIGNING
+            } else {
+                &signature::ECDSA_P256_SHA256_ASN1_SIGNING
+            };
+            let pair = EcdsaKeyPair::from_pkcs8(alg, &self.pkcs8, &SystemRandom::new()).unwrap();
+            pair.public_key().as_ref().to_vec()
+        }
+    }
+
+    fn der(tag: u8, content: &[u8]) -> Vec<u8> {
+        let len = content.len();
+        let mut out = vec![tag];
+        if len < 0x80 {
+            out.push(len as u8);
+        } else if len < 0x100 {
+            out.extend([0x81, len as u8]);
+        } else {
+            out.extend([0x82, (len >> 8) as u8, len as u8]);
+        }
+        out.extend_from_slice(content);
+        out
+    }
+
+    fn name(cn: &str) -> Vec<u8> {
+        let attr = der(
+            0x30,
+            &[
+                &[0x06, 0x03, 0x55, 0x04, 0x03],
+                &der(0x0c, cn.as_bytes())[..],
+            ]
+            .concat(),
+        );
+        der(0x30, &der(0x31, &attr))
+    }
+
+    // 构造由 issuer 签发的 ECDSA 证书
+    fn cert(subject: &str, key: &Key, issuer: &str, issuer_key: &Key) -> Vec<u8> {
+        let sig_alg: &[u8] = if issuer_key.p384 {
+            &[
+                0x30, 0x0a, 0x06, 0x08, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x04, 0x03, 0x03,
+            ]
+        } else {
+            &[
+                0x30, 0x0a, 0x06, 0x08, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x04, 0x03, 0x02,
+            ]
+        };
+        let curve: &[u8] = if key.p384 {
+            &[0x06, 0x05, 0x2b, 0x81, 0x04, 0x00, 0x22]
+        } else {
+            &[0x06, 0x08, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x03, 0x01, 0x07]
+        };
+        let spki = der(
+            0x30,
+            &[
+                der(
+                    0x30,
+                    &[
+                        &[0x06, 0x07, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x02, 0x01],
+                        curve,
+                    ]
+                    .concat(),
+                ),
+                der(0x03, &[&[0u8][..], &key.public_key()].concat()),
+            ]
+            .concat(),
+        );
+        let validity = der(
+            0x30,
+            &[der(0x17, b"000101000000Z"), der(0x17, b"491231235959Z")].concat(),
+        );
+        let tbs = der(
+            0x30,
+            &[
+                &[0xa0, 0x03, 0x02, 0x01, 0x02, 0x02, 0x01, 0x01][..],
+                sig_alg,
+                &name(issuer),
+                &validity,
+                &name(subject),
+                &spki,
+            ]
+            .concat(),
+        );
+        let sig = issuer_key.sign(false, &tbs);
+        der(
+            0x30,
+            &[&tbs[..], sig_alg, &der(0x03, &[&[0u8][..], &sig].concat())].concat(),
+        )
+    }
+
+    fn pem(der: &[u8]) -> String {
+        let b64 = BASE64.encode(der);
+        let lines: Vec<&str> = b64
+            .as_bytes()
+            .chunks(64)
+            .map(|v| std::str::from_utf8(v).unwrap())
+            .collect();
+        format!(
+            "-----BEGIN CERTIFICATE-----\n{}\n-----END CERTIFICATE-----\n",
+            lines.join("\n")
+        )
+    }
+
+    fn tee_info(kind: TEEKind, attestation: Vec<u8>) -> TEEInfo {
+        TEEInfo {
+            id: Principal::anonymous(),
+            kind,
+            url: "https://example.com/.well-known/tee".to_string(),
+            attestation: Some(attestation.into()),
+        }
+    }
+
+    fn snp_report(vcek: &Key, report_data: &[u8; 64], policy: u64) -> Vec<u8> {
+        let mut report = vec![0u8; SNP_REPORT_LEN];
+        report[0..4].copy_from_slice(&2u32.to_le_bytes());
+        report[SNP_POLICY..SNP_POLICY + 8].copy_from_slice(&policy.to_le_bytes());
+        report[SNP_SIGNATURE_ALGO..SNP_SIGNATURE_ALGO + 4].copy_from_slice(&1u32.to_le_bytes());
+        report[SNP_REPORT_DATA..SNP_REPORT_DATA + 64].copy_from_slice(report_data);
+        report[SNP_MEASUREMENT..SNP_MEASUREMENT + 48].fill(3);
+        report[SNP_CHIP_ID..SNP_CHIP_ID + 64].fill(9);
+        let sig = vcek.sign(true, &report[..SNP_SIGNATURE]);
+        for i in 0..48 {
+            report[SNP_SIGNATURE + i] = sig[47 - i];
+            report[SNP_SIGNATURE + 72 + i] = sig[95 - i];
+        }
+        report
+    }
+
+    #[test]
+    fn test_verify_sev_snp() {
+        let (ark, ask, vcek) = (Key::new(true), Key::new(true), Key::new(true));
+        let ark_cert = cert("ARK-Test", &ark, "ARK-Test", &ark);
+        let certs = vec![
+            cert("SEV-VCEK", &vcek, "SEV-Test", &ask).into(),
+            cert("SEV-Test", &ask, "ARK-Test", &ark).into(),
+        ];
+        let pubkey = [1u8; 32];
+        let nonce = [2u8; 16];
+        let attestation = |report: Vec<u8>| {
+            let v = SevSnpAttestation {
+                report: report.into(),
+                certs: certs.clone(),
+            };
+            tee_info(TEEKind::SEV_SNP, cbor2::to_vec(&v).unwrap())
+        };
+        let tee = attestation(snp_report(
+            &vcek,
+            &tee_report_data(&pubkey, &nonce),
+            0x30000,
+        ));
+
+        // 未配置信任根时无法验证
+        let res = verify(&tee, &pubkey, &nonce, None, NOW_MS);
+        assert!(matches!(res, Err(RegistryError::NotSupported { .. })));
+
+        assert!(validate_root(&TEEKind::SEV_SNP, &ark_cert).is_ok());
+        assert!(validate_root(&TEEKind::SEV_SNP, &certs[1]).is_err());
+        assert!(validate_root(&TEEKind::NITRO, &ark_cert).is_err());
+        store::state::add_tee_roots(TEEKind::SEV_SNP, vec![ark_cert.into()]);
+
+        let claims = verify(&tee, &pubkey, &nonce, None, NOW_MS)
+            .unwrap()
+            .unwrap();
+        assert_eq!(claims.kind, TEEKind::SEV_SNP);
+        assert_eq!(claims.module_id, "09".repeat(64));
+        assert_eq!(claims.measurements.len(), 1);
+        assert_eq!(claims.measurements["MEASUREMENT"].as_slice(), &[3u8; 48]);
+        assert_eq!(claims.verified_at, NOW_MS);
+
+        // 报告数据必须绑定公钥和挑战码
+        assert!(verify(&tee, &pubkey, &[3u8; 16], None, NOW_MS).is_err());
+        assert!(verify(&tee, &[2u8; 32], &nonce, None, NOW_MS).is_err());
+
+        // 篡改报告、可调试的客户机和非信任链均被拒绝
+        let mut report = snp_report(&vcek, &tee_report_data(&pubkey, &nonce), 0x30000);
+        report[SNP_MEASUREMENT] = 4;
+        assert!(verify(&attestation(report), &pubkey, &nonce, None, NOW_MS).is_err());
+        let report = snp_report(
+            &vcek,
+            &tee_report_data(&pubkey, &nonce),
+            0x30000 | SNP_POLICY_DEBUG,
+        );
+        assert!(verify(&attestation(report), &pubkey, &nonce, None, NOW_MS).is_err());
+        let other = Key::new(true);
+        let report = snp_report(&other, &tee_report_data(&pubkey, &nonce), 0x30000);
+        assert!(verify(&attestation(report), &pubkey, &nonce, None, NOW_MS).is_err());
+
+        // 度量值不在白名单中时不视为 TEE 验证通过
+        let entry = TEEMeasurements::from([("MEASUREMENT".to_string(), vec![5u8; 48].into())]);
+        store::state::add_tee_measurements(TEEKind::SEV_SNP, None, vec![entry]);
+        assert_eq!(verify(&tee, &pubkey, &nonce, None, NOW_MS).unwrap(), None);
+        store::state::add_tee_measurements(TEEKind::SEV_SNP, None, vec![claims.measurements]);
+        assert!(
+            verify(&tee, &pubkey, &nonce, None, NOW_MS)
+                .unwrap()
+                .is_some()
+        );
+    }
+
+    fn tdx_quote(attest: &Key, pck: &Key, pck_chain: &str, report_data: &[u8; 64]) -> Vec<u8> {
+        let mut quote = vec![0u8; TDX_HEADER_LEN + TDX_BODY_LEN];
+        quote[0..2].copy_from_slice(&4u16.to_le_bytes());
+        quote[2..4].copy_from_slice(&2u16.to_le_bytes());
+        quote[4..8].copy_from_slice(&TDX_TEE_TYPE.to_le_bytes());
+        quote[TDX_MRSEAM..TDX_MRSEAM + 48].fill(7);
+        quote[TDX_MRTD..TDX_MRTD + 48].fill(1);
+        for i in 0..4 {
+            quote[TDX_RTMR0 + i * 48..TDX_RTMR0 + (i + 1) * 48].fill(10 + i as u8);
+        }
+        quote[TDX_REPORT_DATA..TDX_REPORT_DATA + 64].copy_from_slice(report_data);
+
+        let attest_key = attest.public_key()[1..].to_vec();
+        let auth_data = [6u8; 32];
+        let mut qe_report = vec![0u8; QE_REPORT_LEN];
+        let mut ctx = digest::Context::new(&digest::SHA256);
+        ctx.update(&attest_key);
+        ctx.update(&auth_data);
+        qe_report[QE_REPORT_DATA..QE_REPORT_DATA + 32].copy_from_slice(ctx.finish().as_ref());
+
+        let mut pem_data = pck_chain.as_bytes().to_vec();
+        pem_data.push(0);
+        let mut qe_cert_data = qe_report.clone();
+        qe_cert_data.extend(pck.sign(true, &qe_report));
+        qe_cert_data.extend((auth_data.len() as u16).to_le_bytes());
+        qe_cert_data.extend(auth_data);
+        qe_cert_data.extend(CERT_TYPE_PCK_CHAIN.to_le_bytes());
+        qe_cert_data.extend((pem_data.len() as u32).to_le_bytes());
+        qe_cert_data.extend(pem_data);
+
+        let mut sig_data = attest.sign(true, &quote);
+        sig_data.extend(attest_key);
+        sig_data.extend(CERT_TYPE_QE_REPORT.to_le_bytes());
+        sig_data.extend((qe_cert_data.len() as u32).to_le_bytes());
+        sig_data.extend(qe_cert_data);
+
+        quote.extend((sig_data.len() as u32).to_le_bytes());
+        quote.extend(sig_data);
+        quote
+    }
+
+    #[test]
+    fn test_verify_tdx() {
+        let (root, ca, pck, attest) = (
+            Key::new(false),
+            Key::new(false),
+            Key::new(false),
+            Key::new(false),
+        );
+        let root_cert = cert("SGX Root CA", &root, "SGX Root CA", &root);
+        let pck_chain = [
+            pem(&cert(
+                "SGX PCK Certificate",
+                &pck,
+                "SGX PCK Platform CA",
+                &ca,
+            )),
+            pem(&cert("SGX PCK Platform CA", &ca, "SGX Root CA", &root)),
+            pem(&root_cert),
+        ]
+        .concat();
+        let pubkey = [1u8; 32];
+        let nonce = [2u8; 16];
+        let quote = tdx_quote(&attest, &pck, &pck_chain, &tee_report_data(&pubkey, &nonce));
+        let tee = tee_info(TEEKind::TDX, quote.clone());
+
+        let res = verify(&tee, &pubkey, &nonce, None, NOW_MS);
+        assert!(matches!(res, Err(RegistryError::NotSupported { .. })));
+
+        // 链顶的根证书必须是受信任的根
+        let other = Key::new(false);
+        store::state::add_tee_roots(
+            TEEKind::TDX,
+            vec![cert("SGX Root CA", &other, "SGX Root CA", &other).into()],
+        );
+        assert!(verify(&tee, &pubkey, &nonce, None, NOW_MS).is_err());
+        store::state::add_tee_roots(TEEKind::TDX, vec![root_cert.into()]);
+
+        let claims = verify(&tee, &pubkey, &nonce, None, NOW_MS)
+            .unwrap()
+            .unwrap();
+        assert_eq!(claims.kind, TEEKind::TDX);
+        assert_eq!(claims.module_id, "07".repeat(48));
+        assert_eq!(
+            claims.measurements.keys().collect::<Vec<_>>(),
+            vec!["MRTD", "RTMR0", "RTMR1", "RTMR2", "RTMR3"]
+        );
+        assert_eq!(claims.measurements["MRTD"].as_slice(), &[1u8; 48]);
+        assert_eq!(claims.measurements["RTMR3"].as_slice(), &[13u8; 48]);
+
+        assert!(verify(&tee, &pubkey, &[3u8; 16], None, NOW_MS).is_err());
+
+        // 篡改报告主体、截断和非 PCK 签发的 QE 报告均被拒绝
+        let mut tampered = quote.clone();
+        tampered[TDX_MRTD] = 0;
+        assert!(
+            verify(
+                &tee_info(TEEKind::TDX, tampered),
+                &pubkey,
+                &nonce,
+                None,
+                NOW_MS
+            )
+            .is_err()
+        );
+        let truncated = quote[..quote.len() - 100].to_vec();
+        assert!(
+            verify(
+                &tee_info(TEEKind::TDX, truncated),
+                &pubkey,
+                &nonce,
+                None,
+                NOW_MS
+            )
+            .is_err()
+        );
+        let forged = tdx_quote(
+            &attest,
+            &other,
+            &pck_chain,
+            &tee_report_data(&pubkey, &nonce),
+        );
+        assert!(
+            verify(
+                &tee_info(TEEKind::TDX, forged),
+                &pubkey,
+                &nonce,
+                None,
+                NOW_MS
+            )
+            .is_err()
+        );
+
+        // 提供者白名单
+        let provider = Principal::management_canister();
+        let entry = TEEMeasurements::from([("MRTD".to_string(), vec![1u8; 48].into())]);
+        let rtmr = TEEMeasurements::from([
+            ("MRTD".to_string(), vec![1u8; 48].into()),
+            ("RTMR1".to_string(), vec![0u8; 48].into()),
+        ]);
+        store::state::add_tee_measurements(TEEKind::TDX, Some(provider), vec![rtmr]);
+        assert_eq!(
+            verify(&tee, &pubkey, &nonce, Some(provider), NOW_MS).unwrap(),
+            None
+        );
+        assert!(
+            verify(&tee, &pubkey, &nonce, None, NOW_MS)
+                .unwrap()
+                .is_some()
+        );
+        store::state::add_tee_measurements(TEEKind::TDX, Some(provider), vec![entry]);
+        assert!(
+            verify(&tee, &pubkey, &nonce, Some(provider), NOW_MS)
+                .unwrap()
+                .is_some()
+        );
+    }
+}
@@ -14,6 +14,7 @@ use pocket_ic::{PocketIc, PocketIcBuilder};
 use serde::{Deserialize, Serialize};
 use serde_bytes::ByteBuf;
 use std::{
+    collections::BTreeMap,
     ops::Add,
     path::Path,
     time::{Duration, SystemTime},
@@ -109,7 +110,7 @@ fn anda_registry_canister_should_work() {
             registry: can.canister,
             code: [0u8; 16].into(),
             agent: AgentInfo {
-                handle: "anda".to_string(),
+                handle: "anda_agent".to_string(),
                 handle_canister: None,
                 name: "Anda".to_string(),
                 description: "test agent".to_string(),
@@ -148,7 +149,7 @@ fn anda_registry_canister_should_work() {
             registry: can.canister,
             code: agent.challenge_code,
             agent: AgentInfo {
-                handle: "anda".to_string(),
+                handle: "anda_agent".to_string(),
                 handle_canister: None,
                 name: "Anda".to_string(),
                 description: "test agent".to_string(),
@@ -182,7 +183,7 @@ fn anda_registry_canister_should_work() {
         let rt: Result<Agent, RegistryError> =
             can.query(caller, "get_agent", &(agent_id.sender().unwrap(),));
         let agent = rt.unwrap();
-        assert_eq!(agent.info.handle, "anda");
+        assert_eq!(agent.info.handle, "anda_agent");
         assert_eq!(agent.info.name, "Anda");
         assert!(agent.health_power >= 1000);
     }
@@ -203,7 +204,7 @@ fn anda_registry_canister_should_work() {
                 .any(|h| { h.0 == "content-type" && h.1 == "application/json" })
         );
         let agent: Agent = serde_json::from_slice(&rt.body).unwrap();
-        assert_eq!(agent.info.handle, "anda");
+        assert_eq!(agent.info.handle, "anda_agent");
         assert_eq!(agent.info.name, "Anda");
         assert!(agent.health_power >= 1000);
 
@@ -217,7 +218,7 @@ fn anda_registry_canister_should_work() {
                 registry: can.canister,
                 code: agent.challenge_code,
                 agent: AgentInfo {
-                    handle: "anda".to_string(),
+                    handle: "anda_agent".to_string(),
                     handle_canister: None,
                     name: "Anda 2".to_string(),
                     description: "test agent".to_string(),
@@ -274,7 +275,247 @@ fn anda_registry_canister_should_work() {
         let agent: Agent = cbor2::from_slice(&rt.body).unwrap();
         assert_eq!(agent.info.name, "Anda 2");
         assert!(agent.health_power >= 2000);
+
+        // batch lookup
+        let req = HttpRequest::builder()
+            .with_method(Method::GET)
+            .with_url(format!(
+                "/agents/batch?id={}&id={}",
+                agent_id.sender().unwrap(),
+                Principal::anonymous()
+            ))
+            .with_headers(vec![("accept".into(), "application/json".into())])
+            .build();
+        let rt: HttpResponse = can.query(caller, "http_request", &(req, true));
+        assert_eq!(rt.status_code, 200);
+        let agents: Vec<Result<Agent, RegistryError>> = serde_json::from_slice(&rt.body).unwrap();
+        assert_eq!(agents.len(), 2);
+        assert_eq!(agents[0].as_ref().unwrap().info.name, "Anda 2");
+        assert!(agents[1].is_err());
+
+        // a batch of one key is still a list
+        let req = HttpRequest::builder()
+            .with_method(Method::GET)
+            .with_url("/agents/batch?handle=anda_agent")
+            .with_headers(vec![("accept".into(), "application/json".into())])
+            .build();
+        let rt: HttpResponse = can.query(caller, "http_request", &(req, true));
+        assert_eq!(rt.status_code, 200);
+        let agents: Vec<Result<Agent, RegistryError>> = serde_json::from_slice(&rt.body).unwrap();
+        assert_eq!(agents.len(), 1);
+        assert_eq!(agents[0].as_ref().unwrap().id, agent_id.sender().unwrap());
+
+        // a lookup of more than one key is served as a batch, without certification
+        let rt = http_get(
+            &can,
+            caller,
+            &format!("/lookup?id={}&handle=anda_agent", Principal::anonymous()),
+            false,
+        );
+        assert_eq!(rt.status_code, 200);
+        assert!(
+            rt.headers
+                .iter()
+                .any(|h| h.0 == "ic-certificateexpression" && h.1.contains("no_certification"))
+        );
+        let agents: Vec<Result<Agent, RegistryError>> = serde_json::from_slice(&rt.body).unwrap();
+        assert_eq!(agents.len(), 2);
+        assert!(agents[0].is_err());
+        assert_eq!(agents[1].as_ref().unwrap().id, agent_id.sender().unwrap());
+    }
+
+    let rt: Result<Vec<Result<Agent, RegistryError>>, RegistryError> = can.query(
+        caller,
+        "get_agents",
+        &(vec![agent_id.sender().unwrap(), Principal::anonymous()],),
+    );
+    let agents = rt.unwrap();
+    assert_eq!(agents[0].as_ref().unwrap().id, agent_id.sender().unwrap());
+    assert!(matches!(agents[1], Err(RegistryError::NotFound { .. })));
+}
+
+// run `make build-wasm` to build the wasm
+#[test]
+#[ignore]
+fn anda_registry_canister_http_listing_should_work() {
+    let challenger_id = new_basic_identity();
+    let caller = challenger_id.sender().unwrap();
+    let can = TestCanister::new::<()>("anda_registry_canister", None, Some(caller));
+    can.pic.set_time(SystemTime::now().into());
+    let rt: Result<(), String> = can.update(caller, "admin_add_challengers", &(vec![caller],));
+    assert!(rt.is_ok());
+
+    let mut ids = Vec::new();
+    for i in 0..3 {
+        let agent_id = new_basic_identity();
+        let rt: Result<(), RegistryError> = can.update(
+            caller,
+            "register",
+            &(&challenge_envelope(
+                &can,
+                &challenger_id,
+                &agent_id,
+                &format!("listed_agent_{i}"),
+                [0u8; 16],
+            ),),
+        );
+        assert!(rt.is_ok());
+        ids.push(agent_id);
     }
+
+    // challenge the first agent so that it enters the health power ranking
+    let time = can.pic.get_time();
+    can.pic.set_time(time.add(Duration::from_millis(1000)));
+    let rt: Result<Agent, RegistryError> =
+        can.query(caller, "get_agent", &(ids[0].sender().unwrap(),));
+    let code = rt.unwrap().challenge_code;
+    let rt: Result<(), RegistryError> = can.update(
+        caller,
+        "challenge",
+        &(&challenge_envelope(
+            &can,
+            &challenger_id,
+            &ids[0],
+            "listed_agent_0",
+            *code,
+        ),),
+    );
+    assert!(rt.is_ok());
+
+    // GET /agents, newest first with pagination
+    let rt = http_get(&can, caller, "/agents?take=2", false);
+    assert_eq!(rt.status_code, 200);
+    let (prev, agents): (u64, Vec<Agent>) = serde_json::from_slice(&rt.body).unwrap();
+    assert_eq!(
+        agents.iter().map(|a| a.id).collect::<Vec<_>>(),
+        vec![ids[2].sender().unwrap(), ids[1].sender().unwrap()]
+    );
+    let rt = http_get(&can, caller, &format!("/agents?prev={prev}&take=2"), true);
+    assert_eq!(rt.status_code, 200);
+    assert!(
+        rt.headers
+            .iter()
+            .any(|h| h.0 == "content-type" && h.1 == "application/cbor")
+    );
+    let (_, agents): (u64, Vec<Agent>) = cbor2::from_slice(&rt.body).unwrap();
+    assert_eq!(
+        agents.iter().map(|a| a.id).collect::<Vec<_>>(),
+        vec![ids[0].sender().unwrap()]
+    );
+
+    // GET /agents/top
+    for in_cbor in [false, true] {
+        let rt = http_get(&can, caller, "/agents/top?take=10", in_cbor);
+        assert_eq!(rt.status_code, 200);
+        let agents: Vec<Agent> = if in_cbor {
+            cbor2::from_slice(&rt.body).unwrap()
+        } else {
+            serde_json::from_slice(&rt.body).unwrap()
+        };
+        assert_eq!(agents.len(), 1);
+        assert_eq!(agents[0].id, ids[0].sender().unwrap());
+        assert!(agents[0].effective_health_power >= Some(1000));
+    }
+
+    // GET /agents/recent
+    for in_cbor in [false, true] {
+        let rt = http_get(&can, caller, "/agents/recent?take=2", in_cbor);
+        assert_eq!(rt.status_code, 200);
+        let recent: BTreeMap<Principal, u64> = if in_cbor {
+            cbor2::from_slice(&rt.body).unwrap()
+        } else {
+            serde_json::from_slice(&rt.body).unwrap()
+        };
+        assert_eq!(recent.len(), 2);
+        assert!(recent.contains_key(&ids[0].sender().unwrap()));
+    }
+
+    // GET /agents/{principal}
+    for in_cbor in [false, true] {
+        let id = ids[1].sender().unwrap();
+        let rt = http_get(&can, caller, &format!("/agents/{id}"), in_cbor);
+        assert_eq!(rt.status_code, 200);
+        let agent: Agent = if in_cbor {
+            cbor2::from_slice(&rt.body).unwrap()
+        } else {
+            serde_json::from_slice(&rt.body).unwrap()
+        };
+        assert_eq!(agent.id, id);
+        assert_eq!(agent.info.handle, "listed_agent_1");
+    }
+
+    // error cases
+    let rt = http_get(&can, caller, "/agents/not-a-principal", false);
+    assert_eq!(rt.status_code, 400);
+    let rt = http_get(
+        &can,
+        caller,
+        &format!("/agents/{}", Principal::anonymous()),
+        false,
+    );
+    assert_eq!(rt.status_code, 404);
+    for url in [
+        "/agents?take=abc",
+        "/agents?prev=-1",
+        "/agents?limit=10",
+        "/agents/top?take=abc",
+        "/agents/recent?take=-1",
+    ] {
+        let rt = http_get(&can, caller, url, false);
+        assert_eq!(rt.status_code, 400, "{url}");
+        assert!(
+            rt.headers
+                .iter()
+                .any(|h| h.0 == "content-type" && h.1 == "text/plain")
+        );
+    }
+}
+
+fn challenge_envelope(
+    can: &TestCanister,
+    challenger_id: &impl Identity,
+    agent_id: &impl Identity,
+    handle: &str,
+    code: [u8; 16],
+) -> ChallengeEnvelope {
+    let mut request = ChallengeRequest {
+        registry: can.canister,
+        code: code.into(),
+        agent: AgentInfo {
+            handle: handle.to_string(),
+            handle_canister: None,
+            name: "Test Agent".to_string(),
+            image: "https://example.com/image.png".to_string(),
+            description: "test agent".to_string(),
+            endpoint: "https://test.agent/endpoint".to_string(),
+            ..Default::default()
+        },
+        created_at: unix_timestamp().as_millis() as u64,
+        authentication: None,
+    };
+    let digest = request.core_digest();
+    request.authentication =
+        Some(SignedEnvelope::sign_digest(challenger_id, digest.into()).unwrap());
+    let digest = request.digest();
+    ChallengeEnvelope {
+        request,
+        authentication: SignedEnvelope::sign_digest(agent_id, digest.into()).unwrap(),
+        tee: None,
+    }
+}
+
+fn http_get(can: &TestCanister, caller: Principal, url: &str, in_cbor: bool) -> HttpResponse {
+    let accept = if in_cbor {
+        "application/cbor"
+    } else {
+        "application/json"
+    };
+    let req = HttpRequest::builder()
+        .with_method(Method::GET)
+        .with_url(url.to_string())
+        .with_headers(vec![("accept".into(), accept.into())])
+        .build();
+    can.query(caller, "http_request", &(req, true))
 }
 
 struct TestCanister {